@@ -0,0 +1,150 @@
+use std::{collections::{BTreeMap, HashMap}, fmt, fs, path::Path};
+
+use anyhow::anyhow;
+use regex::Regex;
+use serde::Deserialize;
+
+/// Push rules, as they appear in the ACL file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPushRules {
+    #[serde(default)]
+    allow_families: Vec<String>,
+    #[serde(default)]
+    deny_families: Vec<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPushAcl {
+    #[serde(default)]
+    global: RawPushRules,
+    #[serde(default)]
+    identities: HashMap<String, RawPushRules>,
+}
+
+/// Compiles a pattern into a regex that has to match the whole string, like in Prometheus
+fn anchored_regex(pattern: &str) -> Result<Regex, anyhow::Error> {
+    Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| anyhow!("Invalid regex `{}`: {}", pattern, e))
+}
+
+/// What a client is allowed to push
+#[derive(Debug, Default)]
+struct PushRules {
+    /// If there are any, every family name has to match one of these
+    allow_families: Vec<Regex>,
+    /// Family names that can't be pushed, even if they're allowed above
+    deny_families: Vec<Regex>,
+    /// Grouping key labels (from the push URL) whose values have to match the given regex. A push without
+    /// one of these labels is treated as if it had it set to the empty string
+    labels: Vec<(String, Regex)>,
+}
+
+impl PushRules {
+    fn from_raw(raw: RawPushRules) -> Result<PushRules, anyhow::Error> {
+        Ok(PushRules {
+            allow_families: raw.allow_families.iter().map(|pattern| anchored_regex(pattern)).collect::<Result<_, _>>()?,
+            deny_families: raw.deny_families.iter().map(|pattern| anchored_regex(pattern)).collect::<Result<_, _>>()?,
+            labels: raw.labels.into_iter().map(|(name, pattern)| anchored_regex(&pattern).map(|regex| (name, regex))).collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn allows_family(&self, name: &str) -> bool {
+        (self.allow_families.is_empty() || self.allow_families.iter().any(|regex| regex.is_match(name)))
+            && !self.deny_families.iter().any(|regex| regex.is_match(name))
+    }
+}
+
+/// The reasons that a push was rejected by the ACL
+#[derive(Debug, Default, PartialEq)]
+pub struct AclViolation {
+    /// Grouping key labels that have values the client isn't allowed to push, as name=value
+    pub labels: Vec<String>,
+    /// Families that the client isn't allowed to push
+    pub families: Vec<String>,
+}
+
+impl fmt::Display for AclViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut reasons = Vec::new();
+        if !self.labels.is_empty() {
+            reasons.push(format!("disallowed grouping labels: {}", self.labels.join(", ")));
+        }
+
+        if !self.families.is_empty() {
+            reasons.push(format!("disallowed families: {}", self.families.join(", ")));
+        }
+
+        write!(f, "Push not allowed - {}", reasons.join("; "))
+    }
+}
+
+/// Controls which families and grouping keys each identity can push. Every push has to pass the global rules,
+/// and the rules for the identity it's authenticated as, if there are any
+#[derive(Debug, Default)]
+pub struct PushAcl {
+    global: PushRules,
+    identities: HashMap<String, PushRules>,
+}
+
+impl PushAcl {
+    fn rules_for<'a>(&'a self, identity: &str) -> impl Iterator<Item = &'a PushRules> {
+        std::iter::once(&self.global).chain(self.identities.get(identity))
+    }
+
+    /// Checks the grouping key labels of a push, which can be done before the body's been parsed
+    pub fn check_labels(&self, identity: &str, labels: &HashMap<String, String>) -> Result<(), AclViolation> {
+        let mut disallowed = Vec::new();
+        for (name, regex) in self.rules_for(identity).flat_map(|rules| rules.labels.iter()) {
+            let value = labels.get(name).map(|value| value.as_str()).unwrap_or("");
+            if !regex.is_match(value) {
+                disallowed.push(format!("{}={}", name, value));
+            }
+        }
+
+        if disallowed.is_empty() {
+            return Ok(());
+        }
+
+        disallowed.sort();
+        disallowed.dedup();
+        Err(AclViolation { labels: disallowed, families: Vec::new() })
+    }
+
+    /// Checks the names of the families in a push
+    pub fn check_families<'a, I: Iterator<Item = &'a str>>(&self, identity: &str, families: I) -> Result<(), AclViolation> {
+        let mut disallowed: Vec<String> = families
+            .filter(|name| !self.rules_for(identity).all(|rules| rules.allows_family(name)))
+            .map(|name| name.to_owned())
+            .collect();
+
+        if disallowed.is_empty() {
+            return Ok(());
+        }
+
+        disallowed.sort();
+        disallowed.dedup();
+        Err(AclViolation { labels: Vec::new(), families: disallowed })
+    }
+}
+
+/// Loads a push ACL from a YAML file, with `global` rules for every push and rules for specific `identities`
+pub fn load_push_acl<P: AsRef<Path>>(path: P) -> Result<PushAcl, anyhow::Error> {
+    parse_push_acl(&fs::read_to_string(path)?)
+}
+
+pub fn parse_push_acl(s: &str) -> Result<PushAcl, anyhow::Error> {
+    let raw: RawPushAcl = serde_yaml::from_str(s)?;
+    let mut identities = HashMap::new();
+    for (identity, rules) in raw.identities {
+        let rules = PushRules::from_raw(rules).map_err(|e| anyhow!("Invalid rules for identity {}: {}", identity, e))?;
+        identities.insert(identity, rules);
+    }
+
+    Ok(PushAcl {
+        global: PushRules::from_raw(raw.global).map_err(|e| anyhow!("Invalid global rules: {}", e))?,
+        identities,
+    })
+}
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use crate::acl::*;
+
+const ACL: &str = "global:
+  deny_families: [gravel_.*]
+identities:
+  team-a:
+    allow_families: [team_a_.*, shared_.*]
+    labels:
+      job: team-a-.*
+";
+
+fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+    labels.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+}
+
+#[test]
+fn test_family_rules() {
+    let acl = parse_push_acl(ACL).unwrap();
+    assert!(acl.check_families("team-a", ["team_a_requests_total", "shared_errors_total"].iter().copied()).is_ok());
+    assert_eq!(acl.check_families("team-a", ["team_b_requests_total", "team_a_requests_total", "gravel_series", "team_b_requests_total"].iter().copied()), Err(AclViolation {
+        labels: Vec::new(),
+        families: vec!["gravel_series".to_owned(), "team_b_requests_total".to_owned()],
+    }));
+
+    // Identities without rules of their own only have to pass the global ones
+    assert!(acl.check_families("team-b", ["team_a_requests_total"].iter().copied()).is_ok());
+    assert!(acl.check_families("", ["gravel_series"].iter().copied()).is_err());
+}
+
+#[test]
+fn test_label_rules() {
+    let acl = parse_push_acl(ACL).unwrap();
+    assert!(acl.check_labels("team-a", &labels(&[("job", "team-a-api"), ("instance", "1")])).is_ok());
+    assert_eq!(acl.check_labels("team-a", &labels(&[("job", "team-b-api")])), Err(AclViolation {
+        labels: vec!["job=team-b-api".to_owned()],
+        families: Vec::new(),
+    }));
+
+    // Missing labels count as empty
+    assert!(acl.check_labels("team-a", &labels(&[])).is_err());
+    assert!(acl.check_labels("team-b", &labels(&[])).is_ok());
+}
+
+#[test]
+fn test_invalid_acls() {
+    assert!(parse_push_acl("global:\n  allow_families: [\"(\"]\n").is_err(), "invalid regexes should be rejected");
+    assert!(parse_push_acl("identities:\n  a:\n    allow: [b]\n").is_err(), "unknown fields should be rejected");
+    assert!(parse_push_acl("{}").is_ok());
+}
@@ -1,16 +1,40 @@
-use std::{collections::HashMap, str::FromStr, sync::Arc, fmt, time::Duration};
+use std::{collections::{BTreeMap, HashMap, HashSet}, str::FromStr, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, fmt, time::{Duration, SystemTime}, ops::Add};
 
-use openmetrics_parser::{RenderableMetricValue, HistogramBucket, MetricsExposition, ParseError, PrometheusMetricFamily, PrometheusType, PrometheusValue, Sample, prometheus, MetricFamily, Timestamp, MetricNumber};
+use openmetrics_parser::{RenderableMetricValue, Exemplar, PrometheusCounterValue, HistogramBucket, HistogramValue, Quantile, SummaryValue, MetricsExposition, ParseError, PrometheusMetricFamily, PrometheusType, PrometheusValue, Sample, prometheus, MetricFamily, Timestamp, MetricNumber};
 use tokio::sync::RwLock;
+use warp::hyper::body::Bytes;
 
-use crate::pebble::{TimePebble, parse_duration, sum_merge_strategy, mean_merge_strategy};
+use crate::native_histogram::NativeHistogram;
+use crate::compression;
+use crate::protobuf;
+use crate::relabel::{RelabelConfig, relabel_families};
+use crate::selector::Selector;
+use crate::self_metrics::SelfMetricFamily;
+use crate::pebble::{AppendError, TimePebble, EwmaPebble, parse_duration, sum_merge_strategy, mean_merge_strategy, histogram_merge_strategy};
 
-const CLEARMODE_LABEL_NAME: &str = "clearmode";
+pub(crate) const CLEARMODE_LABEL_NAME: &str = "clearmode";
+
+/// Whether a name matches Prometheus' metric name grammar, `[a-zA-Z_:][a-zA-Z0-9_:]*`
+pub(crate) fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Whether a name matches Prometheus' label name grammar, `[a-zA-Z_][a-zA-Z0-9_]*`
+pub(crate) fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
 #[derive(Debug)]
 pub enum AggregationError {
     ParseError(ParseError),
     Error(String),
+    /// A single sample in a push was invalid, for the given reason. In lenient mode, these samples
+    /// are dropped (and counted) rather than rejecting the whole push
+    InvalidSample(&'static str, String),
+    /// Merging a push would take the gateway over one of its series limits
+    LimitExceeded(String),
 }
 
 impl From<ParseError> for AggregationError {
@@ -19,11 +43,21 @@ impl From<ParseError> for AggregationError {
     }
 }
 
+impl From<AppendError> for AggregationError {
+    fn from(e: AppendError) -> Self {
+        match e {
+            AppendError::TooOld => AggregationError::InvalidSample("too_old", "Sample is older than the window of its pebble".to_owned()),
+        }
+    }
+}
+
 impl AggregationError {
     pub fn to_string(&self) -> String{
         match self {
             AggregationError::ParseError(err) => err.to_string(),
             AggregationError::Error(err) => err.to_owned(),
+            AggregationError::InvalidSample(_, err) => err.to_owned(),
+            AggregationError::LimitExceeded(err) => err.to_owned(),
         }
     }
 }
@@ -33,16 +67,68 @@ pub enum ClearMode {
     Aggregate,
     Replace,
     Family,
-    Mean(Duration),
-    Sum(Duration)
+    /// A mean over the given window, split into the given number of buckets (or the configured default)
+    Mean(Duration, Option<usize>),
+    /// A sum over the given window, split into the given number of buckets (or the configured default)
+    Sum(Duration, Option<usize>),
+    Ewma(Duration),
+    /// Treats each push as the current value of a cumulative counter, adding only its increase to a running total
+    Cumulative,
 }
 
-type GravelMetricFamily = MetricFamily<PrometheusType, GravelValue>;
+pub(crate) type GravelMetricFamily = MetricFamily<PrometheusType, GravelValue>;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum GravelValue {
     Prometheus(PrometheusValue),
-    Pebble(TimePebble)
+    Pebble(TimePebble),
+    HistogramPebble(TimePebble<HistogramValue>),
+    Ewma(EwmaPebble),
+    NativeHistogram(NativeHistogram),
+    Cumulative(CumulativeCounter),
+}
+
+/// A counter that's pushed with its cumulative value (e.g. by a long lived client) rather than an increment. Only
+/// the increase since the previous push is added to the exposed total, so that a client restarting (which resets
+/// its counter to zero) doesn't make the total go down
+#[derive(Debug, Clone, PartialEq)]
+pub struct CumulativeCounter {
+    /// The value of the last push
+    last: f64,
+    /// The total increase over every push
+    total: f64,
+    exemplar: Option<Exemplar>,
+}
+
+impl CumulativeCounter {
+    fn new(counter: &PrometheusCounterValue) -> CumulativeCounter {
+        // Like Prometheus, we assume that a counter we've never seen before started from zero
+        CumulativeCounter {
+            last: counter.value.as_f64(),
+            total: counter.value.as_f64(),
+            exemplar: counter.exemplar.clone(),
+        }
+    }
+
+    fn append(&mut self, counter: &PrometheusCounterValue) {
+        let value = counter.value.as_f64();
+        if value >= self.last {
+            self.total += value - self.last;
+        } else {
+            // The counter went down, so the client must have restarted and everything it's reported is new
+            self.total += value;
+        }
+
+        self.last = value;
+        self.exemplar = counter.exemplar.clone();
+    }
+
+    pub fn to_counter(&self) -> PrometheusCounterValue {
+        PrometheusCounterValue {
+            value: MetricNumber::Float(self.total),
+            exemplar: self.exemplar.clone(),
+        }
+    }
 }
 
 impl RenderableMetricValue for GravelValue {
@@ -60,6 +146,18 @@ impl RenderableMetricValue for GravelValue {
                 let value = pebble.aggregate();
 
                 return PrometheusValue::Gauge(MetricNumber::Float(value)).render(f, metric_name, timestamp, label_names, label_values);
+            },
+            GravelValue::HistogramPebble(pebble) => {
+                PrometheusValue::Histogram(pebble.aggregate()).render(f, metric_name, timestamp, label_names, label_values)
+            },
+            GravelValue::Ewma(ewma) => {
+                PrometheusValue::Gauge(MetricNumber::Float(ewma.aggregate())).render(f, metric_name, timestamp, label_names, label_values)
+            },
+            GravelValue::NativeHistogram(histogram) => {
+                PrometheusValue::Histogram(histogram.to_classic()).render(f, metric_name, timestamp, label_names, label_values)
+            },
+            GravelValue::Cumulative(counter) => {
+                PrometheusValue::Counter(counter.to_counter()).render(f, metric_name, timestamp, label_names, label_values)
             }
         }
     }
@@ -72,36 +170,114 @@ impl From<PrometheusValue> for GravelValue {
 }
 
 impl GravelValue {
-    fn convert_with_clearmode(self, clearmode: ClearMode) -> GravelValue {
-        const DEFAULT_PEBBLE_GRANULARITY: usize = 100;
+    /// Converts a newly pushed value into whatever it should be stored as for the given clearmode. Pebbles
+    /// start off with the value in the bucket for the given time
+    fn convert_with_clearmode(self, clearmode: ClearMode, timestamp: SystemTime, config: &AggregatorConfig) -> GravelValue {
         match clearmode {
-            ClearMode::Sum(duration) => {
-                let mut pebble = TimePebble::new(duration, DEFAULT_PEBBLE_GRANULARITY, sum_merge_strategy);
+            ClearMode::Sum(duration, granularity) if matches!(self, GravelValue::Prometheus(PrometheusValue::Histogram(_))) => {
+                let mut pebble = TimePebble::new(duration, granularity.unwrap_or(config.pebble_granularity), histogram_merge_strategy);
+                if let GravelValue::Prometheus(PrometheusValue::Histogram(histogram)) = self {
+                    // A brand new pebble has no newest bucket yet, so it can't reject a value for being too old
+                    let _ = pebble.append_with_timestamp(histogram, timestamp);
+                }
+
+                GravelValue::HistogramPebble(pebble)
+            },
+            ClearMode::Sum(duration, granularity) => {
+                let mut pebble = TimePebble::new(duration, granularity.unwrap_or(config.pebble_granularity), sum_merge_strategy);
                 if let GravelValue::Prometheus(prom) = self {
-                    match prom {
-                        PrometheusValue::Counter(counter) => pebble.append(counter.value.as_f64()),
-                        PrometheusValue::Gauge(gauge) => pebble.append(gauge.as_f64()),
-                        _ => {}
+                    let _ = match prom {
+                        PrometheusValue::Counter(counter) => pebble.append_with_timestamp(counter.value.as_f64(), timestamp),
+                        PrometheusValue::Gauge(gauge) => pebble.append_with_timestamp(gauge.as_f64(), timestamp),
+                        _ => Ok(())
                     };
                 }
 
                 return GravelValue::Pebble(pebble);
             },
-            ClearMode::Mean(duration) => {
-                let mut pebble = TimePebble::new(duration, DEFAULT_PEBBLE_GRANULARITY, mean_merge_strategy);
+            ClearMode::Mean(duration, granularity) => {
+                let mut pebble = TimePebble::new(duration, granularity.unwrap_or(config.pebble_granularity), mean_merge_strategy);
                 if let GravelValue::Prometheus(prom) = self {
-                    match prom {
-                        PrometheusValue::Counter(counter) => pebble.append(counter.value.as_f64()),
-                        PrometheusValue::Gauge(gauge) => pebble.append(gauge.as_f64()),
-                        _ => {}
+                    let _ = match prom {
+                        PrometheusValue::Counter(counter) => pebble.append_with_timestamp(counter.value.as_f64(), timestamp),
+                        PrometheusValue::Gauge(gauge) => pebble.append_with_timestamp(gauge.as_f64(), timestamp),
+                        _ => Ok(())
                     };
                 }
 
                 return GravelValue::Pebble(pebble);
             }
+            ClearMode::Ewma(half_life) => {
+                let mut ewma = EwmaPebble::new(half_life);
+                match self {
+                    GravelValue::Prometheus(PrometheusValue::Counter(counter)) => ewma.append_with_timestamp(counter.value.as_f64(), timestamp),
+                    GravelValue::Prometheus(PrometheusValue::Gauge(gauge)) => ewma.append_with_timestamp(gauge.as_f64(), timestamp),
+                    _ => return self
+                };
+
+                GravelValue::Ewma(ewma)
+            }
+            ClearMode::Cumulative => {
+                match &self {
+                    GravelValue::Prometheus(PrometheusValue::Counter(counter)) => GravelValue::Cumulative(CumulativeCounter::new(counter)),
+                    _ => self
+                }
+            }
             _ => return self
         }
     }
+
+    /// Checks that a newly pushed value is something that we can sensibly merge with the given clearmode
+    fn validate(&self, clear_mode: &ClearMode) -> Result<(), AggregationError> {
+        // These have to be checked before the type specific checks, or the first push of a series would be stored
+        // without its clearmode, and only the next one would fail to merge with it
+        match clear_mode {
+            ClearMode::Cumulative if !matches!(self, GravelValue::Prometheus(PrometheusValue::Counter(_))) => {
+                return Err(AggregationError::Error("clearmode cumulative is only supported for counters".to_owned()));
+            },
+            ClearMode::Ewma(_) if !matches!(self, GravelValue::Prometheus(PrometheusValue::Counter(_) | PrometheusValue::Gauge(_))) => {
+                return Err(AggregationError::Error(format!("clearmode {:?} is only supported for counters and gauges", clear_mode)));
+            },
+            _ => {}
+        }
+
+        match self {
+            GravelValue::Prometheus(PrometheusValue::Histogram(_)) if matches!(clear_mode, ClearMode::Mean(..)) => {
+                Err(AggregationError::Error(format!("clearmode {:?} is not supported for histograms, which don't have a meaningful mean. Use a sum window instead", clear_mode)))
+            },
+            GravelValue::Prometheus(PrometheusValue::Histogram(histogram)) => validate_buckets(&histogram.buckets),
+            GravelValue::NativeHistogram(histogram) => {
+                if !matches!(clear_mode, ClearMode::Aggregate | ClearMode::Replace | ClearMode::Family) {
+                    return Err(AggregationError::Error(format!("clearmode {:?} is not supported for native histograms", clear_mode)));
+                }
+
+                histogram.validate()
+            },
+            GravelValue::Prometheus(PrometheusValue::Counter(counter)) => {
+                let value = counter.value.as_f64();
+                if !value.is_finite() {
+                    return Err(AggregationError::InvalidSample("non_finite_counter", format!("Counters must be finite, got {}", counter.value)));
+                }
+
+                if value < 0. {
+                    return Err(AggregationError::InvalidSample("negative_counter", format!("Counters can't be negative, got {}", counter.value)));
+                }
+
+                Ok(())
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// Returns the approximate number of bytes used by this value, if it's a pebble
+    fn pebble_memory_bytes(&self) -> Option<usize> {
+        match self {
+            GravelValue::Prometheus(_) | GravelValue::NativeHistogram(_) | GravelValue::Cumulative(_) => None,
+            GravelValue::Pebble(pebble) => Some(pebble.memory_bytes()),
+            GravelValue::HistogramPebble(pebble) => Some(pebble.memory_bytes()),
+            GravelValue::Ewma(ewma) => Some(std::mem::size_of_val(ewma)),
+        }
+    }
 }
 
 impl ClearMode {
@@ -112,10 +288,21 @@ impl ClearMode {
         }
     }
 
-    fn from_family<T>(family_type: PrometheusType, metric: &Sample<T>) -> ClearMode where T: RenderableMetricValue + Clone {
-        match metric.get_labelset().unwrap().get_label_value(CLEARMODE_LABEL_NAME) {
-            Some(c) => ClearMode::from_str(c).unwrap_or(ClearMode::default_for_type(family_type)),
-            None => ClearMode::default_for_type(family_type)
+    /// Checks that a windowed clearmode doesn't ask for more pebble buckets than the config allows, as every
+    /// series pushed with it allocates all of its buckets up front
+    fn check_granularity(&self, config: &AggregatorConfig) -> Result<(), AggregationError> {
+        match self {
+            ClearMode::Mean(_, Some(granularity)) | ClearMode::Sum(_, Some(granularity)) if *granularity > config.max_pebble_granularity => {
+                Err(AggregationError::Error(format!("Invalid pebble granularity: {} is more than the limit of {}", granularity, config.max_pebble_granularity)))
+            },
+            _ => Ok(())
+        }
+    }
+
+    fn from_family<T>(family_type: PrometheusType, metric: &Sample<T>) -> Result<ClearMode, AggregationError> where T: RenderableMetricValue + Clone {
+        match metric.get_labelset()?.get_label_value(CLEARMODE_LABEL_NAME) {
+            Some(c) => ClearMode::from_str(c),
+            None => Ok(ClearMode::default_for_type(family_type))
         }
     }
 }
@@ -128,20 +315,32 @@ impl FromStr for ClearMode {
             "aggregate" | "sum" => Ok(ClearMode::Aggregate),
             "replace" => Ok(ClearMode::Replace),
             "family" | "info" => Ok(ClearMode::Family),
+            "cumulative" => Ok(ClearMode::Cumulative),
             _ => {
-                if s.starts_with("mean") || s.starts_with("sum") {
-                    let num_preceeding = s.chars().take_while(|c| c.is_digit(10)).count();
-                    match parse_duration(&s[num_preceeding..]) {
-                        Some(duration) => {
-                            if s.starts_with("mean") {
-                                return Ok(ClearMode::Mean(duration))
-                            }
-
-                            if s.starts_with("sum") {
-                                return Ok(ClearMode::Sum(duration))
-                            }
+                if s.starts_with("mean") || s.starts_with("sum") || s.starts_with("ewma") {
+                    // Windowed clearmodes can specify the number of buckets to use after an @, e.g. mean1h@60
+                    let (window, granularity) = match s.split_once('@') {
+                        Some((window, granularity)) => match granularity.parse::<usize>() {
+                            Ok(g) if g > 0 => (window, Some(g)),
+                            _ => return Err(AggregationError::Error(format!("Invalid pebble granularity: {}", granularity)))
                         },
-                        None => return Err(AggregationError::Error(format!("Invalid duration string: {}", &s[num_preceeding..])))
+                        None => (s, None)
+                    };
+
+                    if let Some(duration) = window.strip_prefix("mean") {
+                        return Ok(ClearMode::Mean(parse_duration(duration)?, granularity))
+                    }
+
+                    if let Some(duration) = window.strip_prefix("sum") {
+                        return Ok(ClearMode::Sum(parse_duration(duration)?, granularity))
+                    }
+
+                    if let Some(half_life) = window.strip_prefix("ewma") {
+                        if granularity.is_some() {
+                            return Err(AggregationError::Error(format!("ewma clearmodes don't take a granularity: {}", s)))
+                        }
+
+                        return Ok(ClearMode::Ewma(parse_duration(half_life)?))
                     }
                 }
 
@@ -151,6 +350,113 @@ impl FromStr for ClearMode {
     }
 }
 
+/// QuantileMergeStrategy controls how the quantiles of two summaries are combined when they're aggregated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuantileMergeStrategy {
+    /// Take the largest value of each quantile. This overestimates, but never hides a slow tail
+    Max,
+    /// Average each quantile, weighted by the _count of each summary
+    Weighted,
+}
+
+impl FromStr for QuantileMergeStrategy {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(QuantileMergeStrategy::Max),
+            "weighted" => Ok(QuantileMergeStrategy::Weighted),
+            _ => Err(AggregationError::Error(format!("Invalid quantile merge strategy: {}", s)))
+        }
+    }
+}
+
+/// HistogramBucketPolicy controls what happens when a histogram is pushed with a different set of buckets
+/// to the one that is already stored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistogramBucketPolicy {
+    /// Reject the push
+    Reject,
+    /// Re-bucket both histograms into the union of their bounds
+    Union,
+    /// Re-bucket the new histogram into the existing bounds
+    Existing,
+}
+
+impl FromStr for HistogramBucketPolicy {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(HistogramBucketPolicy::Reject),
+            "union" => Ok(HistogramBucketPolicy::Union),
+            "existing" => Ok(HistogramBucketPolicy::Existing),
+            _ => Err(AggregationError::Error(format!("Invalid histogram bucket policy: {}", s)))
+        }
+    }
+}
+
+/// ValidationMode controls what happens when a push contains invalid samples, e.g. negative counters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValidationMode {
+    /// Reject the whole push
+    Strict,
+    /// Drop the invalid samples (counting them in the self metrics), and merge the rest of the push
+    Lenient,
+}
+
+impl FromStr for ValidationMode {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(ValidationMode::Strict),
+            "lenient" => Ok(ValidationMode::Lenient),
+            _ => Err(AggregationError::Error(format!("Invalid validation mode: {}", s)))
+        }
+    }
+}
+
+/// TimestampMode controls what happens to the timestamps of samples that are pushed with clearmode replace (or family)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampMode {
+    /// Expose the samples without a timestamp, so that Prometheus uses the scrape time
+    Strip,
+    /// Expose the samples with the timestamp they were last pushed with
+    Preserve,
+}
+
+impl FromStr for TimestampMode {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => Ok(TimestampMode::Strip),
+            "preserve" => Ok(TimestampMode::Preserve),
+            _ => Err(AggregationError::Error(format!("Invalid timestamp mode: {}", s)))
+        }
+    }
+}
+
+/// Checks every sample in a newly pushed family, returning the family without any invalid samples, along with the
+/// reasons that they were dropped. In strict mode, the first invalid sample rejects the whole family instead
+fn validate_family(family: GravelMetricFamily, config: &AggregatorConfig) -> Result<(GravelMetricFamily, Vec<&'static str>), AggregationError> {
+    let mut valid = GravelMetricFamily::new(family.family_name.clone(), family.get_label_names().to_vec(), family.family_type.clone(), family.help.clone(), family.unit.clone());
+    let family_type = family.family_type.clone();
+    let mut dropped = Vec::new();
+    for sample in family.into_iter_samples() {
+        let clear_mode = ClearMode::from_family(family_type.clone(), &sample)?;
+        clear_mode.check_granularity(config)?;
+        match sample.value.validate(&clear_mode).and_then(|_| validate_timestamp(sample.timestamp, &clear_mode, config)) {
+            Ok(_) => valid.add_sample(sample)?,
+            Err(AggregationError::InvalidSample(reason, _)) if config.validation_mode == ValidationMode::Lenient => dropped.push(reason),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((valid, dropped))
+}
+
 /// An aggregation family is a wrapped around a normal metrics family that is able to aggregate
 /// new families into itself
 #[derive(Debug)]
@@ -158,61 +464,190 @@ struct AggregationFamily {
     base_family: GravelMetricFamily,
 }
 
-/// Takes two sets of Histogram buckets and merges them. Assumes that they are in ascending order of upperbound
-/// (TODO: We should probably sanity check this / sort) and performs essentially a merge sort merge, summing the counts
-/// if two buckets have the same bound
-fn merge_buckets(val1: &Vec<HistogramBucket>, val2: &Vec<HistogramBucket>) -> Vec<HistogramBucket> {
+/// Formats a bucket upper bound the same way as it appears in the `le` label
+fn format_bound(bound: f64) -> String {
+    if bound == f64::INFINITY {
+        String::from("+Inf")
+    } else if bound == f64::NEG_INFINITY {
+        String::from("-Inf")
+    } else {
+        bound.to_string()
+    }
+}
+
+/// Checks that a set of Histogram buckets is sorted in ascending order of upper bound, with no duplicate
+/// bounds, and ends in a +Inf bucket
+fn validate_buckets(buckets: &[HistogramBucket]) -> Result<(), AggregationError> {
+    for pair in buckets.windows(2) {
+        // NaN bounds aren't ordered with anything, so get rejected here too
+        if pair[0].upper_bound.partial_cmp(&pair[1].upper_bound) != Some(std::cmp::Ordering::Less) {
+            return Err(AggregationError::InvalidSample("unsorted_buckets", format!(
+                "Histogram buckets must be sorted by upper bound with no duplicates, but le=\"{}\" came before le=\"{}\"",
+                format_bound(pair[0].upper_bound), format_bound(pair[1].upper_bound)
+            )));
+        }
+
+        if pair[0].count.as_f64() > pair[1].count.as_f64() {
+            return Err(AggregationError::InvalidSample("non_cumulative_buckets", format!(
+                "Histogram bucket counts must be cumulative, but le=\"{}\" has a count of {} and le=\"{}\" has a count of {}",
+                format_bound(pair[0].upper_bound), pair[0].count, format_bound(pair[1].upper_bound), pair[1].count
+            )));
+        }
+    }
+
+    if let Some(bucket) = buckets.iter().find(|bucket| bucket.count.as_f64() < 0.) {
+        return Err(AggregationError::InvalidSample("negative_buckets", format!("Histogram bucket le=\"{}\" has a negative count of {}", format_bound(bucket.upper_bound), bucket.count)));
+    }
+
+    match buckets.last() {
+        Some(bucket) if bucket.upper_bound == f64::INFINITY => Ok(()),
+        _ => Err(AggregationError::InvalidSample("missing_inf_bucket", "Histograms must have a +Inf bucket".to_owned()))
+    }
+}
+
+/// Returns the cumulative count of a set of buckets at the given bound, i.e. the count of the largest bucket
+/// whose upper bound is less than or equal to the bound. Assumes the buckets are sorted
+fn cumulative_count_at(buckets: &[HistogramBucket], bound: f64) -> MetricNumber {
+    buckets.iter().take_while(|bucket| bucket.upper_bound <= bound).last().map_or(MetricNumber::Int(0), |bucket| bucket.count)
+}
+
+/// Takes two sets of Histogram buckets (in ascending order of upperbound) and merges them into the union of
+/// their bounds. Because bucket counts are cumulative, a bound that only exists on one side gets the other side's
+/// count from its closest smaller bound, which keeps the merged counts cumulative
+pub(crate) fn merge_buckets(val1: &[HistogramBucket], val2: &[HistogramBucket]) -> Vec<HistogramBucket> {
     let mut i = 0;
     let mut j = 0;
-    let mut output = Vec::new();
-
-    // Basically merge sort on the buckets with a bit of extra logic for buckets that have the same upperbound
-    while i < val1.len() && j < val2.len() {
-        let bucket1 = &val1[i];
-        let bucket2 = &val2[j];
-        if bucket1.upper_bound < bucket2.upper_bound {
-            output.push(bucket1.clone());
-            i += 1;
-        } else if bucket1.upper_bound > bucket2.upper_bound {
-            output.push(bucket2.clone());
-            j += 1;
-        } else {
-            output.push(HistogramBucket {
-                count: bucket1.count + bucket2.count,
-                upper_bound: bucket1.upper_bound,
-                exemplar: bucket2.exemplar.clone(),
-            });
+    // The cumulative count of each side at the last bound that we passed
+    let mut count1 = MetricNumber::Int(0);
+    let mut count2 = MetricNumber::Int(0);
+    let mut output = Vec::with_capacity(val1.len().max(val2.len()));
+
+    // Basically merge sort on the buckets, carrying the cumulative counts of each side along with us
+    loop {
+        let bound = match (val1.get(i), val2.get(j)) {
+            (Some(bucket1), Some(bucket2)) => bucket1.upper_bound.min(bucket2.upper_bound),
+            (Some(bucket), None) | (None, Some(bucket)) => bucket.upper_bound,
+            (None, None) => break,
+        };
+
+        let mut exemplar = None;
+        if let Some(bucket) = val1.get(i).filter(|bucket| bucket.upper_bound == bound) {
+            count1 = bucket.count;
+            exemplar = bucket.exemplar.clone();
             i += 1;
+        }
+
+        if let Some(bucket) = val2.get(j).filter(|bucket| bucket.upper_bound == bound) {
+            count2 = bucket.count;
+            exemplar = bucket.exemplar.clone().or(exemplar);
             j += 1;
         }
+
+        output.push(HistogramBucket {
+            count: count1 + count2,
+            upper_bound: bound,
+            exemplar,
+        });
+    }
+
+    output
+}
+
+/// Merges a new set of Histogram buckets into an existing one, according to the given policy for when
+/// the two sets of buckets have different bounds. Both sets of buckets are assumed to be valid
+fn merge_buckets_with_policy(existing: &[HistogramBucket], new: &[HistogramBucket], policy: HistogramBucketPolicy) -> Result<Vec<HistogramBucket>, AggregationError> {
+    let same_layout = existing.len() == new.len() && existing.iter().zip(new.iter()).all(|(a, b)| a.upper_bound == b.upper_bound);
+    if same_layout {
+        return Ok(merge_buckets(existing, new));
+    }
+
+    match policy {
+        HistogramBucketPolicy::Reject => {
+            let bounds = |buckets: &[HistogramBucket]| buckets.iter().map(|bucket| format_bound(bucket.upper_bound)).collect::<Vec<String>>().join(", ");
+            Err(AggregationError::Error(format!("Histogram buckets [{}] don't match the existing buckets [{}]", bounds(new), bounds(existing))))
+        },
+        HistogramBucketPolicy::Union => Ok(merge_buckets(existing, new)),
+        HistogramBucketPolicy::Existing => {
+            Ok(existing.iter().map(|bucket| {
+                let exemplar = new.iter().find(|new_bucket| new_bucket.upper_bound == bucket.upper_bound).and_then(|new_bucket| new_bucket.exemplar.clone());
+                HistogramBucket {
+                    count: bucket.count + cumulative_count_at(new, bucket.upper_bound),
+                    upper_bound: bucket.upper_bound,
+                    exemplar: exemplar.or_else(|| bucket.exemplar.clone()),
+                }
+            }).collect())
+        }
     }
+}
 
-    for i in i..val1.len() {
-        output.push(val1[i].clone());
+/// Adds two optional values together, treating a missing value as absent rather than as zero
+pub(crate) fn sum_optional<T: Add<Output = T>>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (Some(a), None) | (None, Some(a)) => Some(a),
+        (None, None) => None,
     }
+}
+
+/// Merges the quantiles of two summaries, using the given strategy for quantiles that exist in both of them.
+/// Quantiles are not really aggregatable, so this is always an approximation.
+fn merge_quantiles(val1: &SummaryValue, val2: &SummaryValue, strategy: QuantileMergeStrategy) -> Vec<Quantile> {
+    let mut output = val1.quantiles.clone();
+    for new_quantile in val2.quantiles.iter() {
+        let existing = match output.iter_mut().find(|q| q.quantile == new_quantile.quantile) {
+            Some(existing) => existing,
+            None => {
+                output.push(new_quantile.clone());
+                continue;
+            }
+        };
 
-    for j in j..val2.len() {
-        output.push(val1[j].clone());
+        let (old_value, new_value) = (existing.value.as_f64(), new_quantile.value.as_f64());
+
+        // Summaries without any observations report NaN for their quantiles, so they shouldn't count towards the merged value
+        if new_value.is_nan() {
+            continue;
+        }
+
+        if old_value.is_nan() {
+            existing.value = new_quantile.value;
+            continue;
+        }
+
+        existing.value = match strategy {
+            QuantileMergeStrategy::Max => MetricNumber::Float(old_value.max(new_value)),
+            QuantileMergeStrategy::Weighted => {
+                let old_weight = val1.count.unwrap_or(0) as f64;
+                let new_weight = val2.count.unwrap_or(0) as f64;
+                if old_weight + new_weight == 0. {
+                    new_quantile.value
+                } else {
+                    MetricNumber::Float((old_value * old_weight + new_value * new_weight) / (old_weight + new_weight))
+                }
+            }
+        };
     }
 
-    return output;
+    output.sort_by(|a, b| a.quantile.partial_cmp(&b.quantile).unwrap_or(std::cmp::Ordering::Equal));
+    output
 }
 
 /// Merges two metrics into one another (using the given clearmode), storing the result in the first one.
-pub fn merge_metric(into: &mut Sample<GravelValue>, merge: Sample<GravelValue>, clear_mode: ClearMode) -> Result<(), AggregationError> {
+pub fn merge_metric(into: &mut Sample<GravelValue>, merge: Sample<GravelValue>, clear_mode: ClearMode, config: &AggregatorConfig) -> Result<(), AggregationError> {
+    let pebble_time = pebble_timestamp(merge.timestamp, config);
     match (&mut into.value, &merge.value) {
         (GravelValue::Prometheus(PrometheusValue::Unknown(val1)), GravelValue::Prometheus(PrometheusValue::Unknown(val2))) => {
             match clear_mode {
                 ClearMode::Aggregate => *val1 += val2,
                 ClearMode::Replace => *val1 = *val2,
-                _ => unreachable!()
+                _ => return Err(AggregationError::Error(format!("clearmode {:?} can't be used on a untyped metric that was first pushed without it", clear_mode)))
             }
         }
         (GravelValue::Prometheus(PrometheusValue::Gauge(val1)), GravelValue::Prometheus(PrometheusValue::Gauge(val2))) => {
             match clear_mode {
                 ClearMode::Aggregate => *val1 += val2,
                 ClearMode::Replace => *val1 = *val2,
-                _ => unreachable!()
+                _ => return Err(AggregationError::Error(format!("clearmode {:?} can't be used on a gauge that was first pushed without it", clear_mode)))
             }
         }
         (GravelValue::Prometheus(PrometheusValue::Counter(val1)), GravelValue::Prometheus(PrometheusValue::Counter(val2))) => {
@@ -226,7 +661,7 @@ pub fn merge_metric(into: &mut Sample<GravelValue>, merge: Sample<GravelValue>,
                     val1.value = val2.value;
                     val1.exemplar = val2.exemplar.clone();
                 },
-                _ => unreachable!()
+                _ => return Err(AggregationError::Error(format!("clearmode {:?} can't be used on a counter that was first pushed without it", clear_mode)))
             }
         }
         (GravelValue::Prometheus(PrometheusValue::Histogram(val1)), GravelValue::Prometheus(PrometheusValue::Histogram(val2))) => {
@@ -243,9 +678,9 @@ pub fn merge_metric(into: &mut Sample<GravelValue>, merge: Sample<GravelValue>,
             };
 
             let buckets = match clear_mode {
-                ClearMode::Aggregate => merge_buckets(&val1.buckets, &val2.buckets),
+                ClearMode::Aggregate => merge_buckets_with_policy(&val1.buckets, &val2.buckets, config.histogram_bucket_policy)?,
                 ClearMode::Replace => val2.buckets.clone(),
-                _ => unreachable!()
+                _ => return Err(AggregationError::Error(format!("clearmode {:?} is not supported for histograms", clear_mode)))
             };
 
             val1.sum = sum;
@@ -255,36 +690,160 @@ pub fn merge_metric(into: &mut Sample<GravelValue>, merge: Sample<GravelValue>,
         }
         (GravelValue::Pebble(time_pebble), GravelValue::Prometheus(p)) => {
             match p {
-                PrometheusValue::Counter(counter) => time_pebble.append(counter.value.as_f64()),
-                PrometheusValue::Gauge(gauge) => time_pebble.append(gauge.as_f64()),
+                PrometheusValue::Counter(counter) => time_pebble.append_with_timestamp(counter.value.as_f64(), pebble_time)?,
+                PrometheusValue::Gauge(gauge) => time_pebble.append_with_timestamp(gauge.as_f64(), pebble_time)?,
+                _ => {}
+            }
+        },
+        (GravelValue::HistogramPebble(time_pebble), GravelValue::Prometheus(PrometheusValue::Histogram(histogram))) => {
+            time_pebble.append_with_timestamp(histogram.clone(), pebble_time)?;
+        },
+        (GravelValue::Cumulative(cumulative), GravelValue::Prometheus(PrometheusValue::Counter(counter))) => {
+            cumulative.append(counter);
+        },
+        (GravelValue::Ewma(ewma), GravelValue::Prometheus(p)) => {
+            match p {
+                PrometheusValue::Counter(counter) => ewma.append_with_timestamp(counter.value.as_f64(), pebble_time),
+                PrometheusValue::Gauge(gauge) => ewma.append_with_timestamp(gauge.as_f64(), pebble_time),
                 _ => {}
             }
         },
-        (GravelValue::Prometheus(PrometheusValue::Summary(_)), GravelValue::Prometheus(PrometheusValue::Summary(_))) => return Err(AggregationError::Error("cannot merge summaries".to_string())),
+        (GravelValue::Prometheus(PrometheusValue::Summary(val1)), GravelValue::Prometheus(PrometheusValue::Summary(val2))) => {
+            match clear_mode {
+                ClearMode::Aggregate => {
+                    // The quantiles need to be merged before the counts, as the weighted strategy uses them
+                    val1.quantiles = merge_quantiles(val1, val2, config.quantile_merge_strategy);
+                    val1.sum = match (val1.sum, val2.sum) {
+                        (Some(a), Some(b)) => Some(a + b),
+                        _ => None,
+                    };
+                    val1.count = match (val1.count, val2.count) {
+                        (Some(a), Some(b)) => Some(a + b),
+                        _ => None,
+                    };
+                    val1.created = val2.created;
+                },
+                ClearMode::Replace => *val1 = val2.clone(),
+                _ => return Err(AggregationError::Error(format!("clearmode {:?} is not supported for summaries", clear_mode)))
+            }
+        },
+        (GravelValue::NativeHistogram(val1), GravelValue::NativeHistogram(val2)) => {
+            match clear_mode {
+                ClearMode::Aggregate => val1.merge(val2),
+                ClearMode::Replace => *val1 = val2.clone(),
+                _ => return Err(AggregationError::Error(format!("clearmode {:?} is not supported for native histograms", clear_mode)))
+            }
+        },
+        (GravelValue::NativeHistogram(_), _) | (_, GravelValue::NativeHistogram(_)) => {
+            return Err(AggregationError::Error("Native histograms can't be merged with classic histograms".to_owned()));
+        },
         _ => unreachable!(),
     };
 
+    into.timestamp = output_timestamp(merge.timestamp, &clear_mode, config);
     Ok(())
 }
 
+/// Converts a sample timestamp (in milliseconds, like the exposition formats use) into a SystemTime
+fn timestamp_to_system_time(millis: Timestamp) -> Option<SystemTime> {
+    if !millis.is_finite() || millis < 0. {
+        return None;
+    }
+
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs_f64(millis / 1000.))
+}
+
+/// Works out the time that a sample counts as when it's pushed into a pebble. That's the sample's own timestamp
+/// if we're configured to use them (and it has one), or the time it was pushed otherwise
+fn pebble_timestamp(timestamp: Option<Timestamp>, config: &AggregatorConfig) -> SystemTime {
+    match timestamp.and_then(timestamp_to_system_time) {
+        Some(time) if config.pebble_sample_timestamps => time,
+        _ => SystemTime::now(),
+    }
+}
+
+/// Works out the timestamp to expose for a merged sample. Aggregated samples combine pushes from any number
+/// of clients, so no single timestamp is meaningful for them, but replaced ones can keep theirs
+fn output_timestamp(timestamp: Option<Timestamp>, clear_mode: &ClearMode, config: &AggregatorConfig) -> Option<Timestamp> {
+    match (clear_mode, config.replace_timestamps) {
+        (ClearMode::Replace | ClearMode::Family, TimestampMode::Preserve) => timestamp,
+        _ => None,
+    }
+}
+
+/// Checks that the timestamp of a sample that's about to go into a pebble isn't too far away from our own clock.
+/// Samples that don't go into pebbles, or that go in at the time they were pushed, don't care about their timestamp
+fn validate_timestamp(timestamp: Option<Timestamp>, clear_mode: &ClearMode, config: &AggregatorConfig) -> Result<(), AggregationError> {
+    let millis = match timestamp {
+        Some(millis) if config.pebble_sample_timestamps && matches!(clear_mode, ClearMode::Mean(..) | ClearMode::Sum(..) | ClearMode::Ewma(_)) => millis,
+        _ => return Ok(()),
+    };
+
+    let time = timestamp_to_system_time(millis).ok_or_else(|| AggregationError::InvalidSample("invalid_timestamp", format!("Invalid sample timestamp {}", millis)))?;
+    let skew = match time.duration_since(SystemTime::now()) {
+        Ok(ahead) if ahead > config.future_timestamp_tolerance => {
+            return Err(AggregationError::InvalidSample("future_timestamp", format!(
+                "Sample timestamp {} is {}ms ahead of the gateway's clock, which is more than the allowed tolerance of {}ms",
+                millis, ahead.as_millis(), config.future_timestamp_tolerance.as_millis()
+            )));
+        },
+        Ok(ahead) => ahead,
+        Err(behind) => behind.duration(),
+    };
+
+    if skew > config.max_timestamp_skew {
+        return Err(AggregationError::InvalidSample("timestamp_skew", format!(
+            "Sample timestamp {} is {}ms away from the gateway's clock, which is more than the allowed skew of {}ms",
+            millis, skew.as_millis(), config.max_timestamp_skew.as_millis()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Orders two samples from the same family by their label values
+fn compare_label_values(a: &Sample<GravelValue>, b: &Sample<GravelValue>) -> std::cmp::Ordering {
+    match (a.get_labelset(), b.get_labelset()) {
+        (Ok(a), Ok(b)) => a.iter_values().cmp(b.iter_values()),
+        _ => std::cmp::Ordering::Equal,
+    }
+}
+
+/// Sorts the samples of a family by their label values, so that it always renders the same way. The samples
+/// before `sorted_up_to` must already be sorted. Pushes are usually sorted already, and series are added to
+/// existing families one at a time, so this is an insertion sort
+fn sort_samples(family: &mut GravelMetricFamily, sorted_up_to: usize) {
+    let mut samples: Vec<&mut Sample<GravelValue>> = family.iter_samples_mut().collect();
+    for i in sorted_up_to.max(1)..samples.len() {
+        let mut j = i;
+        while j > 0 && compare_label_values(samples[j - 1], samples[j]) == std::cmp::Ordering::Greater {
+            let (before, after) = samples.split_at_mut(j);
+            std::mem::swap(before[j - 1], after[0]);
+            j -= 1;
+        }
+    }
+}
+
 impl AggregationFamily {
     // Constructs a new AggregationFamily, over the given MetricFamily
-    fn new(base_family: PrometheusMetricFamily) -> Self {
-        let mut base_family: GravelMetricFamily = base_family.clone_and_convert_type();
+    fn new(mut base_family: GravelMetricFamily, config: &AggregatorConfig) -> Result<Self, AggregationError> {
         let family_type = base_family.family_type.clone();
         for metric in base_family.iter_samples_mut() {
-            let clear_mode = ClearMode::from_family(family_type.clone(), &metric);
-            metric.value = metric.value.clone().convert_with_clearmode(clear_mode);
+            let clear_mode = ClearMode::from_family(family_type.clone(), &metric)?;
+            let pebble_time = pebble_timestamp(metric.timestamp, config);
+            metric.timestamp = output_timestamp(metric.timestamp, &clear_mode, config);
+            metric.value = metric.value.clone().convert_with_clearmode(clear_mode, pebble_time, config);
         }
 
-        let base_family = base_family.without_label(CLEARMODE_LABEL_NAME).unwrap_or(base_family);
-        Self { base_family }
+        let mut base_family = base_family.without_label(CLEARMODE_LABEL_NAME).unwrap_or(base_family);
+        sort_samples(&mut base_family, 0);
+        Ok(Self { base_family })
     }
 
     /// Merges the given metrics family into this one, respecting (and then removing) the clear mode 
-    /// label from each sample
-    fn merge(&mut self, prom_family: PrometheusMetricFamily) -> Result<(), AggregationError> {
-        let new_family = prom_family.clone_and_convert_type();
+    /// label from each sample, and keeping the usage up to date with any series that are added or cleared. Returns
+    /// the reasons for any samples that were too late to merge, which are dropped
+    fn merge(&mut self, new_family: GravelMetricFamily, config: &AggregatorConfig, usage: &mut SeriesUsage) -> Result<Vec<&'static str>, AggregationError> {
         // Sanity checks to make sure that it makes sense to merge these families
         if new_family.family_name != self.base_family.family_name {
             return Err(AggregationError::Error(format!(
@@ -306,15 +865,23 @@ impl AggregationFamily {
         let old_is_empty = !self.base_family.iter_samples().any(|_| { true });
 
         // We should clear the whole family if any of the samples has a clearmode="family" label
-        let should_clear_family = new_family.iter_samples().any(|metric| {
-            ClearMode::from_family(new_family.family_type.clone(), metric) == ClearMode::Family
-        });
+        let mut should_clear_family = false;
+        for metric in new_family.iter_samples() {
+            if ClearMode::from_family(new_family.family_type.clone(), metric)? == ClearMode::Family {
+                should_clear_family = true;
+            }
+        }
 
+        let mut dropped = Vec::new();
         if new_is_empty {
-            return Ok(())
+            return Ok(dropped)
         }
         else if old_is_empty || should_clear_family {
-            self.base_family = new_family.without_label(CLEARMODE_LABEL_NAME).unwrap_or(new_family);
+            // Build the family from scratch so that any pebbles in it get set up
+            let base_family = AggregationFamily::new(new_family, config)?.base_family;
+            usage.remove_family(&self.base_family);
+            usage.add_family(&base_family);
+            self.base_family = base_family;
         }
         else {
             if !are_label_names_equivalent(self.base_family.get_label_names(), new_family.get_label_names()) {
@@ -328,31 +895,375 @@ impl AggregationFamily {
 
                 // We want to compare without the clearmode label - it's not stored, so doesn't exist in our internal representation
                 let cmp_metric = metric.without_label(CLEARMODE_LABEL_NAME).unwrap_or(metric.clone());
-                let clear_mode = ClearMode::from_family(self.base_family.family_type.clone(), &metric);
+                let clear_mode = ClearMode::from_family(self.base_family.family_type.clone(), &metric)?;
                 match self.base_family.get_sample_matches_mut(&cmp_metric)
                 {
                     None => {
                         // Just add the metric if its a new labelset
-                        self.base_family.add_sample(cmp_metric)?
+                        let mut cmp_metric = cmp_metric;
+                        let pebble_time = pebble_timestamp(cmp_metric.timestamp, config);
+                        cmp_metric.timestamp = output_timestamp(cmp_metric.timestamp, &clear_mode, config);
+                        cmp_metric.value = cmp_metric.value.convert_with_clearmode(clear_mode, pebble_time, config);
+                        let job = job_label(&cmp_metric);
+                        self.base_family.add_sample(cmp_metric)?;
+                        usage.add(&job);
+                        let last = self.base_family.iter_samples().count() - 1;
+                        sort_samples(&mut self.base_family, last);
                     },
                     Some(s) => {
-                        // Otherwise we have to merge
-                        merge_metric(s, metric, clear_mode)?;
+                        // Otherwise we have to merge. Samples that are too old for their pebble are dropped
+                        // no matter the validation mode, as it's normal for a slow pusher to race the window
+                        match merge_metric(s, metric, clear_mode, config) {
+                            Ok(()) => {},
+                            Err(AggregationError::InvalidSample(reason @ "too_old", _)) => dropped.push(reason),
+                            Err(e) => return Err(e),
+                        }
                     }
                 }
             }
         }
         
-        return Ok(());
+        return Ok(dropped);
+    }
+
+    /// Returns a copy of this family with only the series that match at least one of the given selectors
+    /// (or all of them, if there aren't any selectors), or None if none of them do
+    fn matching(&self, selectors: &[Selector]) -> Option<AggregationFamily> {
+        let family = &self.base_family;
+        let samples: Vec<Sample<GravelValue>> = family.iter_samples()
+            .filter(|sample| selectors.is_empty() || match sample.get_labelset() {
+                Ok(labels) => selectors.iter().any(|selector| selector.matches(&family.family_name, &labels)),
+                Err(_) => false,
+            })
+            .cloned()
+            .collect();
+
+        if samples.is_empty() && !selectors.is_empty() {
+            return None;
+        }
+
+        let base_family = GravelMetricFamily::new(family.family_name.clone(), family.get_label_names().to_vec(), family.family_type.clone(), family.help.clone(), family.unit.clone());
+        // The samples came out of a family, so they can't clash
+        Some(AggregationFamily { base_family: base_family.with_samples(samples).expect("failed to copy samples") })
+    }
+
+    /// Returns a copy of this family without the given labels, with the series that only differed by them added
+    /// together. Only counters, histograms, and summaries can be added up like this, so None is returned for any other
+    /// family (or one with pebbles in it), or if the family doesn't have any of the labels
+    fn without_labels(&self, labels: &[String], config: &AggregatorConfig) -> Option<AggregationFamily> {
+        let family = &self.base_family;
+        let kept_labels: Vec<String> = family.get_label_names().iter().filter(|name| !labels.contains(name)).cloned().collect();
+        if kept_labels.len() == family.get_label_names().len() {
+            return None;
+        }
+
+        let mut grouped: BTreeMap<Vec<String>, Sample<GravelValue>> = BTreeMap::new();
+        for sample in family.iter_samples() {
+            let value = match &sample.value {
+                GravelValue::Prometheus(value @ (PrometheusValue::Counter(_) | PrometheusValue::Histogram(_) | PrometheusValue::Summary(_))) => GravelValue::Prometheus(value.clone()),
+                GravelValue::NativeHistogram(histogram) => GravelValue::NativeHistogram(histogram.clone()),
+                GravelValue::Cumulative(cumulative) => GravelValue::Prometheus(PrometheusValue::Counter(cumulative.to_counter())),
+                _ => return None,
+            };
+
+            let sample_labels = sample.get_labelset().ok()?;
+            let label_values: Vec<String> = kept_labels.iter().map(|name| sample_labels.get_label_value(name).unwrap_or("").to_owned()).collect();
+            let new_sample = Sample::new(label_values.clone(), None, value);
+            match grouped.get_mut(&label_values) {
+                // Histograms with different buckets can fail to merge, in which case the family is left as is
+                Some(into) => merge_metric(into, new_sample, ClearMode::Aggregate, config).ok()?,
+                None => {
+                    grouped.insert(label_values, new_sample);
+                },
+            }
+        }
+
+        let base_family = GravelMetricFamily::new(family.family_name.clone(), kept_labels, family.family_type.clone(), family.help.clone(), family.unit.clone());
+        // The grouping means that every sample has different label values, so they can't clash
+        let mut base_family = base_family.with_samples(grouped.into_values()).expect("failed to group samples");
+        sort_samples(&mut base_family, 0);
+        Some(AggregationFamily { base_family })
+    }
+
+    /// Returns whether any of the series in this family are pebbles, whose value depends on when they're looked at
+    fn has_pebbles(&self) -> bool {
+        self.base_family.iter_samples().any(|sample| matches!(sample.value, GravelValue::Pebble(_) | GravelValue::HistogramPebble(_) | GravelValue::Ewma(_)))
+    }
+
+    /// Builds companion series describing the window of every pebble in this family, so that e.g. a mean
+    /// over a single sample can be told apart from a mean over thousands
+    fn window_series_families(&self) -> Vec<PrometheusMetricFamily> {
+        let new_family = |suffix: &str, help: &str| {
+            PrometheusMetricFamily::new(format!("{}_{}", self.base_family.family_name, suffix), self.base_family.get_label_names().to_vec(), PrometheusType::Gauge, help.to_owned(), String::new())
+        };
+
+        let mut count = new_family("window_count", "The number of samples in the pebble window");
+        let mut min = new_family("window_min", "The smallest sample in the pebble window");
+        let mut max = new_family("window_max", "The largest sample in the pebble window");
+        let mut start = new_family("window_start_timestamp_seconds", "The start of the oldest bucket in the pebble window that holds data");
+
+        let mut has_pebbles = false;
+        for sample in self.base_family.iter_samples() {
+            let stats = match &sample.value {
+                GravelValue::Pebble(pebble) => pebble.window_stats(),
+                GravelValue::HistogramPebble(pebble) => pebble.window_stats(),
+                _ => continue
+            };
+
+            let label_values: Vec<String> = match sample.get_labelset() {
+                Ok(labelset) => labelset.iter_values().cloned().collect(),
+                Err(_) => continue
+            };
+
+            has_pebbles = true;
+            let add_sample = |family: &mut PrometheusMetricFamily, value: MetricNumber| {
+                // The label values come straight out of the base family, so they're guaranteed to be unique and the right length
+                family.add_sample(Sample::new(label_values.clone(), None, PrometheusValue::Gauge(value))).expect("invalid window series sample");
+            };
+
+            add_sample(&mut count, MetricNumber::Int(stats.count as i64));
+            if let Some(value) = stats.min {
+                add_sample(&mut min, MetricNumber::Float(value));
+            }
+
+            if let Some(value) = stats.max {
+                add_sample(&mut max, MetricNumber::Float(value));
+            }
+
+            if let Some(time) = stats.start {
+                add_sample(&mut start, MetricNumber::Float(time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64()));
+            }
+        }
+
+        if !has_pebbles {
+            return Vec::new();
+        }
+
+        vec![count, min, max, start].into_iter().filter(|family| family.iter_samples().next().is_some()).collect()
+    }
+}
+
+/// The settings that control how an Aggregator merges new metrics into itself
+#[derive(Debug, Clone)]
+pub struct AggregatorConfig {
+    /// The number of buckets that pebbles are split into, unless their clearmode specifies otherwise
+    pub pebble_granularity: usize,
+    /// The most buckets that a clearmode can ask for its pebbles to be split into
+    pub max_pebble_granularity: usize,
+    /// Whether to expose the count, min, max, and start time of each pebble's window as extra series
+    pub pebble_window_series: bool,
+    /// How to combine the quantiles of summaries that are aggregated together
+    pub quantile_merge_strategy: QuantileMergeStrategy,
+    /// What to do when a histogram is pushed with different buckets to the existing one
+    pub histogram_bucket_policy: HistogramBucketPolicy,
+    /// Whether invalid samples reject the whole push, or are dropped
+    pub validation_mode: ValidationMode,
+    /// Whether pebbles put samples with timestamps into the bucket for that time, rather than the time they were pushed
+    pub pebble_sample_timestamps: bool,
+    /// How far a sample timestamp can be from our own clock before the sample is rejected, when using sample timestamps
+    pub max_timestamp_skew: Duration,
+    /// How far ahead of our own clock a sample timestamp can be before the sample is rejected, when using sample timestamps
+    pub future_timestamp_tolerance: Duration,
+    /// Whether replaced samples keep the timestamp they were pushed with
+    pub replace_timestamps: TimestampMode,
+    /// The most series (label sets) that a single family can have
+    pub max_series_per_family: Option<usize>,
+    /// The most series that can have the same job label, across all families. Only the job label counts, not the
+    /// rest of the grouping key that the series were pushed with
+    pub max_series_per_job_label: Option<usize>,
+    /// The most series that the gateway will hold in total
+    pub max_series: Option<usize>,
+    /// The most families that a single push can contain
+    pub max_families_per_push: Option<usize>,
+    /// The most samples that a single push can contain
+    pub max_samples_per_push: Option<usize>,
+    /// The longest label name that a push can contain
+    pub max_label_name_length: Option<usize>,
+    /// The longest label value that a push can contain
+    pub max_label_value_length: Option<usize>,
+    /// Prometheus style relabeling that's applied to every pushed series before it's merged
+    pub relabel_configs: Vec<RelabelConfig>,
+}
+
+impl Default for AggregatorConfig {
+    fn default() -> Self {
+        AggregatorConfig {
+            pebble_granularity: 100,
+            max_pebble_granularity: 1000,
+            pebble_window_series: false,
+            quantile_merge_strategy: QuantileMergeStrategy::Max,
+            histogram_bucket_policy: HistogramBucketPolicy::Union,
+            validation_mode: ValidationMode::Strict,
+            pebble_sample_timestamps: false,
+            max_timestamp_skew: Duration::from_secs(5 * 60),
+            future_timestamp_tolerance: Duration::from_secs(60),
+            replace_timestamps: TimestampMode::Strip,
+            max_series_per_family: None,
+            max_series_per_job_label: None,
+            max_series: None,
+            max_families_per_push: None,
+            max_samples_per_push: None,
+            max_label_name_length: None,
+            max_label_value_length: None,
+            relabel_configs: Vec::new(),
+        }
+    }
+}
+
+/// Renders of an aggregator, by format and whether they're gzipped, along with the generation they were rendered at
+type RenderCache = HashMap<(ExpositionFormat, bool), (u64, Bytes)>;
+
+/// What a scrape asked to see, beyond the format it wants it in
+#[derive(Debug, Default)]
+pub struct ScrapeQuery {
+    /// Only series that match at least one of these are rendered, or every series if there aren't any
+    pub selectors: Vec<Selector>,
+    /// Labels to remove, adding together the series that only differed by them
+    pub without: Vec<String>,
+}
+
+/// The families in a push, parsed but not merged yet, so that they can be checked first
+pub struct ParsedPush {
+    families: Vec<(String, GravelMetricFamily)>,
+}
+
+impl ParsedPush {
+    /// Parses a push in the Prometheus text exposition format, adding the given labels to every sample. It's parsed a
+    /// family at a time, so that a push that's over the per push limits in the config is rejected without parsing all of it
+    pub fn parse(s: &str, extra_labels: &HashMap<&str, &str>, config: &AggregatorConfig) -> Result<ParsedPush, AggregationError> {
+        let mut limiter = PushLimiter::new(config);
+        let mut families = Vec::new();
+        for (start_line, chunk) in family_chunks(s) {
+            let metrics = prometheus::parse_prometheus(chunk).map_err(|e| match e {
+                // Positions in syntax errors are relative to the chunk, so say where the chunk is
+                ParseError::ParseError(e) if start_line > 1 => AggregationError::Error(format!("In the family starting on line {}: {}", start_line, e)),
+                e => AggregationError::ParseError(e),
+            })?;
+
+            for (name, family) in add_extra_labels(metrics, extra_labels)?.families {
+                let family = family.clone_and_convert_type();
+                limiter.add_family(&name, &family)?;
+                families.push((name, family));
+            }
+        }
+
+        Ok(ParsedPush { families })
+    }
+
+    /// Decodes a push in the protobuf exposition format, adding the given labels to every sample. Like parse, the
+    /// per push limits are checked as each family is decoded
+    pub fn parse_protobuf(data: &[u8], extra_labels: &HashMap<&str, &str>, config: &AggregatorConfig) -> Result<ParsedPush, AggregationError> {
+        let mut limiter = PushLimiter::new(config);
+        let families = protobuf::decode_families(data, extra_labels, |family| limiter.add_family(&family.family_name, family))?;
+        Ok(ParsedPush { families: families.into_iter().map(|family| (family.family_name.clone(), family)).collect() })
+    }
+
+    pub fn family_names(&self) -> impl Iterator<Item = &str> {
+        self.families.iter().map(|(name, _)| name.as_str())
     }
 }
 
+/// Splits a text exposition into the parts of it that belong to each family, going by their HELP and TYPE lines,
+/// along with the line that each part starts on
+fn family_chunks(s: &str) -> Vec<(usize, &str)> {
+    let mut chunks = Vec::new();
+    let (mut start, mut start_line, mut offset) = (0, 1, 0);
+    let mut current_family = None;
+    for (i, line) in s.split_inclusive('\n').enumerate() {
+        let metadata_name = line.strip_prefix("# HELP ").or_else(|| line.strip_prefix("# TYPE ")).and_then(|rest| rest.split_whitespace().next());
+        if let Some(name) = metadata_name {
+            let new_family = match current_family {
+                Some(current_family) => current_family != name,
+                // Samples before the first HELP or TYPE line are untyped families of their own
+                None => s[start..offset].lines().any(|line| !line.trim().is_empty() && !line.starts_with('#')),
+            };
+
+            if new_family {
+                chunks.push((start_line, &s[start..offset]));
+                start = offset;
+                start_line = i + 1;
+            }
+
+            current_family = Some(name);
+        }
+
+        offset += line.len();
+    }
+
+    chunks.push((start_line, &s[start..]));
+    chunks
+}
+
+/// The formats that the aggregator can be rendered in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExpositionFormat {
+    Text,
+    Protobuf,
+}
+
 /// Aggregator is an struct that stores a number of metric families, and has the ability to merge
 /// new metric families into itself
 #[derive(Debug, Clone)]
 pub struct Aggregator {
-    /// The families in this Aggregator
-    families: Arc<RwLock<HashMap<String, AggregationFamily>>>,
+    /// The families in this Aggregator, sorted by name so that they always render in the same order
+    families: Arc<RwLock<BTreeMap<String, AggregationFamily>>>,
+    /// The number of samples that have been dropped in lenient mode, by family and reason
+    dropped_samples: Arc<RwLock<BTreeMap<(String, &'static str), u64>>>,
+    /// Bumped every time the families are written to, so that renders of them can be cached until they change
+    generation: Arc<AtomicU64>,
+    /// The last render in each format, along with the generation it was rendered at
+    render_cache: Arc<Mutex<RenderCache>>,
+    /// The number of series in the families, kept up to date as series are added and cleared. It's only
+    /// changed while the families are write locked
+    series_usage: Arc<Mutex<SeriesUsage>>,
+    config: Arc<AggregatorConfig>,
+}
+
+/// The number of series held by the aggregator, in total and for each job
+#[derive(Debug, Default)]
+struct SeriesUsage {
+    total: usize,
+    per_job: BTreeMap<String, usize>,
+}
+
+impl SeriesUsage {
+    fn job(&self, job: &str) -> usize {
+        self.per_job.get(job).copied().unwrap_or(0)
+    }
+
+    fn add(&mut self, job: &str) {
+        self.total += 1;
+        *self.per_job.entry(job.to_owned()).or_insert(0) += 1;
+    }
+
+    fn remove(&mut self, job: &str) {
+        self.total -= 1;
+        if let Some(count) = self.per_job.get_mut(job) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_job.remove(job);
+            }
+        }
+    }
+
+    fn add_family(&mut self, family: &GravelMetricFamily) {
+        for sample in family.iter_samples() {
+            self.add(&job_label(sample));
+        }
+    }
+
+    fn remove_family(&mut self, family: &GravelMetricFamily) {
+        for sample in family.iter_samples() {
+            self.remove(&job_label(sample));
+        }
+    }
+}
+
+/// Returns the value of the job label of the given sample, or an empty string if it doesn't have one
+fn job_label(sample: &Sample<GravelValue>) -> String {
+    sample.get_labelset().ok()
+        .and_then(|labels| labels.get_label_value("job").map(|job| job.to_owned()))
+        .unwrap_or_default()
 }
 
 /// A utility function that adds a set of labels to all the metrics in an exposition
@@ -389,44 +1300,420 @@ fn are_label_names_equivalent(existing: &[String], new: &[String]) -> bool {
     return true;
 }
 
+/// Counts what's been parsed of a push so far, so that the per push limits can be checked as it's parsed, rather
+/// than once all of it is in memory
+struct PushLimiter<'a> {
+    config: &'a AggregatorConfig,
+    /// The names of the families that have been parsed, which can't come up again
+    families: HashSet<String>,
+    samples: usize,
+}
+
+impl<'a> PushLimiter<'a> {
+    fn new(config: &'a AggregatorConfig) -> PushLimiter<'a> {
+        PushLimiter { config, families: HashSet::new(), samples: 0 }
+    }
+
+    /// Checks the next family of the push
+    fn add_family(&mut self, name: &str, family: &GravelMetricFamily) -> Result<(), AggregationError> {
+        if !self.families.insert(name.to_owned()) {
+            return Err(AggregationError::Error(format!("Family {} appears more than once in the push", name)));
+        }
+
+        if let Some(limit) = self.config.max_families_per_push.filter(|&limit| self.families.len() > limit) {
+            return Err(AggregationError::LimitExceeded(format!("Push has more families than the limit of {}", limit)));
+        }
+
+        if let Some(limit) = self.config.max_label_name_length {
+            if let Some(label_name) = family.get_label_names().iter().find(|label_name| label_name.len() > limit) {
+                return Err(AggregationError::LimitExceeded(format!("Label name {} in family {} is longer than the limit of {}", label_name, name, limit)));
+            }
+        }
+
+        for sample in family.iter_samples() {
+            self.samples += 1;
+            if let Some(limit) = self.config.max_samples_per_push.filter(|&limit| self.samples > limit) {
+                return Err(AggregationError::LimitExceeded(format!("Push has more samples than the limit of {}", limit)));
+            }
+
+            if let (Some(limit), Ok(labels)) = (self.config.max_label_value_length, sample.get_labelset()) {
+                if let Some((label_name, _)) = labels.iter().find(|(_, value)| value.len() > limit) {
+                    return Err(AggregationError::LimitExceeded(format!("Value of label {} in family {} is longer than the limit of {}", label_name, name, limit)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that merging the given families wouldn't take the aggregator over any of its series limits, given the
+/// series that it already holds. This is done before anything is merged, so that a push that's over the limit is
+/// rejected as a whole. Only the families in the push are looked at, not every series in the aggregator
+fn check_series_limits(families: &BTreeMap<String, AggregationFamily>, usage: &SeriesUsage, new_families: &[(String, GravelMetricFamily)], config: &AggregatorConfig) -> Result<(), AggregationError> {
+    if config.max_series_per_family.is_none() && config.max_series_per_job_label.is_none() && config.max_series.is_none() {
+        return Ok(());
+    }
+
+    // The series that the push would add, and the ones that it would clear
+    let mut added = SeriesUsage::default();
+    let mut removed = SeriesUsage::default();
+    for (name, new_family) in new_families {
+        if new_family.iter_samples().next().is_none() {
+            continue;
+        }
+
+        // Families that are new, or being cleared, end up with just the series in this push
+        let existing = families.get(name).map(|family| &family.base_family).filter(|family| family.iter_samples().next().is_some());
+        let clears_family = new_family.iter_samples().any(|sample| matches!(ClearMode::from_family(new_family.family_type.clone(), sample), Ok(ClearMode::Family)));
+        let existing = match existing {
+            Some(existing) if clears_family => {
+                removed.add_family(existing);
+                None
+            },
+            existing => existing,
+        };
+
+        let mut family_series = existing.map(|family| family.iter_samples().count()).unwrap_or(0);
+        for sample in new_family.iter_samples() {
+            let cmp_sample = sample.without_label(CLEARMODE_LABEL_NAME).unwrap_or(sample.clone());
+            if existing.is_some_and(|existing| existing.get_sample_matches(&cmp_sample).is_some()) {
+                continue;
+            }
+
+            let job = job_label(sample);
+            family_series += 1;
+            added.add(&job);
+
+            // Cleared series are all already counted in the usage, so this can't underflow
+            if let Some(limit) = config.max_series_per_job_label.filter(|&limit| usage.job(&job) + added.job(&job) - removed.job(&job) > limit) {
+                return Err(AggregationError::LimitExceeded(format!("Family {} would take job \"{}\" over its limit of {} series", name, job, limit)));
+            }
+
+            if let Some(limit) = config.max_series.filter(|&limit| usage.total + added.total - removed.total > limit) {
+                return Err(AggregationError::LimitExceeded(format!("Family {} would take the gateway over its limit of {} series", name, limit)));
+            }
+        }
+
+        if let Some(limit) = config.max_series_per_family.filter(|&limit| family_series > limit) {
+            return Err(AggregationError::LimitExceeded(format!("Family {} would have {} series, which is over the limit of {}", name, family_series, limit)));
+        }
+    }
+
+    Ok(())
+}
+
 impl Aggregator {
+    #[cfg(test)]
     pub fn new() -> Aggregator {
-        return Aggregator {
-            families: Arc::new(RwLock::new(HashMap::new())),
-        };
+        Aggregator::new_with_config(AggregatorConfig::default())
+    }
+
+    pub fn new_with_config(config: AggregatorConfig) -> Aggregator {
+        Aggregator {
+            families: Arc::new(RwLock::new(BTreeMap::new())),
+            dropped_samples: Arc::new(RwLock::new(BTreeMap::new())),
+            generation: Arc::new(AtomicU64::new(0)),
+            render_cache: Arc::new(Mutex::new(HashMap::new())),
+            series_usage: Arc::new(Mutex::new(SeriesUsage::default())),
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn config(&self) -> &AggregatorConfig {
+        &self.config
     }
 
     /// Takes a string representing a Prometheus exposition format, parses that and 
     /// merges the metrics into this aggregator
+    #[cfg(test)]
     pub async fn parse_and_merge(&mut self, s: &str, extra_labels: &HashMap<&str, &str>) -> Result<(), AggregationError> {
-        let metrics = add_extra_labels(prometheus::parse_prometheus(&s)?, extra_labels)?;
+        self.merge(ParsedPush::parse(s, extra_labels, &self.config)?).await
+    }
+
+    /// Takes a protobuf exposition (which can contain native histograms), decodes that and
+    /// merges the metrics into this aggregator
+    #[cfg(test)]
+    pub async fn parse_and_merge_protobuf(&mut self, data: &[u8], extra_labels: &HashMap<&str, &str>) -> Result<(), AggregationError> {
+        self.merge(ParsedPush::parse_protobuf(data, extra_labels, &self.config)?).await
+    }
+
+    /// Merges a push that's already been parsed into this aggregator
+    pub async fn merge(&mut self, push: ParsedPush) -> Result<(), AggregationError> {
+        self.merge_families(push.families).await
+    }
+
+    async fn merge_families(&mut self, new_families: Vec<(String, GravelMetricFamily)>) -> Result<(), AggregationError> {
+        // Relabeling happens first, so that anything it drops doesn't count towards the series limits
+        let new_families = relabel_families(new_families, &self.config.relabel_configs)?;
+
+        // Everything is validated up front, so that a push with an invalid sample doesn't get partially merged
+        let mut valid_families = Vec::with_capacity(new_families.len());
+        let mut dropped_samples = Vec::new();
+        for (name, family) in new_families {
+            let had_samples = family.iter_samples().next().is_some();
+            let (family, dropped) = validate_family(family, &self.config)?;
+            let has_samples = family.iter_samples().next().is_some();
+            dropped_samples.extend(dropped.into_iter().map(|reason| (name.clone(), reason)));
+
+            // If every sample was dropped, there's nothing left to merge
+            if has_samples || !had_samples {
+                valid_families.push((name, family));
+            }
+        }
+
+        if !dropped_samples.is_empty() {
+            let mut dropped_counts = self.dropped_samples.write().await;
+            for key in dropped_samples {
+                *dropped_counts.entry(key).or_insert(0) += 1;
+            }
+        }
+
         let mut families = self.families.write().await;
+        // Even a push that fails part way through can have changed things, so anything rendered before now is stale
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let late_samples = self.merge_valid_families(&mut families, valid_families)?;
+
+        if !late_samples.is_empty() {
+            let mut dropped_counts = self.dropped_samples.write().await;
+            for key in late_samples {
+                *dropped_counts.entry(key).or_insert(0) += 1;
+            }
+        }
 
-        for (name, metrics) in metrics.families {
+        return Ok(());
+    }
+
+    /// Merges families that have already been validated into the given (locked) families, returning the reasons
+    /// for any samples that were dropped for being too late
+    fn merge_valid_families(&self, families: &mut BTreeMap<String, AggregationFamily>, valid_families: Vec<(String, GravelMetricFamily)>) -> Result<Vec<(String, &'static str)>, AggregationError> {
+        let mut usage = self.series_usage.lock().unwrap();
+        check_series_limits(families, &usage, &valid_families, &self.config)?;
+        let mut late_samples = Vec::new();
+
+        for (name, metrics) in valid_families {
             match families.get_mut(&name) {
                 Some(f) => {
                     // If we have the family already, merge this new stuff into it.
-                    f.merge(metrics)?;
+                    let dropped = f.merge(metrics, &self.config, &mut usage)?;
+                    late_samples.extend(dropped.into_iter().map(|reason| (name.clone(), reason)));
                 }
                 None => {
                     // Otherwise, just add the new family
-                    families.insert(name, AggregationFamily::new(metrics));
+                    let family = AggregationFamily::new(metrics, &self.config)?;
+                    usage.add_family(&family.base_family);
+                    families.insert(name, family);
                 }
             }
         }
 
-        return Ok(());
+        Ok(late_samples)
     }
 
     /// Converts this aggregator into a Prometheus text exposition format
     /// that can be scraped by a Prometheus
+    #[cfg(test)]
     pub async fn to_string(&self) -> String {
         let families = self.families.read().await;
+        self.render_text(&families)
+    }
+
+    fn render_text(&self, families: &BTreeMap<String, AggregationFamily>) -> String {
         let mut family_strings = String::new();
         for (_, family) in families.iter() {
             family_strings.push_str(&family.base_family.to_string());
+            if self.config.pebble_window_series {
+                for window_family in family.window_series_families() {
+                    family_strings.push_str(&window_family.to_string());
+                }
+            }
         }
 
         family_strings
     }
+
+    /// Converts this aggregator into a Prometheus protobuf exposition, which unlike the text format
+    /// can carry the buckets of native histograms
+    #[cfg(test)]
+    pub async fn to_protobuf(&self) -> Vec<u8> {
+        let families = self.families.read().await;
+        self.render_protobuf(&families)
+    }
+
+    fn render_protobuf(&self, families: &BTreeMap<String, AggregationFamily>) -> Vec<u8> {
+        let mut window_families: Vec<GravelMetricFamily> = Vec::new();
+        if self.config.pebble_window_series {
+            for family in families.values() {
+                window_families.extend(family.window_series_families().iter().map(|window_family| window_family.clone_and_convert_type()));
+            }
+        }
+
+        protobuf::encode_families(families.values().map(|family| &family.base_family).chain(window_families.iter()))
+    }
+
+    /// Renders this aggregator in the given format, optionally gzipped. The render is reused until something new is
+    /// merged, unless the aggregator holds any pebbles, as their windows move along with the time rather than pushes
+    pub async fn render(&self, format: ExpositionFormat, gzip: bool) -> Bytes {
+        // Holding the read lock stops the generation from changing underneath us
+        let families = self.families.read().await;
+        let generation = self.generation.load(Ordering::SeqCst);
+        let key = (format, gzip);
+        if let Some((rendered_generation, body)) = self.render_cache.lock().unwrap().get(&key) {
+            if *rendered_generation == generation {
+                return body.clone();
+            }
+        }
+
+        let body = self.render_families(&families, format, gzip);
+        if !families.values().any(|family| family.has_pebbles()) {
+            self.render_cache.lock().unwrap().insert(key, (generation, body.clone()));
+        }
+
+        body
+    }
+
+    /// Renders the view of this aggregator that a scrape asked for, which is only the series that match at least
+    /// one of its selectors, with its `without` labels aggregated away. Families without any matching series are
+    /// left out. Scrapes that don't ask for anything in particular get the normal (cached) render
+    pub async fn render_query(&self, format: ExpositionFormat, gzip: bool, query: &ScrapeQuery) -> Bytes {
+        if query.selectors.is_empty() && query.without.is_empty() {
+            return self.render(format, gzip).await;
+        }
+
+        let families = self.families.read().await;
+        let view: BTreeMap<String, AggregationFamily> = families.iter()
+            .filter_map(|(name, family)| {
+                let family = family.matching(&query.selectors)?;
+                let family = family.without_labels(&query.without, &self.config).unwrap_or(family);
+                Some((name.clone(), family))
+            })
+            .collect();
+
+        self.render_families(&view, format, gzip)
+    }
+
+    fn render_families(&self, families: &BTreeMap<String, AggregationFamily>, format: ExpositionFormat, gzip: bool) -> Bytes {
+        let body = match format {
+            ExpositionFormat::Text => self.render_text(families).into_bytes(),
+            ExpositionFormat::Protobuf => self.render_protobuf(families),
+        };
+
+        Bytes::from(if gzip { compression::gzip(&body) } else { body })
+    }
+
+    /// Renders metrics about the state of this aggregator itself, in the Prometheus text exposition format
+    pub async fn self_metrics_string(&self) -> String {
+        let mut metrics = AggregatorSelfMetrics::new();
+        let families = self.families.read().await;
+        for (name, family) in families.iter() {
+            let memory: Vec<usize> = family.base_family.iter_samples().filter_map(|sample| sample.value.pebble_memory_bytes()).collect();
+            if memory.is_empty() {
+                continue;
+            }
+
+            metrics.add(SelfMetric::PebbleCount, &[name], memory.len());
+            metrics.add(SelfMetric::PebbleMemory, &[name], memory.iter().sum());
+        }
+
+        for ((name, reason), count) in self.dropped_samples.read().await.iter() {
+            metrics.add(SelfMetric::DroppedSamples, &[name, reason], *count as usize);
+        }
+
+        for (name, family) in families.iter() {
+            metrics.add(SelfMetric::Series, &[name], family.base_family.iter_samples().count());
+        }
+
+        for (job, count) in self.series_usage.lock().unwrap().per_job.iter() {
+            metrics.add(SelfMetric::JobSeries, &[job], *count);
+        }
+
+        for (scope, limit) in self.series_limits() {
+            metrics.add(SelfMetric::SeriesLimit, &[scope], limit);
+        }
+
+        metrics.to_string()
+    }
+
+    /// Totals of the self metrics of this aggregator, without any of the family names or label values that have been pushed
+    /// to it, so that they can be shown for tenants without giving away what they're pushing
+    pub async fn self_metric_totals(&self) -> SelfMetricTotals {
+        let families = self.families.read().await;
+        let memory: Vec<usize> = families.values().flat_map(|family| family.base_family.iter_samples()).filter_map(|sample| sample.value.pebble_memory_bytes()).collect();
+        let mut dropped_samples = BTreeMap::new();
+        for ((_, reason), count) in self.dropped_samples.read().await.iter() {
+            *dropped_samples.entry(*reason).or_default() += *count as usize;
+        }
+
+        SelfMetricTotals {
+            pebbles: memory.len(),
+            pebble_memory_bytes: memory.iter().sum(),
+            dropped_samples,
+            series: self.series_usage.lock().unwrap().total,
+            series_limits: self.series_limits(),
+        }
+    }
+
+    /// The series limits that are set, by their scope
+    fn series_limits(&self) -> Vec<(&'static str, usize)> {
+        let limits = [("family", self.config.max_series_per_family), ("job", self.config.max_series_per_job_label), ("total", self.config.max_series)];
+        limits.iter().filter_map(|(scope, limit)| limit.map(|limit| (*scope, limit))).collect()
+    }
+}
+
+/// The totals of an aggregator's self metrics, across all of its families
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfMetricTotals {
+    pub pebbles: usize,
+    pub pebble_memory_bytes: usize,
+    /// The number of dropped samples, by the reason they were dropped
+    pub dropped_samples: BTreeMap<&'static str, usize>,
+    pub series: usize,
+    /// The series limits that are set, by their scope
+    pub series_limits: Vec<(&'static str, usize)>,
+}
+
+/// The self metrics that each aggregator has
+#[derive(Debug, Clone, Copy)]
+enum SelfMetric {
+    PebbleCount,
+    PebbleMemory,
+    DroppedSamples,
+    Series,
+    JobSeries,
+    SeriesLimit,
+}
+
+/// The self metrics of an aggregator
+struct AggregatorSelfMetrics {
+    families: Vec<SelfMetricFamily>,
+}
+
+impl AggregatorSelfMetrics {
+    fn new() -> AggregatorSelfMetrics {
+        // In the same order as SelfMetric
+        AggregatorSelfMetrics {
+            families: vec![
+                SelfMetricFamily::new("gravel_pebbles", "The number of pebbles in each family", PrometheusType::Gauge, &["family"]),
+                SelfMetricFamily::new("gravel_pebble_memory_bytes", "The approximate memory used by the pebbles in each family", PrometheusType::Gauge, &["family"]),
+                SelfMetricFamily::new("gravel_dropped_samples_total", "The number of samples that have been dropped, either for being invalid in lenient mode or too late", PrometheusType::Counter, &["family", "reason"]),
+                SelfMetricFamily::new("gravel_series", "The number of series in each family", PrometheusType::Gauge, &["family"]),
+                SelfMetricFamily::new("gravel_job_series", "The number of series with each job label, across all families", PrometheusType::Gauge, &["job"]),
+                SelfMetricFamily::new("gravel_series_limit", "The configured limits on the number of series", PrometheusType::Gauge, &["scope"]),
+            ],
+        }
+    }
+
+    fn add(&mut self, metric: SelfMetric, label_values: &[&str], value: usize) {
+        self.families[metric as usize].add_sample(label_values, MetricNumber::Int(value as i64));
+    }
+}
+
+impl fmt::Display for AggregatorSelfMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for family in self.families.iter() {
+            family.fmt(f)?;
+        }
+
+        Ok(())
+    }
 }
@@ -1,7 +1,7 @@
-use openmetrics_parser::{Exemplar, MetricNumber, PrometheusCounterValue, PrometheusValue, Sample};
+use openmetrics_parser::{Exemplar, HistogramBucket, MetricNumber, PrometheusCounterValue, PrometheusValue, Sample};
 
 use crate::aggregator::*;
-use std::{collections::HashMap, str::FromStr};
+use std::{collections::HashMap, str::FromStr, time::Duration};
 
 #[test]
 fn test_clear_mode_parsing() {
@@ -14,6 +14,24 @@ fn test_clear_mode_parsing() {
     assert!(ClearMode::from_str("family").is_ok());
     assert_eq!(ClearMode::from_str("family").unwrap(), ClearMode::Family);
 
+    assert_eq!(ClearMode::from_str("cumulative").unwrap(), ClearMode::Cumulative);
+
+    assert!(ClearMode::from_str("ewma5m").is_ok());
+    assert_eq!(ClearMode::from_str("ewma5m").unwrap(), ClearMode::Ewma(Duration::from_secs(300)));
+
+    assert_eq!(ClearMode::from_str("mean1h").unwrap(), ClearMode::Mean(Duration::from_secs(3600), None));
+    assert_eq!(ClearMode::from_str("mean1h@60").unwrap(), ClearMode::Mean(Duration::from_secs(3600), Some(60)));
+    assert_eq!(ClearMode::from_str("sum5m@10").unwrap(), ClearMode::Sum(Duration::from_secs(300), Some(10)));
+    assert!(ClearMode::from_str("mean1h@0").is_err());
+    assert!(ClearMode::from_str("mean1h@foo").is_err());
+    assert!(ClearMode::from_str("ewma5m@10").is_err());
+
+    assert_eq!(ClearMode::from_str("mean1h30m").unwrap(), ClearMode::Mean(Duration::from_secs(90 * 60), None));
+    assert_eq!(ClearMode::from_str("sum7d").unwrap(), ClearMode::Sum(Duration::from_secs(7 * 24 * 60 * 60), None));
+    assert_eq!(ClearMode::from_str("mean500ms@10").unwrap(), ClearMode::Mean(Duration::from_millis(500), Some(10)));
+    assert!(ClearMode::from_str("mean1h30").is_err());
+    assert!(ClearMode::from_str("meanfoo").is_err());
+
     assert!(ClearMode::from_str("foo").is_err());
 }
 
@@ -22,7 +40,7 @@ fn test_clear_mode_replace() {
     let mut sample = Sample::new(vec![], None, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(1))));
     merge_metric(&mut sample, 
                 Sample::new(vec![], None, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(2)))),
-                      ClearMode::Replace).unwrap();
+                      ClearMode::Replace, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(2))));
 
@@ -43,7 +61,7 @@ fn test_clear_mode_replace() {
                         }),
                     }
                 ))),
-                ClearMode::Replace).unwrap();
+                ClearMode::Replace, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Counter(PrometheusCounterValue{
         value: MetricNumber::Int(1000),
@@ -70,7 +88,7 @@ fn test_clear_mode_replace() {
                         exemplar: None
                     }
                 ))),
-                ClearMode::Replace).unwrap();
+                ClearMode::Replace, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Counter(PrometheusCounterValue{
         value: MetricNumber::Int(1000),
@@ -83,7 +101,7 @@ fn test_clear_mode_aggregate() {
     let mut sample = Sample::new(vec![], None, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(1))));
     merge_metric(&mut sample, 
                 Sample::new(vec![], None, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(2)))),
-                      ClearMode::Aggregate).unwrap();
+                      ClearMode::Aggregate, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(3))));
 
@@ -104,7 +122,7 @@ fn test_clear_mode_aggregate() {
                         }),
                     }
                 ))),
-                ClearMode::Aggregate).unwrap();
+                ClearMode::Aggregate, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Counter(PrometheusCounterValue{
         value: MetricNumber::Int(1001),
@@ -131,7 +149,7 @@ fn test_clear_mode_aggregate() {
                         exemplar: None
                     }
                 ))),
-                ClearMode::Aggregate).unwrap();
+                ClearMode::Aggregate, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Counter(PrometheusCounterValue{
         value: MetricNumber::Int(1001),
@@ -244,3 +262,750 @@ async fn test_openmetrics_parser_2() {
 
     assert!(result.is_ok(), "failed to parse valid metric: {:?}", result.err());
 }
+
+#[tokio::test]
+async fn test_histogram_pebble() {
+    let mut agg = Aggregator::new();
+    let push = "# TYPE request_latency histogram
+request_latency_bucket{clearmode=\"sum15m\",le=\"0.5\"} 1
+request_latency_bucket{clearmode=\"sum15m\",le=\"+Inf\"} 2
+request_latency_sum{clearmode=\"sum15m\"} 1.5
+request_latency_count{clearmode=\"sum15m\"} 2
+";
+
+    agg.parse_and_merge(push, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(push, &HashMap::new()).await.unwrap();
+
+    let output = agg.to_string().await;
+    assert_eq!(output, "# TYPE request_latency histogram
+request_latency_bucket{le=\"0.5\"} 2
+request_latency_bucket{le=\"+Inf\"} 4
+request_latency_sum 3
+request_latency_count 4
+");
+
+    // Histograms don't have a mean, so a mean window is rejected rather than quietly summed
+    let mut agg = Aggregator::new();
+    let result = agg.parse_and_merge(&push.replace("sum15m", "mean15m"), &HashMap::new()).await;
+    assert!(result.unwrap_err().to_string().contains("not supported for histograms"));
+    assert_eq!(agg.to_string().await, "");
+}
+
+#[tokio::test]
+async fn test_pebble_self_metrics() {
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        pebble_granularity: 10,
+        ..Default::default()
+    });
+
+    agg.parse_and_merge("# TYPE memory_bytes gauge\nmemory_bytes{clearmode=\"mean5m\",function=\"a\"} 1\nmemory_bytes{clearmode=\"mean5m\",function=\"b\"} 1\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 1\n", &HashMap::new()).await.unwrap();
+
+    let output = agg.self_metrics_string().await;
+    assert!(output.contains("gravel_pebbles{family=\"memory_bytes\"} 2\n"), "missing pebble count in {}", output);
+    assert!(output.contains("gravel_pebble_memory_bytes{family=\"memory_bytes\"}"), "missing pebble memory in {}", output);
+    assert!(!output.contains("gravel_pebbles{family=\"requests_total\"}"), "non-pebble families shouldn't be reported in {}", output);
+    assert!(!output.contains("gravel_pebble_memory_bytes{family=\"requests_total\"}"), "non-pebble families shouldn't be reported in {}", output);
+}
+
+#[tokio::test]
+async fn test_pebble_window_series() {
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        pebble_window_series: true,
+        ..Default::default()
+    });
+
+    agg.parse_and_merge("# TYPE memory_bytes gauge\nmemory_bytes{clearmode=\"mean5m\"} 1\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE memory_bytes gauge\nmemory_bytes{clearmode=\"mean5m\"} 3\n", &HashMap::new()).await.unwrap();
+
+    let output = agg.to_string().await;
+    assert!(output.starts_with("# TYPE memory_bytes gauge\nmemory_bytes 2\n"), "unexpected pebble value in {}", output);
+    assert!(output.contains("memory_bytes_window_count 2\n"), "missing window count in {}", output);
+    assert!(output.contains("memory_bytes_window_min 1\n"), "missing window min in {}", output);
+    assert!(output.contains("memory_bytes_window_max 3\n"), "missing window max in {}", output);
+    assert!(output.contains("memory_bytes_window_start_timestamp_seconds "), "missing window start in {}", output);
+
+    // Without the option, only the pebble itself should be exposed
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE memory_bytes gauge\nmemory_bytes{clearmode=\"mean5m\"} 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE memory_bytes gauge\nmemory_bytes 1\n");
+}
+
+#[tokio::test]
+async fn test_invalid_clearmode_is_rejected() {
+    let mut agg = Aggregator::new();
+    let result = agg.parse_and_merge("memory_bytes{clearmode=\"mean5x\"} 1\n", &HashMap::new()).await;
+    assert!(result.is_err(), "invalid clearmode should be rejected");
+    assert!(result.unwrap_err().to_string().contains("unknown unit `x`"));
+
+    // The same should be true for families that already exist
+    agg.parse_and_merge("memory_bytes{clearmode=\"mean5m\"} 1\n", &HashMap::new()).await.unwrap();
+    assert!(agg.parse_and_merge("memory_bytes{clearmode=\"mean5m30h\"} 1\n", &HashMap::new()).await.is_err());
+
+    // Switching an existing series to a clearmode that keeps a window is an error, not a panic
+    for type_line in &["# TYPE temperature gauge\n", ""] {
+        let mut agg = Aggregator::new();
+        agg.parse_and_merge(&format!("{}temperature 1\n", type_line), &HashMap::new()).await.unwrap();
+        assert!(agg.parse_and_merge(&format!("{}temperature{{clearmode=\"ewma5m\"}} 1\n", type_line), &HashMap::new()).await.is_err());
+        assert!(agg.parse_and_merge(&format!("{}temperature{{clearmode=\"mean5m\"}} 1\n", type_line), &HashMap::new()).await.is_err());
+        assert!(agg.to_string().await.ends_with("temperature 1\n"));
+    }
+}
+
+#[tokio::test]
+async fn test_max_pebble_granularity() {
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        max_pebble_granularity: 60,
+        ..Default::default()
+    });
+
+    agg.parse_and_merge("# TYPE memory_bytes gauge\nmemory_bytes{clearmode=\"mean1h@60\"} 1\n", &HashMap::new()).await.unwrap();
+    for push in &["memory_bytes{clearmode=\"mean1h@61\"} 1\n", "latency{clearmode=\"sum1h@1000000000\"} 1\n"] {
+        let result = agg.parse_and_merge(push, &HashMap::new()).await;
+        assert!(result.unwrap_err().to_string().starts_with("Invalid pebble granularity"), "{} should be rejected", push);
+    }
+
+    assert_eq!(agg.to_string().await, "# TYPE memory_bytes gauge\nmemory_bytes 1\n");
+}
+
+#[tokio::test]
+async fn test_summary_aggregation() {
+    let first_push = "# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile=\"0.5\"} 1
+rpc_duration_seconds{quantile=\"0.99\"} 4
+rpc_duration_seconds_sum 10
+rpc_duration_seconds_count 5
+";
+
+    let second_push = "# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile=\"0.5\"} 3
+rpc_duration_seconds{quantile=\"0.99\"} 2
+rpc_duration_seconds_sum 20
+rpc_duration_seconds_count 15
+";
+
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        quantile_merge_strategy: QuantileMergeStrategy::Max,
+        ..Default::default()
+    });
+    agg.parse_and_merge(first_push, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(second_push, &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile=\"0.5\"} 3
+rpc_duration_seconds{quantile=\"0.99\"} 4
+rpc_duration_seconds_sum 30
+rpc_duration_seconds_count 20
+");
+
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        quantile_merge_strategy: QuantileMergeStrategy::Weighted,
+        ..Default::default()
+    });
+    agg.parse_and_merge(first_push, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(second_push, &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile=\"0.5\"} 2.5
+rpc_duration_seconds{quantile=\"0.99\"} 2.5
+rpc_duration_seconds_sum 30
+rpc_duration_seconds_count 20
+");
+
+    // Replace should just take the newest summary
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge(first_push, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{clearmode=\"replace\",quantile=\"0.5\"} 3
+rpc_duration_seconds{clearmode=\"replace\",quantile=\"0.99\"} 2
+rpc_duration_seconds_sum{clearmode=\"replace\"} 20
+rpc_duration_seconds_count{clearmode=\"replace\"} 15
+", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, second_push);
+}
+
+#[tokio::test]
+async fn test_histogram_bucket_policies() {
+    let first_push = "# TYPE request_seconds histogram
+request_seconds_bucket{le=\"1\"} 1
+request_seconds_bucket{le=\"5\"} 3
+request_seconds_bucket{le=\"+Inf\"} 4
+request_seconds_sum 10
+request_seconds_count 4
+";
+
+    let second_push = "# TYPE request_seconds histogram
+request_seconds_bucket{le=\"2\"} 2
+request_seconds_bucket{le=\"5\"} 5
+request_seconds_bucket{le=\"+Inf\"} 6
+request_seconds_sum 20
+request_seconds_count 6
+";
+
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        histogram_bucket_policy: HistogramBucketPolicy::Union,
+        ..Default::default()
+    });
+    agg.parse_and_merge(first_push, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(second_push, &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE request_seconds histogram
+request_seconds_bucket{le=\"1\"} 1
+request_seconds_bucket{le=\"2\"} 3
+request_seconds_bucket{le=\"5\"} 8
+request_seconds_bucket{le=\"+Inf\"} 10
+request_seconds_sum 30
+request_seconds_count 10
+");
+
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        histogram_bucket_policy: HistogramBucketPolicy::Existing,
+        ..Default::default()
+    });
+    agg.parse_and_merge(first_push, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(second_push, &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE request_seconds histogram
+request_seconds_bucket{le=\"1\"} 1
+request_seconds_bucket{le=\"5\"} 8
+request_seconds_bucket{le=\"+Inf\"} 10
+request_seconds_sum 30
+request_seconds_count 10
+");
+
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        histogram_bucket_policy: HistogramBucketPolicy::Reject,
+        ..Default::default()
+    });
+    agg.parse_and_merge(first_push, &HashMap::new()).await.unwrap();
+    assert!(agg.parse_and_merge(second_push, &HashMap::new()).await.is_err());
+    // Matching layouts are still fine
+    assert!(agg.parse_and_merge(first_push, &HashMap::new()).await.is_ok());
+}
+
+#[test]
+fn test_merge_bucket_tails() {
+    let buckets = |bounds: &[(f64, i64)]| -> Vec<HistogramBucket> {
+        bounds.iter().map(|&(upper_bound, count)| HistogramBucket { count: MetricNumber::Int(count), upper_bound, exemplar: None }).collect()
+    };
+
+    // Whichever side runs out of buckets first, the other side's remaining buckets are carried over from that side
+    let short = buckets(&[(1., 1)]);
+    let long = buckets(&[(1., 2), (5., 3), (10., 4)]);
+    let expected = buckets(&[(1., 3), (5., 4), (10., 5)]);
+    assert_eq!(merge_buckets(&short, &long), expected);
+    assert_eq!(merge_buckets(&long, &short), expected);
+}
+
+#[tokio::test]
+async fn test_unsorted_histogram_is_rejected() {
+    let push = "# TYPE request_seconds histogram
+request_seconds_bucket{le=\"5\"} 1
+request_seconds_bucket{le=\"1\"} 1
+request_seconds_bucket{le=\"+Inf\"} 4
+request_seconds_sum 10
+request_seconds_count 4
+";
+
+    let mut agg = Aggregator::new();
+    assert!(agg.parse_and_merge(push, &HashMap::new()).await.is_err());
+    assert_eq!(agg.to_string().await, "");
+}
+
+#[tokio::test]
+async fn test_native_histogram_protobuf_round_trip() {
+    use crate::protobuf::{BucketSpan, Histogram, Metric, MetricFamily, MetricType, LabelPair};
+    use prost::Message;
+
+    let push = |schema: i32, spans: Vec<BucketSpan>, deltas: Vec<i64>, count: u64| {
+        let family = MetricFamily {
+            name: String::from("request_seconds"),
+            help: String::from("How long requests take"),
+            r#type: MetricType::Histogram as i32,
+            metric: vec![Metric {
+                label: vec![LabelPair { name: String::from("path"), value: String::from("/") }],
+                histogram: Some(Histogram {
+                    sample_count: count,
+                    sample_sum: 10.,
+                    schema,
+                    zero_threshold: 0.001,
+                    positive_span: spans,
+                    positive_delta: deltas,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            unit: String::new(),
+        };
+
+        let mut data = Vec::new();
+        family.encode_length_delimited(&mut data).unwrap();
+        data
+    };
+
+    let mut agg = Aggregator::new();
+    // Buckets 1 and 2 with counts 1 and 3, at schema 1
+    agg.parse_and_merge_protobuf(&push(1, vec![BucketSpan { offset: 1, length: 2 }], vec![1, 2], 4), &HashMap::from([("job", "test")])).await.unwrap();
+    // Buckets 1 and 3 with counts 2 and 2, at schema 0
+    agg.parse_and_merge_protobuf(&push(0, vec![BucketSpan { offset: 1, length: 1 }, BucketSpan { offset: 1, length: 1 }], vec![2, 0], 4), &HashMap::from([("job", "test")])).await.unwrap();
+
+    // The text format can't show native buckets, so only gets the count and sum
+    assert_eq!(agg.to_string().await, "# HELP request_seconds How long requests take
+# TYPE request_seconds histogram
+request_seconds_bucket{job=\"test\",path=\"/\",le=\"+Inf\"} 8
+request_seconds_sum{job=\"test\",path=\"/\"} 20
+request_seconds_count{job=\"test\",path=\"/\"} 8
+");
+
+    let family = MetricFamily::decode_length_delimited(agg.to_protobuf().await.as_slice()).unwrap();
+    let histogram = family.metric[0].histogram.clone().unwrap();
+    // Both pushes end up at schema 0, where the first push's buckets 1 and 2 both become bucket 1
+    assert_eq!(histogram.schema, 0);
+    assert_eq!(histogram.sample_count, 8);
+    assert_eq!(histogram.positive_span, vec![BucketSpan { offset: 1, length: 1 }, BucketSpan { offset: 1, length: 1 }]);
+    assert_eq!(histogram.positive_delta, vec![6, -4]);
+
+    // Spans that don't match the counts are rejected before they're expanded, however many buckets they claim
+    assert!(agg.parse_and_merge_protobuf(&push(0, vec![BucketSpan { offset: 0, length: u32::MAX }], vec![1], 1), &HashMap::new()).await.is_err());
+    assert!(agg.parse_and_merge_protobuf(&push(0, vec![BucketSpan { offset: i32::MAX, length: 1 }, BucketSpan { offset: 1, length: 1 }], vec![1, 1], 2), &HashMap::new()).await.is_err());
+    assert!(agg.parse_and_merge_protobuf(&push(0, vec![BucketSpan { offset: 0, length: 2 }], vec![i64::MAX, 1], 2), &HashMap::new()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_cumulative_counter() {
+    let push = |value: u64| format!("# TYPE requests_total counter
+requests_total{{clearmode=\"cumulative\"}} {}
+", value);
+
+    let mut agg = Aggregator::new();
+    for value in [10, 15, 3, 5] {
+        agg.parse_and_merge(&push(value), &HashMap::new()).await.unwrap();
+    }
+
+    // 10, plus 5 more, then a restart that reported 3, plus 2 more
+    assert_eq!(agg.to_string().await, "# TYPE requests_total counter
+requests_total 20
+");
+
+    // Cumulative only makes sense for counters
+    let gauge = "# TYPE temperature gauge
+temperature{clearmode=\"cumulative\"} 10
+";
+    assert!(agg.parse_and_merge(gauge, &HashMap::new()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_unsupported_clearmodes_are_rejected_on_first_push() {
+    let histogram = |clearmode: &str| format!("# TYPE request_seconds histogram
+request_seconds_bucket{{clearmode=\"{0}\",le=\"+Inf\"}} 1
+request_seconds_sum{{clearmode=\"{0}\"}} 1
+request_seconds_count{{clearmode=\"{0}\"}} 1
+", clearmode);
+
+    let pushes = vec![
+        histogram("cumulative"),
+        histogram("ewma5m"),
+        "# TYPE rpc_seconds summary\nrpc_seconds{clearmode=\"ewma5m\",quantile=\"0.5\"} 1\nrpc_seconds_sum{clearmode=\"ewma5m\"} 1\nrpc_seconds_count{clearmode=\"ewma5m\"} 1\n".to_owned(),
+        "temperature{clearmode=\"ewma5m\"} 1\n".to_owned(),
+    ];
+
+    for push in pushes {
+        let mut agg = Aggregator::new();
+        let result = agg.parse_and_merge(&push, &HashMap::new()).await;
+        assert!(result.unwrap_err().to_string().contains("is only supported for counters"), "{} should be rejected", push);
+        assert_eq!(agg.to_string().await, "");
+    }
+}
+
+#[tokio::test]
+async fn test_invalid_samples() {
+    use crate::protobuf::{Counter, Metric, MetricFamily, MetricType, LabelPair};
+    use prost::Message;
+
+    // The text parser already rejects negative counters, so these have to come in through protobuf
+    let counter = |path: &str, value: f64| Metric {
+        label: vec![LabelPair { name: String::from("path"), value: path.to_owned() }],
+        counter: Some(Counter { value, exemplar: None }),
+        ..Default::default()
+    };
+
+    let mut push = Vec::new();
+    MetricFamily {
+        name: String::from("requests_total"),
+        help: String::new(),
+        r#type: MetricType::Counter as i32,
+        metric: vec![counter("/", 5.), counter("/login", -5.), counter("/logout", f64::NAN)],
+        unit: String::new(),
+    }.encode_length_delimited(&mut push).unwrap();
+
+    let mut agg = Aggregator::new();
+    match agg.parse_and_merge_protobuf(&push, &HashMap::new()).await {
+        Err(e) => assert_eq!(e.to_string(), "Counters can't be negative, got -5"),
+        Ok(_) => panic!("negative counter was accepted")
+    }
+
+    // Nothing from a rejected push gets merged
+    assert_eq!(agg.to_string().await, "");
+
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        validation_mode: ValidationMode::Lenient,
+        ..Default::default()
+    });
+
+    agg.parse_and_merge_protobuf(&push, &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE requests_total counter
+requests_total{path=\"/\"} 5
+");
+
+    let self_metrics = agg.self_metrics_string().await;
+    assert!(self_metrics.contains("gravel_dropped_samples_total{family=\"requests_total\",reason=\"negative_counter\"} 1\n"));
+    assert!(self_metrics.contains("gravel_dropped_samples_total{family=\"requests_total\",reason=\"non_finite_counter\"} 1\n"));
+
+    let histogram = "# TYPE request_seconds histogram
+request_seconds_bucket{le=\"1\"} 3
+request_seconds_bucket{le=\"5\"} 2
+request_seconds_bucket{le=\"+Inf\"} 4
+request_seconds_sum 10
+request_seconds_count 4
+";
+    assert!(Aggregator::new().parse_and_merge(histogram, &HashMap::new()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_invalid_protobuf_names() {
+    use crate::protobuf::{Gauge, Metric, MetricFamily, MetricType, LabelPair};
+    use prost::Message;
+
+    let push = |family_name: &str, label_name: &str| {
+        let mut push = Vec::new();
+        MetricFamily {
+            name: family_name.to_owned(),
+            help: String::new(),
+            r#type: MetricType::Gauge as i32,
+            metric: vec![Metric {
+                label: vec![LabelPair { name: label_name.to_owned(), value: String::from("a") }],
+                gauge: Some(Gauge { value: 1. }),
+                ..Default::default()
+            }],
+            unit: String::new(),
+        }.encode_length_delimited(&mut push).unwrap();
+        push
+    };
+
+    let mut agg = Aggregator::new();
+    assert!(agg.parse_and_merge_protobuf(&push("evil 1\ninjected_metric", "path"), &HashMap::new()).await.is_err());
+    assert!(agg.parse_and_merge_protobuf(&push("1_temperature", "path"), &HashMap::new()).await.is_err());
+    assert!(agg.parse_and_merge_protobuf(&push("temperature", "bad label\""), &HashMap::new()).await.is_err());
+    assert!(agg.parse_and_merge_protobuf(&push("temperature", "job:name"), &HashMap::new()).await.is_err());
+    assert_eq!(agg.to_string().await, "");
+
+    agg.parse_and_merge_protobuf(&push("node:temperature", "_path"), &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE node:temperature gauge
+node:temperature{_path=\"a\"} 1
+");
+}
+
+#[tokio::test]
+async fn test_replace_timestamps() {
+    let push = |timestamp: u64| format!("# TYPE temperature gauge
+temperature 10 {}
+# TYPE requests_total counter
+requests_total 5 {}
+", timestamp, timestamp);
+
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge(&push(1000), &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(&push(2000), &HashMap::new()).await.unwrap();
+    let output = agg.to_string().await;
+    assert!(output.contains("temperature 10\n"));
+    assert!(output.contains("requests_total 10\n"));
+
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        replace_timestamps: TimestampMode::Preserve,
+        ..Default::default()
+    });
+    agg.parse_and_merge(&push(1000), &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(&push(2000), &HashMap::new()).await.unwrap();
+    let output = agg.to_string().await;
+    // Replaced samples keep the latest timestamp, but aggregated ones never have one
+    assert!(output.contains("temperature 10 2000\n"));
+    assert!(output.contains("requests_total 10\n"));
+}
+
+#[tokio::test]
+async fn test_pebble_sample_timestamps() {
+    let now_millis = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let push = |value: u64, timestamp: u64| format!("# TYPE requests_total counter
+requests_total{{clearmode=\"sum15m\"}} {} {}
+", value, timestamp);
+
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        pebble_sample_timestamps: true,
+        max_timestamp_skew: Duration::from_secs(60 * 60),
+        ..Default::default()
+    });
+
+    // The first push is from 20 minutes ago, so has fallen out of the 15 minute window by the time of the second one
+    agg.parse_and_merge(&push(5, now_millis - 20 * 60 * 1000), &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(&push(3, now_millis), &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE requests_total counter
+requests_total 3
+");
+
+    // Samples too far away from our clock are rejected
+    match agg.parse_and_merge(&push(3, now_millis - 2 * 60 * 60 * 1000), &HashMap::new()).await {
+        Err(e) => assert!(e.to_string().contains("more than the allowed skew")),
+        Ok(_) => panic!("skewed timestamp was accepted")
+    }
+}
+
+#[tokio::test]
+async fn test_late_pebble_samples() {
+    let now_millis = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let push = |value: u64, timestamp: u64| format!("# TYPE requests_total counter
+requests_total{{clearmode=\"sum15m\"}} {} {}
+", value, timestamp);
+
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        pebble_sample_timestamps: true,
+        max_timestamp_skew: Duration::from_secs(60 * 60),
+        ..Default::default()
+    });
+
+    // A late sample inside the window is still counted
+    agg.parse_and_merge(&push(3, now_millis), &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(&push(2, now_millis - 5 * 60 * 1000), &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE requests_total counter
+requests_total 5
+");
+
+    // One from before the window is dropped, even in strict mode, and shows up in the self metrics
+    agg.parse_and_merge(&push(7, now_millis - 20 * 60 * 1000), &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE requests_total counter
+requests_total 5
+");
+    let self_metrics = agg.self_metrics_string().await;
+    assert!(self_metrics.contains("gravel_dropped_samples_total{family=\"requests_total\",reason=\"too_old\"} 1\n"));
+
+    // Samples from too far in the future are rejected
+    match agg.parse_and_merge(&push(3, now_millis + 5 * 60 * 1000), &HashMap::new()).await {
+        Err(e) => assert!(e.to_string().contains("ahead of the gateway's clock")),
+        Ok(_) => panic!("future timestamp was accepted")
+    }
+}
+
+#[tokio::test]
+async fn test_series_limits() {
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        max_series_per_family: Some(2),
+        max_series_per_job_label: Some(3),
+        max_series: Some(4),
+        ..Default::default()
+    });
+    let job = |name: &'static str| {
+        let mut labels = HashMap::new();
+        labels.insert("job", name);
+        labels
+    };
+
+    agg.parse_and_merge("requests_total{path=\"/a\"} 1\nrequests_total{path=\"/b\"} 1\n", &job("api")).await.unwrap();
+    // Series that already exist don't count against the limit
+    agg.parse_and_merge("requests_total{path=\"/a\"} 1\n", &job("api")).await.unwrap();
+
+    match agg.parse_and_merge("requests_total{path=\"/c\"} 1\n", &job("api")).await {
+        Err(AggregationError::LimitExceeded(e)) => assert_eq!(e, "Family requests_total would have 3 series, which is over the limit of 2"),
+        other => panic!("expected the family limit to be hit, got {:?}", other)
+    }
+
+    agg.parse_and_merge("errors_total 1\n", &job("api")).await.unwrap();
+    match agg.parse_and_merge("latency 1\n", &job("api")).await {
+        Err(AggregationError::LimitExceeded(e)) => assert_eq!(e, "Family latency would take job \"api\" over its limit of 3 series"),
+        other => panic!("expected the job limit to be hit, got {:?}", other)
+    }
+
+    agg.parse_and_merge("latency 1\n", &job("batch")).await.unwrap();
+    match agg.parse_and_merge("queue_length 1\n", &job("batch")).await {
+        Err(AggregationError::LimitExceeded(e)) => assert_eq!(e, "Family queue_length would take the gateway over its limit of 4 series"),
+        other => panic!("expected the total limit to be hit, got {:?}", other)
+    }
+
+    // Clearing a family frees up its series
+    agg.parse_and_merge("requests_total{path=\"/c\",clearmode=\"family\"} 1\n", &job("api")).await.unwrap();
+    agg.parse_and_merge("queue_length 1\n", &job("batch")).await.unwrap();
+
+    let self_metrics = agg.self_metrics_string().await;
+    assert!(self_metrics.contains("gravel_series{family=\"requests_total\"} 1\n"));
+    assert!(self_metrics.contains("gravel_job_series{job=\"api\"} 2\n"));
+    assert!(self_metrics.contains("gravel_job_series{job=\"batch\"} 2\n"));
+    assert!(self_metrics.contains("gravel_series_limit{scope=\"total\"} 4\n"));
+
+    // Every grouping key with the same job label shares the job's limit
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        max_series_per_job_label: Some(1),
+        ..Default::default()
+    });
+    let instance = |name: &'static str| {
+        let mut labels = job("api");
+        labels.insert("instance", name);
+        labels
+    };
+
+    agg.parse_and_merge("up 1\n", &instance("a")).await.unwrap();
+    assert!(matches!(agg.parse_and_merge("jobs_running 1\n", &instance("b")).await, Err(AggregationError::LimitExceeded(_))));
+
+    // Clearing a family into another job frees up the old job's series, and it stops being reported
+    agg.parse_and_merge("up{clearmode=\"family\"} 1\n", &job("batch")).await.unwrap();
+    assert!(!agg.self_metrics_string().await.contains("job=\"api\""));
+    agg.parse_and_merge("jobs_running 1\n", &instance("b")).await.unwrap();
+
+    let self_metrics = agg.self_metrics_string().await;
+    assert!(self_metrics.contains("gravel_job_series{job=\"api\"} 1\n"));
+    assert!(self_metrics.contains("gravel_job_series{job=\"batch\"} 1\n"));
+}
+
+#[tokio::test]
+async fn test_push_limits() {
+    let mut agg = Aggregator::new_with_config(AggregatorConfig {
+        max_families_per_push: Some(2),
+        max_samples_per_push: Some(3),
+        max_label_name_length: Some(8),
+        max_label_value_length: Some(16),
+        ..Default::default()
+    });
+
+    agg.parse_and_merge("# TYPE a gauge\na{path=\"/\"} 1\n# TYPE b gauge\nb{path=\"/a\"} 1\nb{path=\"/b\"} 1\n", &HashMap::new()).await.unwrap();
+
+    match agg.parse_and_merge("# TYPE a gauge\na 1\n# TYPE b gauge\nb 1\n# TYPE c gauge\nc 1\n", &HashMap::new()).await {
+        Err(AggregationError::LimitExceeded(e)) => assert_eq!(e, "Push has more families than the limit of 2"),
+        other => panic!("expected the family limit to be hit, got {:?}", other)
+    }
+
+    // Pushes are limited as they're parsed, so nothing after the limit is even looked at
+    match agg.parse_and_merge("# TYPE a gauge\na 1\n# TYPE b gauge\nb 1\n# TYPE c gauge\nc 1\n# TYPE d gauge\nd{{{ not a sample\n", &HashMap::new()).await {
+        Err(AggregationError::LimitExceeded(e)) => assert_eq!(e, "Push has more families than the limit of 2"),
+        other => panic!("expected the family limit to be hit, got {:?}", other)
+    }
+
+    match agg.parse_and_merge("a{path=\"/a\"} 1\na{path=\"/b\"} 1\na{path=\"/c\"} 1\na{path=\"/d\"} 1\n", &HashMap::new()).await {
+        Err(AggregationError::LimitExceeded(e)) => assert_eq!(e, "Push has more samples than the limit of 3"),
+        other => panic!("expected the sample limit to be hit, got {:?}", other)
+    }
+
+    match agg.parse_and_merge("a{request_path=\"/\"} 1\n", &HashMap::new()).await {
+        Err(AggregationError::LimitExceeded(e)) => assert_eq!(e, "Label name request_path in family a is longer than the limit of 8"),
+        other => panic!("expected the label name limit to be hit, got {:?}", other)
+    }
+
+    match agg.parse_and_merge("a{path=\"/a/very/long/path\"} 1\n", &HashMap::new()).await {
+        Err(AggregationError::LimitExceeded(e)) => assert_eq!(e, "Value of label path in family a is longer than the limit of 16"),
+        other => panic!("expected the label value limit to be hit, got {:?}", other)
+    }
+}
+
+#[tokio::test]
+async fn test_parse_by_family() {
+    let mut agg = Aggregator::new();
+    // Untyped samples before the first TYPE line, and comments, are kept with the families around them
+    agg.parse_and_merge("# A comment\nuntyped 1\n# HELP a Help for a\n# TYPE a gauge\na 1\n# TYPE b_total counter\nb_total 2\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# HELP a Help for a\n# TYPE a gauge\na 1\n# TYPE b_total counter\nb_total 2\nuntyped 1\n");
+
+    // Families can't be split up
+    assert!(agg.parse_and_merge("# TYPE a gauge\na 1\n# TYPE b gauge\nb 1\n# TYPE a gauge\na 2\n", &HashMap::new()).await.is_err());
+
+    // Syntax errors after the first family say where the family starts, as their positions are relative to it
+    match agg.parse_and_merge("# TYPE a gauge\na 1\n# TYPE b gauge\nb{{{ 1\n", &HashMap::new()).await {
+        Err(e) => assert!(e.to_string().starts_with("In the family starting on line 3: "), "{}", e.to_string()),
+        Ok(_) => panic!("invalid push was accepted")
+    }
+}
+
+#[tokio::test]
+async fn test_render_cache() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 1\n", &HashMap::new()).await.unwrap();
+
+    let first = agg.render(ExpositionFormat::Text, false).await;
+    assert_eq!(first, "# TYPE requests_total counter\nrequests_total 1\n");
+    // Nothing has changed, so the same render should be handed back
+    assert_eq!(agg.render(ExpositionFormat::Text, false).await.as_ptr(), first.as_ptr());
+
+    // Merging invalidates the cache
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.render(ExpositionFormat::Text, false).await, "# TYPE requests_total counter\nrequests_total 2\n");
+
+    // Each format and encoding is cached separately
+    let gzipped = agg.render(ExpositionFormat::Text, true).await;
+    assert_eq!(crate::compression::decompress(gzipped.to_vec(), "gzip", 1024).unwrap(), b"# TYPE requests_total counter\nrequests_total 2\n");
+
+    // Pebble windows move with time rather than with merges, so renders with pebbles in them aren't reused
+    agg.parse_and_merge("# TYPE memory_bytes gauge\nmemory_bytes{clearmode=\"mean5m\"} 1\n", &HashMap::new()).await.unwrap();
+    let first = agg.render(ExpositionFormat::Text, false).await;
+    assert_ne!(agg.render(ExpositionFormat::Text, false).await.as_ptr(), first.as_ptr());
+}
+
+#[tokio::test]
+async fn test_sorted_output() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE zebras gauge
+zebras{zoo=\"b\"} 1
+zebras{zoo=\"a\"} 2
+# TYPE aardvarks gauge
+aardvarks 3
+", &HashMap::new()).await.unwrap();
+
+    // New series are slotted into place, rather than added to the end
+    agg.parse_and_merge("# TYPE zebras gauge
+zebras{zoo=\"c\"} 4
+zebras{zoo=\"aa\"} 5
+", &HashMap::new()).await.unwrap();
+
+    let expected = "# TYPE aardvarks gauge
+aardvarks 3
+# TYPE zebras gauge
+zebras{zoo=\"a\"} 2
+zebras{zoo=\"aa\"} 5
+zebras{zoo=\"b\"} 1
+zebras{zoo=\"c\"} 4
+";
+    assert_eq!(agg.to_string().await, expected);
+    assert_eq!(agg.render(ExpositionFormat::Text, false).await, expected);
+}
+
+#[tokio::test]
+async fn test_render_without() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE requests_total counter
+requests_total{instance=\"a\",path=\"/\"} 1
+requests_total{instance=\"b\",path=\"/\"} 2
+requests_total{instance=\"b\",path=\"/login\"} 4
+# TYPE latency histogram
+latency_bucket{instance=\"a\",le=\"1\"} 1
+latency_bucket{instance=\"a\",le=\"+Inf\"} 2
+latency_count{instance=\"a\"} 2
+latency_sum{instance=\"a\"} 3
+latency_bucket{instance=\"b\",le=\"1\"} 3
+latency_bucket{instance=\"b\",le=\"+Inf\"} 3
+latency_count{instance=\"b\"} 3
+latency_sum{instance=\"b\"} 1
+# TYPE temperature gauge
+temperature{instance=\"a\"} 20
+temperature{instance=\"b\"} 25
+", &HashMap::new()).await.unwrap();
+
+    let stored = agg.to_string().await;
+    let query = ScrapeQuery { selectors: Vec::new(), without: vec!["instance".to_owned()] };
+    let rendered = agg.render_query(ExpositionFormat::Text, false, &query).await;
+    let expected = "# TYPE latency histogram
+latency_bucket{le=\"1\"} 4
+latency_bucket{le=\"+Inf\"} 5
+latency_sum 4
+latency_count 5
+# TYPE requests_total counter
+requests_total{path=\"/\"} 3
+requests_total{path=\"/login\"} 4
+# TYPE temperature gauge
+temperature{instance=\"a\"} 20
+temperature{instance=\"b\"} 25
+";
+    assert_eq!(rendered, expected);
+
+    // The per instance series are still there for everything else
+    assert_eq!(agg.to_string().await, stored);
+    assert_eq!(agg.render(ExpositionFormat::Text, false).await, stored);
+}
@@ -1,73 +1,431 @@
-use std::{fs::File, io::{self, BufRead, BufReader}, path::PathBuf};
+#[cfg(feature="auth")]
+use std::{collections::{HashMap, HashSet}, fs, io, path::PathBuf, str::FromStr, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime}};
+
+#[cfg(feature="auth")]
+use serde::Deserialize;
 
 pub trait Authenticator {
-    fn authenticate(&self, token: &str) -> Result<bool, anyhow::Error>;
+    /// Checks the given Authorization header, returning the identity that it authenticates as, or None
+    /// if it isn't allowed. Anonymous requests have an empty identity
+    fn authenticate(&self, token: &str) -> Result<Option<String>, anyhow::Error>;
 }
 
+/// How often the htpasswd and token files are checked for changes
 #[cfg(feature="auth")]
-#[derive(Clone)]
-pub struct BasicAuthenticator {
-    allowed_hashes: Vec<String>
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A password hash, in one of the formats that htpasswd can produce
+#[cfg(feature="auth")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum PasswordHash {
+    /// `$2y$...` (or `$2a$`/`$2b$`), from `htpasswd -B`
+    Bcrypt(String),
+    /// `$apr1$<salt>$<hash>`, Apache's MD5 based crypt, from `htpasswd -m` (the default on most systems)
+    Apr1 { salt: String, hash: String },
+    /// `{SHA}<base64 SHA1>`, from `htpasswd -s`
+    Sha1(Vec<u8>),
 }
 
 #[cfg(feature="auth")]
-impl BasicAuthenticator {
-    fn load_from_file(path: PathBuf) -> Result<BasicAuthenticator, io::Error> {
-        let mut allowed_hashes = Vec::new();
-        for line in BufReader::new(File::open(path)?).lines() {
-            match line {
-                Ok(line) => allowed_hashes.push(line),
-                Err(e) => return Err(e)
+impl FromStr for PasswordHash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("$2y$") || s.starts_with("$2a$") || s.starts_with("$2b$") {
+            return Ok(PasswordHash::Bcrypt(s.to_owned()));
+        }
+
+        if let Some(rest) = s.strip_prefix("$apr1$") {
+            return match rest.split_once('$') {
+                Some((salt, hash)) if salt.len() <= 8 && !hash.is_empty() => Ok(PasswordHash::Apr1 { salt: salt.to_owned(), hash: hash.to_owned() }),
+                _ => Err(anyhow::anyhow!("invalid apr1 hash")),
+            };
+        }
+
+        if let Some(hash) = s.strip_prefix("{SHA}") {
+            return base64::decode(hash).map(PasswordHash::Sha1).map_err(|e| anyhow::anyhow!("invalid SHA hash: {}", e));
+        }
+
+        Err(anyhow::anyhow!("unsupported password hash - only bcrypt, apr1, and SHA hashes are supported"))
+    }
+}
+
+/// Compares two byte strings in a time that only depends on their lengths, so that comparing hashes doesn't
+/// leak how much of them matched
+#[cfg(feature="auth")]
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Apache's variant of the MD5 crypt algorithm, returning just the hash part of `$apr1$<salt>$<hash>`
+#[cfg(feature="auth")]
+fn apr1_hash(password: &[u8], salt: &[u8]) -> String {
+    const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+    let mut alternate = md5::Context::new();
+    alternate.consume(password);
+    alternate.consume(salt);
+    alternate.consume(password);
+    let alternate = alternate.compute();
+
+    let mut context = md5::Context::new();
+    context.consume(password);
+    context.consume(b"$apr1$");
+    context.consume(salt);
+    for chunk in (0..password.len()).step_by(16) {
+        context.consume(&alternate[..(password.len() - chunk).min(16)]);
+    }
+
+    let mut i = password.len();
+    while i > 0 {
+        if i & 1 == 1 {
+            context.consume([0]);
+        } else {
+            context.consume(&password[..1]);
+        }
+        i >>= 1;
+    }
+
+    let mut digest = context.compute();
+    for round in 0..1000 {
+        let mut context = md5::Context::new();
+        if round % 2 == 1 { context.consume(password) } else { context.consume(digest.0) }
+        if round % 3 != 0 { context.consume(salt) }
+        if round % 7 != 0 { context.consume(password) }
+        if round % 2 == 1 { context.consume(digest.0) } else { context.consume(password) }
+        digest = context.compute();
+    }
+
+    let mut hash = String::new();
+    let mut push = |value: u32, chars: usize| {
+        let mut value = value;
+        for _ in 0..chars {
+            hash.push(ITOA64[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push((digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32, 4);
+    }
+    push(digest[11] as u32, 2);
+
+    hash
+}
+
+#[cfg(feature="auth")]
+impl PasswordHash {
+    pub fn verify(&self, password: &str) -> bool {
+        use sha1::{Digest, Sha1};
+        match self {
+            PasswordHash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            PasswordHash::Apr1 { salt, hash } => constant_time_eq(apr1_hash(password.as_bytes(), salt.as_bytes()).as_bytes(), hash.as_bytes()),
+            PasswordHash::Sha1(hash) => constant_time_eq(&Sha1::digest(password.as_bytes()), hash),
+        }
+    }
+}
+
+/// The contents of an htpasswd file
+#[cfg(feature="auth")]
+#[derive(Debug, Default)]
+pub struct Htpasswd {
+    users: HashMap<String, PasswordHash>,
+    /// Lines without a username, from before usernames were supported, which accept any username
+    any_user: Vec<PasswordHash>,
+}
+
+#[cfg(feature="auth")]
+impl FromStr for Htpasswd {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut htpasswd = Htpasswd::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let result = match line.split_once(':') {
+                Some((username, hash)) => hash.parse().map(|hash| { htpasswd.users.insert(username.to_owned(), hash); }),
+                None => line.parse().map(|hash| htpasswd.any_user.push(hash)),
+            };
+
+            result.map_err(|e| anyhow::anyhow!("line {}: {}", i + 1, e))?;
+        }
+
+        Ok(htpasswd)
+    }
+}
+
+#[cfg(feature="auth")]
+impl Htpasswd {
+    /// Checks a username and password, returning the identity that they authenticate as, or None if they
+    /// aren't allowed. Lines without a username are shared by everyone who knows the password, so they can't
+    /// vouch for the username, and authenticate as the empty (anonymous) identity instead
+    pub fn authenticate(&self, username: &str, password: &str) -> Option<String> {
+        match self.users.get(username) {
+            Some(hash) => Some(username.to_owned()).filter(|_| hash.verify(password)),
+            None => Some(String::new()).filter(|_| self.any_user.iter().any(|hash| hash.verify(password))),
+        }
+    }
+}
+
+/// A file, as of the last time that it was loaded
+#[cfg(feature="auth")]
+struct LoadedFile<T> {
+    contents: Arc<T>,
+    modified: Option<SystemTime>,
+    last_checked: Instant,
+}
+
+/// A file that's parsed when it's loaded, and reloaded when it changes
+#[cfg(feature="auth")]
+struct ReloadingFile<T> {
+    path: PathBuf,
+    /// How often to check whether the file has changed
+    reload_interval: Duration,
+    loaded: Mutex<LoadedFile<T>>,
+}
+
+#[cfg(feature="auth")]
+fn load_file<T: FromStr<Err = anyhow::Error>>(path: &PathBuf) -> Result<(Arc<T>, Option<SystemTime>), io::Error> {
+    let modified = fs::metadata(path)?.modified().ok();
+    let contents = fs::read_to_string(path)?.parse().map_err(|e: anyhow::Error| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok((Arc::new(contents), modified))
+}
+
+#[cfg(feature="auth")]
+impl<T: FromStr<Err = anyhow::Error>> ReloadingFile<T> {
+    fn load(path: PathBuf, reload_interval: Duration) -> Result<ReloadingFile<T>, io::Error> {
+        let (contents, modified) = load_file(&path)?;
+        Ok(ReloadingFile {
+            path,
+            reload_interval,
+            loaded: Mutex::new(LoadedFile { contents, modified, last_checked: Instant::now() }),
+        })
+    }
+
+    /// The current contents of the file, reloading it first if it's changed since it was last loaded. If the new
+    /// file can't be loaded, the old one keeps being used, so that a half written file doesn't lock everyone out
+    fn get(&self) -> Arc<T> {
+        let mut loaded = self.loaded.lock().unwrap();
+        if loaded.last_checked.elapsed() >= self.reload_interval {
+            loaded.last_checked = Instant::now();
+            let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+            if modified.is_some() && modified != loaded.modified {
+                if let Ok((contents, modified)) = load_file(&self.path) {
+                    loaded.contents = contents;
+                    loaded.modified = modified;
+                }
             }
         }
 
-        return Ok(BasicAuthenticator {
-            allowed_hashes
-        });
+        Arc::clone(&loaded.contents)
+    }
+}
+
+/// Authenticates requests with Basic auth against an htpasswd file, which is reloaded when it changes
+#[cfg(feature="auth")]
+pub struct BasicAuthenticator {
+    htpasswd: ReloadingFile<Htpasswd>,
+}
+
+#[cfg(feature="auth")]
+impl BasicAuthenticator {
+    pub(crate) fn load_from_file(path: PathBuf, reload_interval: Duration) -> Result<BasicAuthenticator, io::Error> {
+        Ok(BasicAuthenticator { htpasswd: ReloadingFile::load(path, reload_interval)? })
     }
 }
 
 #[cfg(feature="auth")]
 impl Authenticator for BasicAuthenticator {
-    fn authenticate(&self, header: &str) -> Result<bool, anyhow::Error> {
-        use bcrypt::verify;
+    fn authenticate(&self, header: &str) -> Result<Option<String>, anyhow::Error> {
         // Header is in the format "Basic <token>", so here we extract the second bit
-        match header.split_ascii_whitespace().nth(1) {
-            Some(token) => {
-                let token: Option<String> = match base64::decode(token) {
-                    Ok(token_bytes) => {
-                        match String::from_utf8(token_bytes) {
-                            // If we have a valid utc-8 base64 auth, split it on the : (format is username:password), and take the second 
-                            // part (i.e. just take the password).
-                            Ok(token_str) if token_str.contains(':') => token_str.split(':').nth(1).map_or(None, |s| Some(s.to_owned())),
-
-                            // If we fail do decode it as a valid utf-8 basic auth header, for whatever reason, treat it as plain text
-                            Ok(token_str) => Some(token_str),
-                            Err(_) => Some(token.to_owned())
-                        }
-                    },
-
-                    // For backwards compatibility reasons, if we fail to base64 decode the header then we
-                    // still accept it as plain text
-                    Err(_) => Some(token.to_owned())
-                };
-
-                if let Some(token) = token {
-                    for hash in self.allowed_hashes.iter() {
-                        return Ok(verify(token, hash).unwrap_or(false))
-                    }
-                }
+        let token = match header.split_ascii_whitespace().nth(1) {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+
+        // The username (if there is one) and password
+        let (username, password) = match base64::decode(token).map(String::from_utf8) {
+            // If we have a valid utf-8 base64 auth, split it on the : (format is username:password)
+            Ok(Ok(token_str)) => match token_str.split_once(':') {
+                Some((username, password)) => (username.to_owned(), password.to_owned()),
+                None => (String::new(), token_str),
+            },
 
-                Ok(false)
+            // For backwards compatibility reasons, if we fail to decode the header then we
+            // still accept it as a plain text password
+            _ => (String::new(), token.to_owned()),
+        };
+
+        // Hashing is slow (that's the point), so it's done without holding the lock
+        Ok(self.htpasswd.get().authenticate(&username, &password))
+    }
+}
+
+#[cfg(feature="auth")]
+pub fn basic_auth(config_file_path: PathBuf) -> Result<BasicAuthenticator, io::Error> {
+    BasicAuthenticator::load_from_file(config_file_path, RELOAD_CHECK_INTERVAL)
+}
+
+/// The things that a bearer token can be allowed to do
+#[cfg(feature="auth")]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Pushing metrics to /metrics
+    Push,
+}
+
+/// A token, as it appears in the token file
+#[cfg(feature="auth")]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawToken {
+    name: String,
+    /// The hex encoded SHA256 hash of the token
+    sha256: String,
+    scopes: Vec<Scope>,
+    /// An RFC 3339 timestamp in UTC, after which the token stops working
+    #[serde(default)]
+    expires: Option<String>,
+}
+
+#[cfg(feature="auth")]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTokenFile {
+    tokens: Vec<RawToken>,
+}
+
+#[cfg(feature="auth")]
+#[derive(Debug)]
+pub struct Token {
+    /// The identity that requests with this token authenticate as
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires: Option<SystemTime>,
+}
+
+/// The contents of a bearer token file. Only the SHA256 hashes of the tokens are stored - tokens are random,
+/// so unlike passwords they don't need a slow hash to stop them from being brute forced
+#[cfg(feature="auth")]
+#[derive(Debug, Default)]
+pub struct TokenFile {
+    tokens: HashMap<Vec<u8>, Token>,
+}
+
+#[cfg(feature="auth")]
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    // An odd length leaves a single character at the end, which get() won't find a pair for
+    (0..s.len()).step_by(2).map(|i| s.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok())).collect()
+}
+
+#[cfg(feature="auth")]
+impl FromStr for TokenFile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let raw: RawTokenFile = serde_yaml::from_str(s)?;
+        let mut names = HashSet::new();
+        let mut tokens = HashMap::new();
+        for token in raw.tokens {
+            if !names.insert(token.name.clone()) {
+                return Err(anyhow::anyhow!("duplicate token name: {}", token.name));
+            }
+
+            let hash = match decode_hex(&token.sha256) {
+                Some(hash) if hash.len() == 32 => hash,
+                _ => return Err(anyhow::anyhow!("invalid sha256 hash for token {} - expected 64 hex characters", token.name)),
+            };
+
+            let expires = match &token.expires {
+                Some(expires) => Some(humantime::parse_rfc3339_weak(expires).map_err(|e| anyhow::anyhow!("invalid expiry for token {}: {}", token.name, e))?),
+                None => None,
+            };
+
+            if tokens.contains_key(&hash) {
+                return Err(anyhow::anyhow!("token {} has the same hash as another token", token.name));
             }
-            None => Ok(false)
+
+            tokens.insert(hash, Token { name: token.name, scopes: token.scopes, expires });
         }
+
+        Ok(TokenFile { tokens })
     }
 }
 
 #[cfg(feature="auth")]
-pub fn basic_auth(config_file_path: PathBuf) -> Result<BasicAuthenticator, io::Error> {
-    BasicAuthenticator::load_from_file(config_file_path)
+impl TokenFile {
+    /// Looks up a token, returning it if it exists, hasn't expired at `now`, and has the given scope
+    pub fn verify(&self, token: &str, scope: Scope, now: SystemTime) -> Option<&Token> {
+        use sha2::{Digest, Sha256};
+        self.tokens.get(Sha256::digest(token.as_bytes()).as_slice())
+            .filter(|token| token.scopes.contains(&scope))
+            .filter(|token| token.expires.iter().all(|&expires| now < expires))
+    }
+}
+
+/// Authenticates requests with `Authorization: Bearer <token>` against a file of hashed tokens, which is reloaded
+/// when it changes. Requests authenticate as the name of their token
+#[cfg(feature="auth")]
+pub struct BearerAuthenticator {
+    tokens: ReloadingFile<TokenFile>,
+    /// The scope that tokens need to be accepted
+    scope: Scope,
+}
+
+#[cfg(feature="auth")]
+impl BearerAuthenticator {
+    pub(crate) fn load_from_file(path: PathBuf, scope: Scope, reload_interval: Duration) -> Result<BearerAuthenticator, io::Error> {
+        Ok(BearerAuthenticator { tokens: ReloadingFile::load(path, reload_interval)?, scope })
+    }
+}
+
+#[cfg(feature="auth")]
+impl Authenticator for BearerAuthenticator {
+    fn authenticate(&self, header: &str) -> Result<Option<String>, anyhow::Error> {
+        let token = match header.split_ascii_whitespace().collect::<Vec<_>>().as_slice() {
+            [scheme, token] if scheme.eq_ignore_ascii_case("bearer") => token.to_string(),
+            _ => return Ok(None),
+        };
+
+        Ok(self.tokens.get().verify(&token, self.scope, SystemTime::now()).map(|token| token.name.clone()))
+    }
+}
+
+#[cfg(feature="auth")]
+pub fn bearer_auth(config_file_path: PathBuf) -> Result<BearerAuthenticator, io::Error> {
+    BearerAuthenticator::load_from_file(config_file_path, Scope::Push, RELOAD_CHECK_INTERVAL)
+}
+
+/// Picks an authenticator by the scheme of the Authorization header (e.g. `Basic` or `Bearer`), so that
+/// clients can use whichever one suits them
+#[cfg(feature="auth")]
+#[derive(Default)]
+pub struct SchemeAuthenticator {
+    schemes: Vec<(String, Box<dyn Authenticator + Send + Sync>)>,
+}
+
+#[cfg(feature="auth")]
+impl SchemeAuthenticator {
+    pub fn with_scheme<A: Authenticator + Send + Sync + 'static>(mut self, scheme: &str, authenticator: A) -> SchemeAuthenticator {
+        self.schemes.push((scheme.to_owned(), Box::new(authenticator)));
+        self
+    }
+}
+
+#[cfg(feature="auth")]
+impl Authenticator for SchemeAuthenticator {
+    fn authenticate(&self, header: &str) -> Result<Option<String>, anyhow::Error> {
+        let scheme = header.split_ascii_whitespace().next().unwrap_or("");
+        match self.schemes.iter().find(|(name, _)| name.eq_ignore_ascii_case(scheme)) {
+            Some((_, authenticator)) => authenticator.authenticate(header),
+            None => Ok(None),
+        }
+    }
 }
 
 pub struct PassThroughAuthenticator{}
@@ -77,7 +435,7 @@ pub fn pass_through_auth() -> PassThroughAuthenticator {
 }
 
 impl Authenticator for PassThroughAuthenticator {
-    fn authenticate(&self, _: &str) -> Result<bool, anyhow::Error> {
-        Ok(true)
+    fn authenticate(&self, _: &str) -> Result<Option<String>, anyhow::Error> {
+        Ok(Some(String::new()))
     }
 }
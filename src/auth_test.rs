@@ -0,0 +1,147 @@
+use std::{fs, path::PathBuf, thread::sleep, time::Duration};
+
+use crate::auth::*;
+
+/// A Basic auth header for the given username and password
+fn basic(username: &str, password: &str) -> String {
+    format!("Basic {}", base64::encode(format!("{}:{}", username, password)))
+}
+
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("gravel-{}-{}", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn test_password_hashes() {
+    // From `openssl passwd -apr1 -salt abcdefgh password`
+    let apr1: PasswordHash = "$apr1$abcdefgh$FBwExRW4dCc8aL.OvjpIE1".parse().unwrap();
+    assert!(apr1.verify("password"));
+    assert!(!apr1.verify("Password"));
+
+    // From `htpasswd -nbs "" password`
+    let sha: PasswordHash = "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=".parse().unwrap();
+    assert!(sha.verify("password"));
+    assert!(!sha.verify("password1"));
+
+    let bcrypt: PasswordHash = bcrypt::hash("password", 4).unwrap().parse().unwrap();
+    assert!(bcrypt.verify("password"));
+    assert!(!bcrypt.verify("hunter2"));
+
+    assert!("plaintext".parse::<PasswordHash>().is_err());
+    assert!("$apr1$nohash".parse::<PasswordHash>().is_err());
+}
+
+#[test]
+fn test_htpasswd() {
+    let htpasswd: Htpasswd = format!("# Comments and blank lines are ignored
+
+alice:$apr1$abcdefgh$FBwExRW4dCc8aL.OvjpIE1
+bob:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=
+carol:{}
+", bcrypt::hash("carols password", 4).unwrap()).parse().unwrap();
+
+    assert_eq!(htpasswd.authenticate("alice", "password"), Some(String::from("alice")));
+    assert_eq!(htpasswd.authenticate("bob", "password"), Some(String::from("bob")));
+    assert_eq!(htpasswd.authenticate("carol", "carols password"), Some(String::from("carol")));
+    // Passwords only work for their own user
+    assert_eq!(htpasswd.authenticate("carol", "password"), None);
+    assert_eq!(htpasswd.authenticate("alice", "carols password"), None);
+    assert_eq!(htpasswd.authenticate("dave", "password"), None);
+
+    let err = "alice:$apr1$abcdefgh$FBwExRW4dCc8aL.OvjpIE1\nbob:password\n".parse::<Htpasswd>().unwrap_err();
+    assert!(err.to_string().starts_with("line 2:"), "{}", err);
+}
+
+#[test]
+fn test_basic_authenticator() {
+    // Lines without a username, from the old format, accept any username
+    let legacy_hash = bcrypt::hash("legacy", 4).unwrap();
+    let path = temp_file("htpasswd", &format!("alice:$apr1$abcdefgh$FBwExRW4dCc8aL.OvjpIE1\nbob:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n{}\n", legacy_hash));
+    let authenticator = BasicAuthenticator::load_from_file(path.clone(), Duration::from_millis(10)).unwrap();
+
+    assert_eq!(authenticator.authenticate(&basic("alice", "password")).unwrap(), Some(String::from("alice")));
+    assert_eq!(authenticator.authenticate(&basic("bob", "password")).unwrap(), Some(String::from("bob")));
+    assert_eq!(authenticator.authenticate(&basic("alice", "wrong")).unwrap(), None);
+    // but can't be used to claim someone else's identity
+    assert_eq!(authenticator.authenticate(&basic("anyone", "legacy")).unwrap(), Some(String::new()));
+    assert_eq!(authenticator.authenticate(&basic("alice", "legacy")).unwrap(), None);
+    assert_eq!(authenticator.authenticate(&format!("Basic {}", base64::encode("legacy"))).unwrap(), Some(String::new()));
+    assert_eq!(authenticator.authenticate("Basic").unwrap(), None);
+
+    // Changes to the file are picked up
+    fs::write(&path, "bob:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n").unwrap();
+    sleep(Duration::from_millis(20));
+    assert_eq!(authenticator.authenticate(&basic("alice", "password")).unwrap(), None);
+    assert_eq!(authenticator.authenticate(&basic("bob", "password")).unwrap(), Some(String::from("bob")));
+
+    // But broken files are ignored
+    fs::write(&path, "bob:password\n").unwrap();
+    sleep(Duration::from_millis(20));
+    assert_eq!(authenticator.authenticate(&basic("bob", "password")).unwrap(), Some(String::from("bob")));
+
+    fs::remove_file(path).unwrap();
+}
+
+fn sha256(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(token.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[test]
+fn test_token_file() {
+    let tokens: TokenFile = format!("tokens:
+  - name: ci
+    sha256: {}
+    scopes: [push]
+  - name: lambda
+    sha256: {}
+    scopes: [push]
+    expires: 2030-01-01T00:00:00Z
+  - name: no-scopes
+    sha256: {}
+    scopes: []
+", sha256("ci-token"), sha256("lambda-token"), sha256("no-scopes-token")).parse().unwrap();
+
+    let now = humantime::parse_rfc3339("2029-12-31T23:59:59Z").unwrap();
+    assert_eq!(tokens.verify("ci-token", Scope::Push, now).map(|token| token.name.as_str()), Some("ci"));
+    assert_eq!(tokens.verify("lambda-token", Scope::Push, now).map(|token| token.name.as_str()), Some("lambda"));
+    assert!(tokens.verify("ci-token2", Scope::Push, now).is_none());
+    assert!(tokens.verify("no-scopes-token", Scope::Push, now).is_none(), "tokens without the scope should be rejected");
+
+    let later = humantime::parse_rfc3339("2030-01-01T00:00:00Z").unwrap();
+    assert!(tokens.verify("lambda-token", Scope::Push, later).is_none(), "expired tokens should be rejected");
+    assert!(tokens.verify("ci-token", Scope::Push, later).is_some());
+
+    let duplicate = format!("tokens:\n  - {{name: a, sha256: {}, scopes: [push]}}\n  - {{name: a, sha256: {}, scopes: [push]}}\n", sha256("a"), sha256("b"));
+    assert!(duplicate.parse::<TokenFile>().is_err(), "duplicate names should be rejected");
+    assert!("tokens:\n  - {name: a, sha256: abcd, scopes: [push]}\n".parse::<TokenFile>().is_err(), "short hashes should be rejected");
+    assert!(format!("tokens:\n  - {{name: a, sha256: {}, scopes: [scrape]}}\n", sha256("a")).parse::<TokenFile>().is_err(), "unknown scopes should be rejected");
+    assert!(format!("tokens:\n  - {{name: a, sha256: {}, scopes: [push], expires: tomorrow}}\n", sha256("a")).parse::<TokenFile>().is_err());
+}
+
+#[test]
+fn test_scheme_authenticator() {
+    let htpasswd = temp_file("scheme-htpasswd", "alice:$apr1$abcdefgh$FBwExRW4dCc8aL.OvjpIE1\n");
+    let tokens = temp_file("scheme-tokens", &format!("tokens:\n  - {{name: ci, sha256: {}, scopes: [push]}}\n", sha256("ci-token")));
+    let authenticator = SchemeAuthenticator::default()
+        .with_scheme("Basic", BasicAuthenticator::load_from_file(htpasswd.clone(), Duration::from_secs(60)).unwrap())
+        .with_scheme("Bearer", BearerAuthenticator::load_from_file(tokens.clone(), Scope::Push, Duration::from_millis(10)).unwrap());
+
+    assert_eq!(authenticator.authenticate(&basic("alice", "password")).unwrap(), Some(String::from("alice")));
+    assert_eq!(authenticator.authenticate("Bearer ci-token").unwrap(), Some(String::from("ci")));
+    assert_eq!(authenticator.authenticate("bearer ci-token").unwrap(), Some(String::from("ci")));
+    assert_eq!(authenticator.authenticate("Bearer wrong-token").unwrap(), None);
+    assert_eq!(authenticator.authenticate("Bearer").unwrap(), None);
+    assert_eq!(authenticator.authenticate("Token ci-token").unwrap(), None);
+    assert_eq!(authenticator.authenticate("").unwrap(), None);
+
+    // Revoking a token takes effect once the file is reloaded
+    fs::write(&tokens, "tokens: []\n").unwrap();
+    sleep(Duration::from_millis(20));
+    assert_eq!(authenticator.authenticate("Bearer ci-token").unwrap(), None);
+
+    fs::remove_file(htpasswd).unwrap();
+    fs::remove_file(tokens).unwrap();
+}
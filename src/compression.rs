@@ -0,0 +1,123 @@
+use std::{fmt, io::{Read, Write}, str::FromStr};
+
+use flate2::{Compression, read::{GzDecoder, ZlibDecoder}, write::GzEncoder};
+
+/// The Content-Encodings that we can decompress push bodies from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    /// zlib wrapped deflate, which is what HTTP means by deflate
+    Deflate,
+    Zstd,
+    /// The snappy block format, as used by Prometheus remote write
+    Snappy,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecompressionError {
+    /// The body was encoded with something we don't know how to decode
+    UnsupportedEncoding(String),
+    /// The decompressed body would be bigger than the given number of bytes
+    TooLarge(u64),
+    /// The body couldn't be decoded with the encoding it said it had
+    Invalid(String),
+}
+
+impl fmt::Display for DecompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecompressionError::UnsupportedEncoding(encoding) => write!(f, "Unsupported Content-Encoding: {}", encoding),
+            DecompressionError::TooLarge(limit) => write!(f, "Decompressed request body is larger than the limit of {} bytes", limit),
+            DecompressionError::Invalid(err) => write!(f, "Failed to decompress request body: {}", err),
+        }
+    }
+}
+
+impl FromStr for ContentEncoding {
+    type Err = DecompressionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "identity" | "" => Ok(ContentEncoding::Identity),
+            "gzip" | "x-gzip" => Ok(ContentEncoding::Gzip),
+            "deflate" => Ok(ContentEncoding::Deflate),
+            "zstd" => Ok(ContentEncoding::Zstd),
+            "snappy" => Ok(ContentEncoding::Snappy),
+            _ => Err(DecompressionError::UnsupportedEncoding(s.trim().to_owned()))
+        }
+    }
+}
+
+/// Reads everything from the given decoder, giving up as soon as it produces more than `limit` bytes so
+/// that a small, highly compressed body can't make us allocate an arbitrary amount of memory
+fn read_with_limit<R: Read>(decoder: R, limit: u64) -> Result<Vec<u8>, DecompressionError> {
+    let mut output = Vec::new();
+    if let Err(e) = decoder.take(limit + 1).read_to_end(&mut output) {
+        return Err(DecompressionError::Invalid(e.to_string()));
+    }
+
+    if output.len() as u64 > limit {
+        return Err(DecompressionError::TooLarge(limit));
+    }
+
+    Ok(output)
+}
+
+impl ContentEncoding {
+    fn decompress(&self, data: Vec<u8>, limit: u64) -> Result<Vec<u8>, DecompressionError> {
+        match self {
+            ContentEncoding::Identity if data.len() as u64 > limit => Err(DecompressionError::TooLarge(limit)),
+            ContentEncoding::Identity => Ok(data),
+            ContentEncoding::Gzip => read_with_limit(GzDecoder::new(data.as_slice()), limit),
+            ContentEncoding::Deflate => read_with_limit(ZlibDecoder::new(data.as_slice()), limit),
+            ContentEncoding::Zstd => {
+                let decoder = zstd::stream::read::Decoder::new(data.as_slice()).map_err(|e| DecompressionError::Invalid(e.to_string()))?;
+                read_with_limit(decoder, limit)
+            },
+            ContentEncoding::Snappy => {
+                // The block format starts with the decompressed length, so we can check it before allocating anything
+                let length = snap::raw::decompress_len(&data).map_err(|e| DecompressionError::Invalid(e.to_string()))?;
+                if length as u64 > limit {
+                    return Err(DecompressionError::TooLarge(limit));
+                }
+
+                snap::raw::Decoder::new().decompress_vec(&data).map_err(|e| DecompressionError::Invalid(e.to_string()))
+            }
+        }
+    }
+}
+
+/// Decodes a body with the given Content-Encoding header, which lists the encodings in the order they were applied,
+/// producing at most `limit` bytes
+pub fn decompress(data: Vec<u8>, content_encoding: &str, limit: u64) -> Result<Vec<u8>, DecompressionError> {
+    let encodings = content_encoding.split(',').map(|encoding| encoding.parse()).collect::<Result<Vec<ContentEncoding>, _>>()?;
+    encodings.iter().rev().try_fold(data, |data, encoding| encoding.decompress(data, limit))
+}
+
+/// Gzips the given data, for sending to clients that accept it
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing into a Vec can't fail
+    encoder.write_all(data).expect("failed to gzip into memory");
+    encoder.finish().expect("failed to gzip into memory")
+}
+
+/// Whether an Accept-Encoding header allows a gzipped response, i.e. it gives gzip (or *, if gzip
+/// isn't listed itself) a non zero q value
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    let mut gzip_quality = None;
+    let mut wildcard_quality = None;
+    for encoding in accept_encoding.split(',') {
+        let mut parts = encoding.split(';').map(|part| part.trim());
+        let name = parts.next().unwrap_or("");
+        let quality = parts.find_map(|param| param.strip_prefix("q=")).map(|q| q.parse::<f64>().unwrap_or(0.)).unwrap_or(1.);
+        if name.eq_ignore_ascii_case("gzip") {
+            gzip_quality = Some(quality);
+        } else if name == "*" {
+            wildcard_quality = Some(quality);
+        }
+    }
+
+    gzip_quality.or(wildcard_quality).is_some_and(|quality| quality > 0.)
+}
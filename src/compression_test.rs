@@ -0,0 +1,53 @@
+use std::io::Write;
+
+use flate2::{Compression, write::{GzEncoder, ZlibEncoder}};
+
+use crate::compression::*;
+
+const BODY: &[u8] = b"# TYPE requests_total counter\nrequests_total 1\n";
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn test_decompress() {
+    assert_eq!(decompress(gzip(BODY), "gzip", 1024).unwrap(), BODY);
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(BODY).unwrap();
+    assert_eq!(decompress(encoder.finish().unwrap(), "deflate", 1024).unwrap(), BODY);
+
+    assert_eq!(decompress(zstd::encode_all(BODY, 0).unwrap(), "zstd", 1024).unwrap(), BODY);
+    assert_eq!(decompress(snap::raw::Encoder::new().compress_vec(BODY).unwrap(), "snappy", 1024).unwrap(), BODY);
+    assert_eq!(decompress(BODY.to_vec(), "identity", 1024).unwrap(), BODY);
+
+    // Multiple encodings are undone in the reverse order to how they were applied
+    let zstd_then_gzip = gzip(&zstd::encode_all(BODY, 0).unwrap());
+    assert_eq!(decompress(zstd_then_gzip, "zstd, gzip", 1024).unwrap(), BODY);
+}
+
+#[test]
+fn test_decompression_errors() {
+    assert_eq!(decompress(BODY.to_vec(), "br", 1024), Err(DecompressionError::UnsupportedEncoding("br".to_owned())));
+    assert!(matches!(decompress(BODY.to_vec(), "gzip", 1024), Err(DecompressionError::Invalid(_))));
+
+    // A small body that decompresses into something huge is cut off once it goes over the limit
+    let bomb = vec![b'a'; 1024 * 1024];
+    assert_eq!(decompress(gzip(&bomb), "gzip", 1024), Err(DecompressionError::TooLarge(1024)));
+    assert_eq!(decompress(zstd::encode_all(bomb.as_slice(), 0).unwrap(), "zstd", 1024), Err(DecompressionError::TooLarge(1024)));
+    assert_eq!(decompress(snap::raw::Encoder::new().compress_vec(&bomb).unwrap(), "snappy", 1024), Err(DecompressionError::TooLarge(1024)));
+}
+
+#[test]
+fn test_accepts_gzip() {
+    assert!(accepts_gzip("gzip"));
+    assert!(accepts_gzip("deflate, gzip;q=0.5"));
+    assert!(accepts_gzip("*"));
+    assert!(!accepts_gzip("identity"));
+    assert!(!accepts_gzip("gzip;q=0"));
+    // An explicit gzip overrides the wildcard
+    assert!(!accepts_gzip("gzip;q=0, *"));
+}
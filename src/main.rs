@@ -1,14 +1,23 @@
-use std::{net::ToSocketAddrs, path::PathBuf};
+use std::{collections::HashMap, net::ToSocketAddrs, path::PathBuf};
 
-use aggregator::Aggregator;
+use aggregator::{Aggregator, AggregatorConfig};
 use clap::{App, Arg};
 use slog::{Drain, error, info, o};
 
-use crate::{auth::pass_through_auth, routes::RoutesConfig};
+use crate::{auth::pass_through_auth, rate_limit::RateLimiter, routes::RoutesConfig, tenants::{Tenancy, TenantSource}};
 
+mod acl;
 mod aggregator;
 mod routes;
 mod pebble;
+mod native_histogram;
+mod protobuf;
+mod compression;
+mod rate_limit;
+mod relabel;
+mod selector;
+mod self_metrics;
+mod tenants;
 
 #[cfg(feature="clustering")]
 mod clustering;
@@ -17,14 +26,30 @@ mod clustering;
 mod aggregator_test;
 #[cfg(test)]
 mod routes_test;
+#[cfg(test)]
+mod pebble_test;
+#[cfg(test)]
+mod native_histogram_test;
+#[cfg(test)]
+mod rate_limit_test;
+#[cfg(test)]
+mod compression_test;
+#[cfg(test)]
+mod selector_test;
+#[cfg(test)]
+mod relabel_test;
+#[cfg(test)]
+mod acl_test;
+#[cfg(test)]
+mod tenants_test;
+#[cfg(all(test, feature="auth"))]
+mod auth_test;
 mod auth;
 
 use tokio::signal;
 
 #[tokio::main]
 async fn main() {
-    let agg = Aggregator::new();
-
     let app = App::new("Prometheus Gravel Gateway")
         .arg(
             Arg::with_name("listen")
@@ -32,6 +57,216 @@ async fn main() {
                 .help("The address/port to listen on")
                 .takes_value(true)
                 .default_value("localhost:4278"),
+        )
+        .arg(
+            Arg::with_name("pebble-granularity")
+                .long("pebble-granularity")
+                .help("The number of buckets to split pebbles into, unless the clearmode specifies one (e.g. mean1h@60)")
+                .takes_value(true)
+                .default_value("100"),
+        )
+        .arg(
+            Arg::with_name("max-pebble-granularity")
+                .long("max-pebble-granularity")
+                .help("The most buckets that a clearmode can ask for its pebbles to be split into (e.g. with mean1h@60)")
+                .takes_value(true)
+                .default_value("1000"),
+        )
+        .arg(
+            Arg::with_name("pebble-window-series")
+                .long("pebble-window-series")
+                .help("Whether to expose the count, min, max, and start time of pebble windows as extra series")
+        )
+        .arg(
+            Arg::with_name("summary-quantile-strategy")
+                .long("summary-quantile-strategy")
+                .help("How to combine the quantiles of summaries that are aggregated together")
+                .possible_values(&["max", "weighted"])
+                .takes_value(true)
+                .default_value("max"),
+        )
+        .arg(
+            Arg::with_name("histogram-bucket-policy")
+                .long("histogram-bucket-policy")
+                .help("What to do when a histogram is pushed with different buckets to the existing one")
+                .long_help(
+                    "What to do when a histogram is pushed with different buckets to the existing one.
+                    reject rejects the push, union re-buckets both histograms into the union of their buckets,
+                    and existing re-buckets the new histogram into the existing buckets."
+                )
+                .possible_values(&["reject", "union", "existing"])
+                .takes_value(true)
+                .default_value("union"),
+        )
+        .arg(
+            Arg::with_name("validation-mode")
+                .long("validation-mode")
+                .help("What to do with invalid samples, e.g. negative counters")
+                .long_help(
+                    "What to do with invalid samples, e.g. negative counters, or histograms with non cumulative buckets.
+                    strict rejects the whole push, and lenient drops the invalid samples (counting them in the self metrics)
+                    and merges the rest."
+                )
+                .possible_values(&["strict", "lenient"])
+                .takes_value(true)
+                .default_value("strict"),
+        )
+        .arg(
+            Arg::with_name("pebble-sample-timestamps")
+                .long("pebble-sample-timestamps")
+                .help("Put samples with timestamps into the pebble bucket for their timestamp, rather than the time they were pushed")
+        )
+        .arg(
+            Arg::with_name("max-timestamp-skew")
+                .long("max-timestamp-skew")
+                .help("How far a sample timestamp can be from the gateway's clock before the sample is rejected, when using --pebble-sample-timestamps")
+                .takes_value(true)
+                .default_value("5m"),
+        )
+        .arg(
+            Arg::with_name("future-timestamp-tolerance")
+                .long("future-timestamp-tolerance")
+                .help("How far ahead of the gateway's clock a sample timestamp can be before the sample is rejected, when using --pebble-sample-timestamps")
+                .takes_value(true)
+                .default_value("1m"),
+        )
+        .arg(
+            Arg::with_name("replace-timestamps")
+                .long("replace-timestamps")
+                .help("Whether samples pushed with clearmode replace keep their timestamps (aggregated samples never do)")
+                .possible_values(&["strip", "preserve"])
+                .takes_value(true)
+                .default_value("strip"),
+        )
+        .arg(
+            Arg::with_name("max-series-per-family")
+                .long("max-series-per-family")
+                .help("The most series a single family can have before pushes that add to it are rejected")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-series-per-job-label")
+                .long("max-series-per-job-label")
+                .help("The most series, across all families, that can have the same job label (whatever other grouping labels they have) before pushes that add to them are rejected")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-body-size")
+                .long("max-body-size")
+                .help("The largest request body, in bytes, that will be accepted on a push")
+                .takes_value(true)
+                .default_value("10485760"),
+        )
+        .arg(
+            Arg::with_name("max-decompressed-size")
+                .long("max-decompressed-size")
+                .help("The largest that a compressed push body can be once it's been decompressed, in bytes")
+                .takes_value(true)
+                .default_value("52428800"),
+        )
+        .arg(
+            Arg::with_name("max-families-per-push")
+                .long("max-families-per-push")
+                .help("The most metric families a single push can contain")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-samples-per-push")
+                .long("max-samples-per-push")
+                .help("The most samples a single push can contain")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-label-name-length")
+                .long("max-label-name-length")
+                .help("The longest label name that a push can contain")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-label-value-length")
+                .long("max-label-value-length")
+                .help("The longest label value that a push can contain")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("push-acl-file")
+                .long("push-acl-file")
+                .help("A YAML file of the family names and grouping labels that each identity is allowed to push")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate-limit")
+                .long("rate-limit")
+                .help("How often each client can push, as <pushes>/<duration> (e.g. 100/1m)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate-limit-identity")
+                .long("rate-limit-identity")
+                .help("A rate limit for a specific identity that overrides --rate-limit, as <identity>=<pushes>/<duration>")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("rate-limit-key")
+                .long("rate-limit-key")
+                .help("What pushes are grouped by to rate limit them - the authenticated identity, the job label, or the source IP")
+                .possible_values(&["identity", "job", "ip"])
+                .takes_value(true)
+                .default_value("ip"),
+        )
+        .arg(
+            Arg::with_name("relabel-config-file")
+                .long("relabel-config-file")
+                .help("A YAML file of Prometheus style relabel_configs to apply to every pushed series")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tenant-source")
+                .long("tenant-source")
+                .help("Where the tenant of a push comes from, if pushes should be split between tenants with their own metrics")
+                .possible_values(&["path", "header", "identity"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tenant-header")
+                .long("tenant-header")
+                .help("The header that tenants are read from, with --tenant-source header")
+                .takes_value(true)
+                .default_value(tenants::DEFAULT_TENANT_HEADER),
+        )
+        .arg(
+            Arg::with_name("max-tenants")
+                .long("max-tenants")
+                .help("The most tenants that can be created, with --tenant-source. Each tenant gets its own --max-series, so this bounds the series across every tenant")
+                .takes_value(true)
+                .default_value("100"),
+        )
+        .arg(
+            Arg::with_name("tenant-limits-file")
+                .long("tenant-limits-file")
+                .help("A YAML file of series and push limits for specific tenants, which override the ones set by flags")
+                .takes_value(true)
+                .requires("tenant-source"),
+        )
+        .arg(
+            Arg::with_name("public-tenant-scrapes")
+                .long("public-tenant-scrapes")
+                .help("Let anyone scrape any tenant's metrics, rather than only clients authenticated as that tenant")
+                .requires("tenant-source"),
+        )
+        .arg(
+            Arg::with_name("public-tenant-pushes")
+                .long("public-tenant-pushes")
+                .help("Let anyone push to any tenant, rather than only clients authenticated as that tenant")
+                .requires("tenant-source"),
+        )
+        .arg(
+            Arg::with_name("max-series")
+                .long("max-series")
+                .help("The most series the gateway will hold in total before pushes that add to them are rejected. With --tenant-source, this is the most series each tenant will hold")
+                .takes_value(true),
         );
     
 
@@ -93,8 +328,19 @@ async fn main() {
             .help("The file to use for basic authentication validation")
             .long_help(
                 "The file to use for basic authentication validation.
-                This should be a path to a file of bcrypt hashes, one per line,
-                with each line being an allowed hash."
+                This should be a path to an htpasswd file, with a username:hash
+                line for each user. It's reloaded whenever it changes."
+            )
+            .takes_value(true)
+    ).arg(
+        Arg::with_name("bearer-token-file")
+            .long("bearer-token-file")
+            .help("The file to use for bearer token validation")
+            .long_help(
+                "The file to use for bearer token validation.
+                This should be a path to a YAML file of token names, SHA256
+                hashes, scopes, and optional expiry times. It's reloaded
+                whenever it changes."
             )
             .takes_value(true)
     );
@@ -120,11 +366,190 @@ async fn main() {
 
     info!(log, "Listening on: {:?}", address);
 
+    let mut agg_config = AggregatorConfig::default();
+    let granularity = matches.value_of("pebble-granularity").unwrap();
+    agg_config.pebble_granularity = match granularity.parse() {
+        Ok(g) if g > 0 => g,
+        _ => {
+            error!(log, "Invalid pebble granularity: {}", granularity);
+            return;
+        }
+    };
+
+    let max_granularity = matches.value_of("max-pebble-granularity").unwrap();
+    agg_config.max_pebble_granularity = match max_granularity.parse() {
+        Ok(g) if g >= agg_config.pebble_granularity => g,
+        _ => {
+            error!(log, "Invalid max pebble granularity: {} (it can't be less than --pebble-granularity)", max_granularity);
+            return;
+        }
+    };
+
+    agg_config.pebble_window_series = matches.is_present("pebble-window-series");
+    // Clap has already checked that this is one of the possible values
+    agg_config.quantile_merge_strategy = matches.value_of("summary-quantile-strategy").unwrap().parse().unwrap();
+    agg_config.histogram_bucket_policy = matches.value_of("histogram-bucket-policy").unwrap().parse().unwrap();
+    agg_config.validation_mode = matches.value_of("validation-mode").unwrap().parse().unwrap();
+    agg_config.replace_timestamps = matches.value_of("replace-timestamps").unwrap().parse().unwrap();
+    agg_config.pebble_sample_timestamps = matches.is_present("pebble-sample-timestamps");
+    let max_skew = matches.value_of("max-timestamp-skew").unwrap();
+    agg_config.max_timestamp_skew = match pebble::parse_duration(max_skew) {
+        Ok(skew) => skew,
+        Err(e) => {
+            error!(log, "Invalid max timestamp skew {}: {}", max_skew, e.to_string());
+            return;
+        }
+    };
+    let future_tolerance = matches.value_of("future-timestamp-tolerance").unwrap();
+    agg_config.future_timestamp_tolerance = match pebble::parse_duration(future_tolerance) {
+        Ok(tolerance) => tolerance,
+        Err(e) => {
+            error!(log, "Invalid future timestamp tolerance {}: {}", future_tolerance, e.to_string());
+            return;
+        }
+    };
+
+    for (flag, limit) in [
+        ("max-series-per-family", &mut agg_config.max_series_per_family),
+        ("max-series-per-job-label", &mut agg_config.max_series_per_job_label),
+        ("max-series", &mut agg_config.max_series),
+        ("max-families-per-push", &mut agg_config.max_families_per_push),
+        ("max-samples-per-push", &mut agg_config.max_samples_per_push),
+        ("max-label-name-length", &mut agg_config.max_label_name_length),
+        ("max-label-value-length", &mut agg_config.max_label_value_length),
+    ] {
+        if let Some(value) = matches.value_of(flag) {
+            *limit = match value.parse() {
+                Ok(l) if l > 0 => Some(l),
+                _ => {
+                    error!(log, "Invalid {}: {}", flag, value);
+                    return;
+                }
+            };
+        }
+    }
+
+    if let Some(path) = matches.value_of("relabel-config-file") {
+        agg_config.relabel_configs = match relabel::load_relabel_configs(path) {
+            Ok(configs) => configs,
+            Err(e) => {
+                error!(log, "Failed to load relabel config file ({}) - {}", path, e);
+                return;
+            }
+        };
+    }
+
+    let max_body_size = matches.value_of("max-body-size").unwrap();
+    let max_body_size = match max_body_size.parse() {
+        Ok(size) if size > 0 => size,
+        _ => {
+            error!(log, "Invalid max body size: {}", max_body_size);
+            return;
+        }
+    };
+
+    let mut rate_limit = None;
+    if let Some(limit) = matches.value_of("rate-limit") {
+        rate_limit = match limit.parse() {
+            Ok(limit) => Some(limit),
+            Err(e) => {
+                error!(log, "{}", e);
+                return;
+            }
+        };
+    }
+
+    let mut identity_rate_limits = HashMap::new();
+    for identity_limit in matches.values_of("rate-limit-identity").into_iter().flatten() {
+        let parsed = match identity_limit.split_once('=') {
+            Some((identity, limit)) => limit.parse().map(|limit| (identity.to_owned(), limit)),
+            None => Err(anyhow::anyhow!("expected <identity>=<pushes>/<duration>")),
+        };
+
+        match parsed {
+            Ok((identity, limit)) => identity_rate_limits.insert(identity, limit),
+            Err(e) => {
+                error!(log, "Invalid identity rate limit {}: {}", identity_limit, e);
+                return;
+            }
+        };
+    }
+
+    let rate_limiter = if rate_limit.is_some() || !identity_rate_limits.is_empty() {
+        // Clap has already checked that this is one of the possible values
+        let key = matches.value_of("rate-limit-key").unwrap().parse().unwrap();
+        Some(RateLimiter::new(key, rate_limit, identity_rate_limits))
+    } else {
+        None
+    };
+
+    let mut push_acl = None;
+    if let Some(path) = matches.value_of("push-acl-file") {
+        push_acl = match acl::load_push_acl(path) {
+            Ok(acl) => Some(acl),
+            Err(e) => {
+                error!(log, "Failed to load push ACL file ({}) - {}", path, e);
+                return;
+            }
+        };
+    }
+
+    let max_decompressed_size = matches.value_of("max-decompressed-size").unwrap();
+    let max_decompressed_size = match max_decompressed_size.parse() {
+        Ok(size) if size > 0 => size,
+        _ => {
+            error!(log, "Invalid max decompressed size: {}", max_decompressed_size);
+            return;
+        }
+    };
+
+    let mut tenancy = None;
+    if let Some(source) = matches.value_of("tenant-source") {
+        // Clap has already checked that this is one of the possible values
+        let source = match source.parse().unwrap() {
+            TenantSource::Header(_) => TenantSource::Header(matches.value_of("tenant-header").unwrap().to_owned()),
+            source => source,
+        };
+
+        let max_tenants = matches.value_of("max-tenants").unwrap();
+        let max_tenants = match max_tenants.parse() {
+            Ok(max) if max > 0 => max,
+            _ => {
+                error!(log, "Invalid max-tenants: {}", max_tenants);
+                return;
+            }
+        };
+
+        let mut overrides = HashMap::new();
+        if let Some(path) = matches.value_of("tenant-limits-file") {
+            overrides = match tenants::load_tenant_limits(path, &agg_config) {
+                Ok(overrides) => overrides,
+                Err(e) => {
+                    error!(log, "Failed to load tenant limits file ({}) - {}", path, e);
+                    return;
+                }
+            };
+        }
+
+        tenancy = Some(Tenancy::new(source, agg_config.clone(), max_tenants)
+            .with_overrides(overrides)
+            .with_public_scrapes(matches.is_present("public-tenant-scrapes"))
+            .with_public_pushes(matches.is_present("public-tenant-pushes")));
+    }
+
+    let agg = Aggregator::new_with_config(agg_config);
+
     #[cfg(feature="clustering")]
     let mut cluster_conf = None;
     #[cfg(feature="clustering")]
     {
         let cluster_enabled = matches.is_present("cluster-enabled");
+        if cluster_enabled && tenancy.is_some() {
+            // Forwarded pushes don't say which tenant they're for
+            error!(log, "Tenancy can't be used with clustering");
+            return;
+        }
+
         if cluster_enabled {
             let self_url = matches.value_of("listen").unwrap().to_owned() + "/metrics";
             if let Some(peers) = matches.values_of("peers") {
@@ -158,25 +583,44 @@ async fn main() {
 
     let mut config = RoutesConfig{
         authenticator: Box::new(pass_through_auth()),
+        max_body_size,
+        max_decompressed_size,
+        rate_limiter,
+        push_acl,
+        tenancy,
         #[cfg(feature="clustering")]
         cluster_conf
     };
 
     #[cfg(feature = "auth")]
     {
-        use auth::basic_auth;
-        if let Some(path) = matches.value_of("basic-auth-file") {
-            config = match basic_auth(PathBuf::from(path)) {
-                Ok(authenticator) => RoutesConfig {
-                    authenticator: Box::new(authenticator),
-                    cluster_conf: None
-                },
-                Err(e) => {
-                    error!(log, "Failed to load basic auth file ({}) - {}", path, e);
-                    return;
-                }
-            };
-        };
+        use auth::{SchemeAuthenticator, basic_auth, bearer_auth};
+        let basic_path = matches.value_of("basic-auth-file");
+        let bearer_path = matches.value_of("bearer-token-file");
+        if basic_path.is_some() || bearer_path.is_some() {
+            let mut authenticator = SchemeAuthenticator::default();
+            if let Some(path) = basic_path {
+                authenticator = match basic_auth(PathBuf::from(path)) {
+                    Ok(basic) => authenticator.with_scheme("Basic", basic),
+                    Err(e) => {
+                        error!(log, "Failed to load basic auth file ({}) - {}", path, e);
+                        return;
+                    }
+                };
+            }
+
+            if let Some(path) = bearer_path {
+                authenticator = match bearer_auth(PathBuf::from(path)) {
+                    Ok(bearer) => authenticator.with_scheme("Bearer", bearer),
+                    Err(e) => {
+                        error!(log, "Failed to load bearer token file ({}) - {}", path, e);
+                        return;
+                    }
+                };
+            }
+
+            config.authenticator = Box::new(authenticator);
+        }
     }
     
     let routes = routes::get_routes(agg, config);
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+
+use openmetrics_parser::{HistogramBucket, HistogramValue, MetricNumber};
+
+use crate::aggregator::AggregationError;
+
+/// The smallest schema that Prometheus supports for exponential buckets (each bucket is 65536x the last)
+const MIN_SCHEMA: i32 = -4;
+/// The largest schema that Prometheus supports for exponential buckets (each bucket is ~1.0027x the last)
+const MAX_SCHEMA: i32 = 8;
+
+/// A Prometheus native (sparse, exponential) histogram.
+///
+/// Bucket boundaries are fixed by the schema - the bucket with index `i` covers `(base^(i-1), base^i]`,
+/// where `base = 2^(2^-schema)` - so only the buckets that actually have observations in them are stored.
+/// Counts are stored as floats so that integer and float histograms can be merged together.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NativeHistogram {
+    pub schema: i32,
+    /// Observations with an absolute value at or below this go in the zero bucket, rather than a regular bucket
+    pub zero_threshold: f64,
+    pub zero_count: f64,
+    pub count: f64,
+    pub sum: f64,
+    /// The (non cumulative) count of each positive bucket, by bucket index
+    pub positive_buckets: BTreeMap<i32, f64>,
+    /// The (non cumulative) count of each negative bucket, by bucket index
+    pub negative_buckets: BTreeMap<i32, f64>,
+}
+
+/// Returns the upper bound of the bucket with the given index in the given schema
+fn bucket_upper_bound(schema: i32, index: i32) -> f64 {
+    2f64.powf(index as f64 * 2f64.powi(-schema))
+}
+
+/// Maps every bucket index into the index of the bucket that contains it at a schema `delta` lower,
+/// adding together the counts of buckets that end up in the same place
+fn reduce_buckets(buckets: &BTreeMap<i32, f64>, delta: i32) -> BTreeMap<i32, f64> {
+    let mut output = BTreeMap::new();
+    for (&index, &count) in buckets.iter() {
+        // Every 2^delta buckets merge into one, with bucket 1 always starting at 1 no matter the schema
+        *output.entry(((index - 1) >> delta) + 1).or_insert(0.) += count;
+    }
+
+    output
+}
+
+impl NativeHistogram {
+    /// Checks that this histogram is something that we can sensibly merge
+    pub fn validate(&self) -> Result<(), AggregationError> {
+        if self.schema < MIN_SCHEMA || self.schema > MAX_SCHEMA {
+            return Err(AggregationError::InvalidSample("invalid_native_histogram", format!("Native histogram schema {} is outside of the supported range {} to {}", self.schema, MIN_SCHEMA, MAX_SCHEMA)));
+        }
+
+        if self.zero_threshold.is_nan() || self.zero_threshold < 0. {
+            return Err(AggregationError::InvalidSample("invalid_native_histogram", format!("Native histogram zero threshold must be a non negative number, got {}", self.zero_threshold)));
+        }
+
+        let totals = [self.zero_count, self.count];
+        for &count in self.positive_buckets.values().chain(self.negative_buckets.values()).chain(totals.iter()) {
+            if !count.is_finite() || count < 0. {
+                return Err(AggregationError::InvalidSample("invalid_native_histogram", format!("Native histogram counts must be finite, non negative numbers, got {}", count)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lowers the resolution of this histogram to the given schema. Does nothing if the histogram
+    /// is already at that schema or lower, as the resolution can't be increased
+    pub fn downscale(&mut self, schema: i32) {
+        if schema >= self.schema {
+            return;
+        }
+
+        let delta = self.schema - schema;
+        self.positive_buckets = reduce_buckets(&self.positive_buckets, delta);
+        self.negative_buckets = reduce_buckets(&self.negative_buckets, delta);
+        self.schema = schema;
+    }
+
+    /// Widens the zero bucket to the given threshold, moving the counts of any buckets that now sit
+    /// entirely inside it into the zero bucket
+    fn widen_zero_bucket(&mut self, zero_threshold: f64) {
+        if zero_threshold <= self.zero_threshold {
+            return;
+        }
+
+        let schema = self.schema;
+        let mut absorbed = 0.;
+        for buckets in [&mut self.positive_buckets, &mut self.negative_buckets] {
+            let indexes: Vec<i32> = buckets.keys().copied().take_while(|&index| bucket_upper_bound(schema, index) <= zero_threshold).collect();
+            for index in indexes {
+                absorbed += buckets.remove(&index).unwrap_or(0.);
+            }
+        }
+
+        self.zero_count += absorbed;
+        self.zero_threshold = zero_threshold;
+    }
+
+    /// Adds the observations of another histogram into this one. If the two have different schemas, the result
+    /// has the lower resolution of the two, and if they have different zero thresholds, the result has the wider one
+    pub fn merge(&mut self, other: &NativeHistogram) {
+        let mut other = other.clone();
+        let schema = self.schema.min(other.schema);
+        self.downscale(schema);
+        other.downscale(schema);
+
+        let zero_threshold = self.zero_threshold.max(other.zero_threshold);
+        self.widen_zero_bucket(zero_threshold);
+        other.widen_zero_bucket(zero_threshold);
+
+        self.zero_count += other.zero_count;
+        self.count += other.count;
+        self.sum += other.sum;
+        for (index, count) in other.positive_buckets {
+            *self.positive_buckets.entry(index).or_insert(0.) += count;
+        }
+
+        for (index, count) in other.negative_buckets {
+            *self.negative_buckets.entry(index).or_insert(0.) += count;
+        }
+    }
+
+    /// Whether all the counts in this histogram are whole numbers, i.e. whether it can be exposed as an integer histogram
+    pub fn has_integer_counts(&self) -> bool {
+        self.positive_buckets.values().chain(self.negative_buckets.values()).chain([self.zero_count, self.count].iter()).all(|count| count.fract() == 0.)
+    }
+
+    /// Converts this histogram into a classic histogram with only a +Inf bucket, which is how native
+    /// histograms are exposed in the text format (which can't represent their buckets)
+    pub fn to_classic(&self) -> HistogramValue {
+        let count = MetricNumber::Int(self.count as i64);
+        HistogramValue {
+            sum: Some(MetricNumber::Float(self.sum)),
+            count: Some(self.count as u64),
+            created: None,
+            buckets: vec![HistogramBucket {
+                count,
+                upper_bound: f64::INFINITY,
+                exemplar: None,
+            }],
+        }
+    }
+}
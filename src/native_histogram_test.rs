@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+
+use crate::native_histogram::NativeHistogram;
+
+fn buckets(counts: &[(i32, f64)]) -> BTreeMap<i32, f64> {
+    counts.iter().copied().collect()
+}
+
+#[test]
+fn test_native_histogram_downscale() {
+    let mut histogram = NativeHistogram {
+        schema: 1,
+        count: 13.,
+        positive_buckets: buckets(&[(1, 1.), (2, 2.), (3, 3.), (4, 4.)]),
+        negative_buckets: buckets(&[(-1, 1.), (0, 2.)]),
+        ..Default::default()
+    };
+
+    histogram.downscale(0);
+    assert_eq!(histogram.schema, 0);
+    assert_eq!(histogram.positive_buckets, buckets(&[(1, 3.), (2, 7.)]));
+    assert_eq!(histogram.negative_buckets, buckets(&[(0, 3.)]));
+
+    // We can't increase the resolution of a histogram, so upscaling does nothing
+    histogram.downscale(2);
+    assert_eq!(histogram.schema, 0);
+}
+
+#[test]
+fn test_native_histogram_merge() {
+    let mut histogram = NativeHistogram {
+        schema: 0,
+        zero_threshold: 0.001,
+        zero_count: 1.,
+        count: 5.,
+        sum: 10.,
+        // Bucket -1 is (0.25, 0.5], so ends up inside the wider zero bucket of the other histogram
+        positive_buckets: buckets(&[(-1, 1.), (1, 1.), (2, 2.)]),
+        ..Default::default()
+    };
+
+    let other = NativeHistogram {
+        schema: 1,
+        zero_threshold: 0.6,
+        zero_count: 3.,
+        count: 8.,
+        sum: 5.,
+        // Bucket -1 at schema 1 is (0.5, 0.707], which becomes bucket 0 at schema 0, and straddles the zero threshold
+        positive_buckets: buckets(&[(-1, 1.), (3, 4.)]),
+        ..Default::default()
+    };
+
+    histogram.merge(&other);
+    assert_eq!(histogram, NativeHistogram {
+        schema: 0,
+        zero_threshold: 0.6,
+        zero_count: 5.,
+        count: 13.,
+        sum: 15.,
+        positive_buckets: buckets(&[(0, 1.), (1, 1.), (2, 6.)]),
+        ..Default::default()
+    });
+}
+
+#[test]
+fn test_native_histogram_validation() {
+    assert!(NativeHistogram::default().validate().is_ok());
+    assert!(NativeHistogram { schema: 9, ..Default::default() }.validate().is_err());
+    assert!(NativeHistogram { zero_threshold: -1., ..Default::default() }.validate().is_err());
+    assert!(NativeHistogram { positive_buckets: buckets(&[(1, -1.)]), ..Default::default() }.validate().is_err());
+}
@@ -1,7 +1,11 @@
 
 use std::{time::{Duration, SystemTime}, fmt};
 
-type MergeStrategy = fn(old: &PebbleEntry, new: &PebbleEntry) -> f64;
+use openmetrics_parser::{HistogramBucket, HistogramValue};
+
+use crate::aggregator::{AggregationError, merge_buckets, sum_optional};
+
+type MergeStrategy<T> = fn(old: &PebbleEntry<T>, new: &PebbleEntry<T>) -> T;
 
 pub fn sum_merge_strategy(old: &PebbleEntry, new: &PebbleEntry) -> f64 {
     old.value + new.value
@@ -18,46 +22,117 @@ pub fn mean_merge_strategy(old: &PebbleEntry, new: &PebbleEntry) -> f64 {
     top / bottom as f64
 }
 
+/// Windowed histograms sum the bucket counts (and _sum/_count) of each push, giving the distribution of every
+/// observation made inside the window
+pub fn histogram_merge_strategy(old: &PebbleEntry<HistogramValue>, new: &PebbleEntry<HistogramValue>) -> HistogramValue {
+    HistogramValue {
+        sum: sum_optional(old.value.sum, new.value.sum),
+        count: sum_optional(old.value.count, new.value.count),
+        created: None,
+        buckets: merge_buckets(&old.value.buckets, &new.value.buckets),
+    }
+}
+
+/// A value that can be stored in the buckets of a TimePebble
+pub trait PebbleValue: Default + Clone {
+    /// The number of bytes this value has allocated on the heap
+    fn heap_bytes(&self) -> usize {
+        0
+    }
+
+    /// The value as a single number, if it can be represented as one, used to track
+    /// the min and max values that were pushed into a window
+    fn as_sample(&self) -> Option<f64> {
+        None
+    }
+}
+
+impl PebbleValue for f64 {
+    fn as_sample(&self) -> Option<f64> {
+        Some(*self)
+    }
+}
+
+impl PebbleValue for HistogramValue {
+    fn heap_bytes(&self) -> usize {
+        self.buckets.capacity() * std::mem::size_of::<HistogramBucket>()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub struct PebbleEntry {
+pub struct PebbleEntry<T = f64> {
     weight: i32,
-    value: f64,
+    value: T,
+    min: f64,
+    max: f64,
 }
 
-impl PebbleEntry {
+impl<T: PebbleValue> PebbleEntry<T> {
+    fn new(weight: i32, value: T) -> PebbleEntry<T> {
+        let (min, max) = match value.as_sample() {
+            Some(sample) if weight > 0 => (sample, sample),
+            _ => (f64::INFINITY, f64::NEG_INFINITY),
+        };
+
+        PebbleEntry { weight, value, min, max }
+    }
+
     fn reset(&mut self) {
-        self.weight = 0;
-        self.value = 0.0;
+        *self = PebbleEntry::new(0, T::default());
     }
 }
 
+/// Why a value couldn't be added to a pebble
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppendError {
+    /// The value is from before the start of the window, so it would have already fallen out of it
+    TooOld,
+}
+
+/// Statistics about the values that have been pushed into the window of a TimePebble
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowStats {
+    /// The number of values pushed into the window
+    pub count: u64,
+    /// The smallest value in the window, if the values are plain numbers
+    pub min: Option<f64>,
+    /// The largest value in the window, if the values are plain numbers
+    pub max: Option<f64>,
+    /// The start of the oldest bucket that still holds data
+    pub start: Option<SystemTime>,
+}
+
+/// A TimePebble is a ring of time based buckets, each holding a pre-aggregated value of type T
+/// for the slice of time that it covers
 #[derive(Clone)]
-pub struct TimePebble {
-    buckets: Vec<PebbleEntry>,
-    merge: MergeStrategy,
+pub struct TimePebble<T = f64> {
+    buckets: Vec<PebbleEntry<T>>,
+    merge: MergeStrategy<T>,
     bucket_size_nanos: u128,
     last_bucket_index: usize,
     last_bucket_time_nanos: u128,
 }
 
-impl fmt::Debug for TimePebble {
+impl<T: fmt::Debug> fmt::Debug for TimePebble<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("TimePebble").field("buckets", &self.buckets).field("bucket_size_nanos", &self.bucket_size_nanos).field("last_bucket_index", &self.last_bucket_index).field("last_bucket_time_nanos", &self.last_bucket_time_nanos).finish()
     }
 }
 
-impl PartialEq for TimePebble {
+impl<T: PartialEq> PartialEq for TimePebble<T> {
     fn eq(&self, other: &Self) -> bool {
         self.buckets == other.buckets && self.bucket_size_nanos == other.bucket_size_nanos && self.last_bucket_index == other.last_bucket_index && self.last_bucket_time_nanos == other.last_bucket_time_nanos
     }
 }
 
-impl TimePebble {
-    pub fn new(time_span: Duration, granularity: usize, merge: MergeStrategy) -> TimePebble {
+impl<T: PebbleValue> TimePebble<T> {
+    /// Constructs a new TimePebble covering the given time span, split into `granularity` buckets
+    pub fn new(time_span: Duration, granularity: usize, merge: MergeStrategy<T>) -> TimePebble<T> {
+        let granularity = granularity.max(1);
         return TimePebble {
-            buckets: vec![PebbleEntry { weight: 0, value: 0. }; 100],
+            buckets: vec![PebbleEntry::new(0, T::default()); granularity],
             merge,
-            bucket_size_nanos: time_span.as_nanos() / granularity as u128,
+            bucket_size_nanos: (time_span.as_nanos() / granularity as u128).max(1),
             last_bucket_index: 0,
             last_bucket_time_nanos: 0,
         }
@@ -77,15 +152,17 @@ impl TimePebble {
             distance = (self.buckets.len() as isize - self.last_bucket_index as isize) + window_offset as isize
         }
 
-        for i in 1..distance as usize {
+        // Reset everything after the last bucket we wrote to, up to and including the one we're moving into
+        for i in 1..=distance as usize {
             let offset = (self.last_bucket_index + i) % self.buckets.len();
             self.buckets[offset].reset();
         }
     }
 
+    /// Moves the window forward to the given time, which must be at or after the newest bucket
     fn keep_consistent(&mut self, adjusted_time: u128, window_offset: usize) {
-        // If the time is before the last bucket, then we need to reset the window
-        if adjusted_time - self.last_bucket_time_nanos > self.buckets.len() as u128 {
+        // If we've moved a whole window (or more) along, then everything in it has expired
+        if adjusted_time - self.last_bucket_time_nanos >= self.buckets.len() as u128 {
             self.reset_window();
         }
 
@@ -102,58 +179,192 @@ impl TimePebble {
         return (adjusted_time, window_offset)
     }
 
-    pub fn append_with_timestamp(&mut self, value: f64, timestamp: SystemTime) {
+    /// Adds a value to the bucket for the given time. Values from before the newest bucket go into their own
+    /// (older) bucket without moving the window, as long as that bucket is still inside the window
+    pub fn append_with_timestamp(&mut self, value: T, timestamp: SystemTime) -> Result<(), AppendError> {
         let (adjusted_time, window_offset) = self.select_bucket(timestamp);
-        self.keep_consistent(adjusted_time, window_offset);
+        if adjusted_time < self.last_bucket_time_nanos {
+            if self.last_bucket_time_nanos - adjusted_time >= self.buckets.len() as u128 {
+                return Err(AppendError::TooOld);
+            }
+        } else {
+            self.keep_consistent(adjusted_time, window_offset);
+            self.last_bucket_time_nanos = adjusted_time;
+            self.last_bucket_index = window_offset;
+        }
 
-        self.buckets[window_offset].value = (self.merge)(&self.buckets[window_offset], &PebbleEntry {
-            weight: 1,
-            value,
-        });
-        self.buckets[window_offset].weight += 1;
+        let new_entry = PebbleEntry::new(1, value);
+        let bucket = &mut self.buckets[window_offset];
+        bucket.value = (self.merge)(bucket, &new_entry);
+        bucket.weight += 1;
+        bucket.min = bucket.min.min(new_entry.min);
+        bucket.max = bucket.max.max(new_entry.max);
 
-        self.last_bucket_time_nanos = adjusted_time;
-        self.last_bucket_index = window_offset;
+        Ok(())
     }
 
-    pub fn append(&mut self, value: f64) {
-        self.append_with_timestamp(value, SystemTime::now())
+    /// Returns the approximate number of bytes this pebble is using
+    pub fn memory_bytes(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.buckets.capacity() * std::mem::size_of::<PebbleEntry<T>>()
+            + self.buckets.iter().map(|bucket| bucket.value.heap_bytes()).sum::<usize>()
     }
 
-    pub fn aggregate(&self) -> f64 {
-        let mut pebble_value = PebbleEntry{
-            weight: 0,
-            value: 0.0,
-        };
+    pub fn aggregate(&self) -> T {
+        let mut pebble_value = PebbleEntry::new(0, T::default());
 
         for bucket in &self.buckets {
             if bucket.weight == 0 {
                 continue
             }
 
-            pebble_value = PebbleEntry {
-                weight: pebble_value.weight + bucket.weight,
-                value: (self.merge)(&pebble_value, bucket)
+            pebble_value = PebbleEntry::new(pebble_value.weight + bucket.weight, (self.merge)(&pebble_value, bucket));
+        }
+
+        return (self.merge)(&pebble_value, &PebbleEntry::new(0, T::default()));
+    }
+
+    /// Returns statistics about the values that are currently in the window
+    pub fn window_stats(&self) -> WindowStats {
+        let mut stats = WindowStats {
+            count: 0,
+            min: None,
+            max: None,
+            start: None,
+        };
+
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            if bucket.weight == 0 {
+                continue
+            }
+
+            stats.count += bucket.weight as u64;
+            if bucket.min <= bucket.max {
+                stats.min = Some(stats.min.map_or(bucket.min, |min| min.min(bucket.min)));
+                stats.max = Some(stats.max.map_or(bucket.max, |max| max.max(bucket.max)));
             }
+
+            // Work out how many buckets ago this one was written, and so what time it starts at
+            let age = (self.last_bucket_index + self.buckets.len() - index) % self.buckets.len();
+            let bucket_start_nanos = (self.last_bucket_time_nanos - age as u128) * self.bucket_size_nanos;
+            let bucket_start = SystemTime::UNIX_EPOCH + Duration::from_nanos(bucket_start_nanos as u64);
+            stats.start = Some(stats.start.map_or(bucket_start, |start| start.min(bucket_start)));
         }
 
-        return (self.merge)(&pebble_value, &PebbleEntry {
-            weight: 0,
-            value: 0.0,
-        });
+        stats
     }
 }
 
-pub fn parse_duration(s: &str) -> Option<Duration> {
-    let magnitude = match s.chars().filter(|c| c.is_numeric()).collect::<String>().parse() {
-        Ok(m) => m,
-        Err(_) => return None,
-    };
-    
-    match s.chars().last() {
-        Some('s') => Some(Duration::from_secs(magnitude)),
-        Some('m') => Some(Duration::from_secs(magnitude * 60)),
-        Some('h') => Some(Duration::from_secs(magnitude * 60 * 60)),
-        _ => return None
+/// An EwmaPebble is an exponentially weighted moving average over wall time. Rather than
+/// keeping a hard window, the weight of every value halves each `half_life` after it was pushed
+#[derive(Debug, Clone, PartialEq)]
+pub struct EwmaPebble {
+    half_life_nanos: f64,
+    weighted_sum: f64,
+    weight: f64,
+    last_update_nanos: u128,
+}
+
+impl EwmaPebble {
+    pub fn new(half_life: Duration) -> EwmaPebble {
+        EwmaPebble {
+            half_life_nanos: half_life.as_nanos() as f64,
+            weighted_sum: 0.,
+            weight: 0.,
+            last_update_nanos: 0,
+        }
+    }
+
+    /// Returns how much a value decays over the given number of nanoseconds
+    fn decay_over(&self, nanos: u128) -> f64 {
+        if self.half_life_nanos == 0. {
+            return 1.;
+        }
+
+        0.5_f64.powf(nanos as f64 / self.half_life_nanos)
+    }
+
+    pub fn append_with_timestamp(&mut self, value: f64, timestamp: SystemTime) {
+        let timestamp_nanos = timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+        if timestamp_nanos < self.last_update_nanos {
+            // A late value has already decayed by the time of the newest one, so only adds its remaining weight
+            let decay = self.decay_over(self.last_update_nanos - timestamp_nanos);
+            self.weighted_sum += value * decay;
+            self.weight += decay;
+            return;
+        }
+
+        let decay = self.decay_over(timestamp_nanos - self.last_update_nanos);
+        self.weighted_sum = self.weighted_sum * decay + value;
+        self.weight = self.weight * decay + 1.;
+        self.last_update_nanos = timestamp_nanos;
+    }
+
+    pub fn aggregate(&self) -> f64 {
+        // Decaying the sum and the weight by the same factor leaves their ratio unchanged, so
+        // the average itself doesn't need to be brought forward to the render time
+        if self.weight == 0. {
+            return 0.;
+        }
+
+        self.weighted_sum / self.weight
     }
-}
\ No newline at end of file
+}
+
+/// Parses a duration in the same format as Prometheus, i.e. a sequence of numbers each followed by a unit
+/// (one of ms, s, m, h, d, w), with the units in descending order and each used at most once, e.g. `1h30m`
+pub fn parse_duration(s: &str) -> Result<Duration, AggregationError> {
+    const UNITS: [(&str, u64); 6] = [
+        ("w", 7 * 24 * 60 * 60 * 1000),
+        ("d", 24 * 60 * 60 * 1000),
+        ("h", 60 * 60 * 1000),
+        ("m", 60 * 1000),
+        ("s", 1000),
+        ("ms", 1),
+    ];
+
+    if s.is_empty() {
+        return Err(AggregationError::Error("Invalid duration: empty duration string".to_owned()));
+    }
+
+    let mut remaining = s;
+    let mut total_millis: u64 = 0;
+    // The index into UNITS of the last unit we saw, so that we can enforce that they're in descending order
+    let mut last_unit: Option<usize> = None;
+    while !remaining.is_empty() {
+        let num_digits = remaining.chars().take_while(|c| c.is_ascii_digit()).count();
+        if num_digits == 0 {
+            return Err(AggregationError::Error(format!("Invalid duration `{}`: expected a number at `{}`", s, remaining)));
+        }
+
+        let magnitude: u64 = remaining[..num_digits].parse().map_err(|_| AggregationError::Error(format!("Invalid duration `{}`: number is too large", s)))?;
+        remaining = &remaining[num_digits..];
+
+        let unit_len = remaining.chars().take_while(|c| c.is_ascii_alphabetic()).count();
+        let unit = &remaining[..unit_len];
+        remaining = &remaining[unit_len..];
+
+        let unit_index = match UNITS.iter().position(|(name, _)| *name == unit) {
+            Some(index) => index,
+            None if unit.is_empty() => return Err(AggregationError::Error(format!("Invalid duration `{}`: missing unit after {}", s, magnitude))),
+            None => return Err(AggregationError::Error(format!("Invalid duration `{}`: unknown unit `{}` (expected one of ms, s, m, h, d, w)", s, unit)))
+        };
+
+        if let Some(last) = last_unit {
+            if unit_index <= last {
+                return Err(AggregationError::Error(format!("Invalid duration `{}`: units must be in descending order, and each used at most once", s)));
+            }
+        }
+
+        last_unit = Some(unit_index);
+        total_millis = magnitude.checked_mul(UNITS[unit_index].1)
+            .and_then(|millis| total_millis.checked_add(millis))
+            .ok_or_else(|| AggregationError::Error(format!("Invalid duration `{}`: duration is too long", s)))?;
+    }
+
+    if total_millis == 0 {
+        return Err(AggregationError::Error(format!("Invalid duration `{}`: duration must be greater than zero", s)));
+    }
+
+    Ok(Duration::from_millis(total_millis))
+}
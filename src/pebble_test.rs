@@ -0,0 +1,87 @@
+use std::time::{Duration, SystemTime};
+
+use crate::pebble::*;
+
+#[test]
+fn test_ewma_decay() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let mut ewma = EwmaPebble::new(Duration::from_secs(60));
+    assert_eq!(ewma.aggregate(), 0.);
+
+    ewma.append_with_timestamp(10., start);
+    assert_eq!(ewma.aggregate(), 10.);
+
+    // One half life later, the first value should only count for half as much as the new one
+    ewma.append_with_timestamp(20., start + Duration::from_secs(60));
+    assert!((ewma.aggregate() - 25. / 1.5).abs() < 1e-9, "unexpected ewma value {}", ewma.aggregate());
+
+    // Values pushed at the same time are weighted equally
+    let mut ewma = EwmaPebble::new(Duration::from_secs(60));
+    ewma.append_with_timestamp(10., start);
+    ewma.append_with_timestamp(20., start);
+    assert_eq!(ewma.aggregate(), 15.);
+}
+
+#[test]
+fn test_pebble_granularity() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let mut pebble = TimePebble::new(Duration::from_secs(60), 6, sum_merge_strategy);
+    let coarse_memory = pebble.memory_bytes();
+
+    // With 6 buckets over a minute, each bucket covers 10 seconds, so these should land in separate buckets
+    // and all be counted
+    for i in 0..6 {
+        pebble.append_with_timestamp(1., start + Duration::from_secs(i * 10)).unwrap();
+    }
+    assert_eq!(pebble.aggregate(), 6.);
+
+    // Once we wrap around the ring, the oldest bucket should be replaced
+    pebble.append_with_timestamp(1., start + Duration::from_secs(60)).unwrap();
+    assert_eq!(pebble.aggregate(), 6.);
+
+    let fine_pebble = TimePebble::new(Duration::from_secs(60), 600, sum_merge_strategy);
+    assert!(fine_pebble.memory_bytes() > coarse_memory, "more buckets should use more memory");
+}
+
+#[test]
+fn test_late_values() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let mut pebble = TimePebble::new(Duration::from_secs(60), 6, sum_merge_strategy);
+    pebble.append_with_timestamp(1., start + Duration::from_secs(50)).unwrap();
+
+    // A late value still inside the window goes into its own bucket, so expires with it rather than with the newest one
+    pebble.append_with_timestamp(2., start + Duration::from_secs(10)).unwrap();
+    assert_eq!(pebble.aggregate(), 3.);
+    pebble.append_with_timestamp(4., start + Duration::from_secs(75)).unwrap();
+    assert_eq!(pebble.aggregate(), 5.);
+
+    // Values from before the start of the window are rejected
+    assert_eq!(pebble.append_with_timestamp(8., start + Duration::from_secs(10)), Err(AppendError::TooOld));
+    assert_eq!(pebble.aggregate(), 5.);
+
+    // A late value in an EWMA counts for as much as it has decayed by the time of the newest value
+    let mut ewma = EwmaPebble::new(Duration::from_secs(60));
+    ewma.append_with_timestamp(20., start + Duration::from_secs(60));
+    ewma.append_with_timestamp(10., start);
+    assert!((ewma.aggregate() - 25. / 1.5).abs() < 1e-9, "unexpected ewma value {}", ewma.aggregate());
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+    assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+    assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(5 * 60));
+    assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(90 * 60));
+    assert_eq!(parse_duration("7d").unwrap(), Duration::from_secs(7 * 24 * 60 * 60));
+    assert_eq!(parse_duration("2w").unwrap(), Duration::from_secs(14 * 24 * 60 * 60));
+    assert_eq!(parse_duration("1d12h30m15s500ms").unwrap(), Duration::from_millis(((36 * 60 + 30) * 60 + 15) * 1000 + 500));
+
+    assert!(parse_duration("").is_err(), "empty durations should be rejected");
+    assert!(parse_duration("5").is_err(), "durations without a unit should be rejected");
+    assert!(parse_duration("m").is_err(), "durations without a number should be rejected");
+    assert!(parse_duration("5y").is_err(), "unknown units should be rejected");
+    assert!(parse_duration("30m1h").is_err(), "units out of order should be rejected");
+    assert!(parse_duration("1m1m").is_err(), "repeated units should be rejected");
+    assert!(parse_duration("0s").is_err(), "zero durations should be rejected");
+    assert!(parse_duration("1h 30m").is_err(), "whitespace should be rejected");
+}
@@ -0,0 +1,537 @@
+use std::collections::{BTreeMap, HashMap};
+
+use openmetrics_parser::{Exemplar as PrometheusExemplar, HistogramBucket, HistogramValue, MetricNumber, PrometheusCounterValue, PrometheusType, PrometheusValue, Quantile as PrometheusQuantile, Sample, SummaryValue};
+use prost::Message;
+
+use crate::aggregator::{AggregationError, GravelMetricFamily, GravelValue, is_valid_label_name, is_valid_metric_name};
+use crate::native_histogram::NativeHistogram;
+
+/// The media type that Prometheus uses for the protobuf exposition format, without any parameters
+pub const PROTOBUF_MEDIA_TYPE: &str = "application/vnd.google.protobuf";
+
+/// The full content type of a protobuf exposition, which is a stream of length delimited MetricFamily messages
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+// The messages below are the subset of Prometheus' io.prometheus.client protobuf definitions (metrics.proto) that we use.
+// Fields that we don't know about (e.g. created timestamps) are skipped when decoding
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LabelPair {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum MetricType {
+    Counter = 0,
+    Gauge = 1,
+    Summary = 2,
+    Untyped = 3,
+    Histogram = 4,
+    GaugeHistogram = 5,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Timestamp {
+    #[prost(int64, tag = "1")]
+    pub seconds: i64,
+    #[prost(int32, tag = "2")]
+    pub nanos: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Exemplar {
+    #[prost(message, repeated, tag = "1")]
+    pub label: Vec<LabelPair>,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+    #[prost(message, optional, tag = "3")]
+    pub timestamp: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Gauge {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Counter {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    #[prost(message, optional, tag = "2")]
+    pub exemplar: Option<Exemplar>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Quantile {
+    #[prost(double, tag = "1")]
+    pub quantile: f64,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Summary {
+    #[prost(uint64, tag = "1")]
+    pub sample_count: u64,
+    #[prost(double, tag = "2")]
+    pub sample_sum: f64,
+    #[prost(message, repeated, tag = "3")]
+    pub quantile: Vec<Quantile>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Untyped {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Bucket {
+    #[prost(uint64, tag = "1")]
+    pub cumulative_count: u64,
+    #[prost(double, tag = "4")]
+    pub cumulative_count_float: f64,
+    #[prost(double, tag = "2")]
+    pub upper_bound: f64,
+    #[prost(message, optional, tag = "3")]
+    pub exemplar: Option<Exemplar>,
+}
+
+/// A run of consecutive native histogram buckets. The first span's offset is the index of its first bucket,
+/// and every other span's offset is the gap between it and the end of the previous span
+#[derive(Clone, PartialEq, Message)]
+pub struct BucketSpan {
+    #[prost(sint32, tag = "1")]
+    pub offset: i32,
+    #[prost(uint32, tag = "2")]
+    pub length: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Histogram {
+    #[prost(uint64, tag = "1")]
+    pub sample_count: u64,
+    #[prost(double, tag = "4")]
+    pub sample_count_float: f64,
+    #[prost(double, tag = "2")]
+    pub sample_sum: f64,
+    #[prost(message, repeated, tag = "3")]
+    pub bucket: Vec<Bucket>,
+    #[prost(sint32, tag = "5")]
+    pub schema: i32,
+    #[prost(double, tag = "6")]
+    pub zero_threshold: f64,
+    #[prost(uint64, tag = "7")]
+    pub zero_count: u64,
+    #[prost(double, tag = "8")]
+    pub zero_count_float: f64,
+    #[prost(message, repeated, tag = "9")]
+    pub negative_span: Vec<BucketSpan>,
+    #[prost(sint64, repeated, packed = "false", tag = "10")]
+    pub negative_delta: Vec<i64>,
+    #[prost(double, repeated, packed = "false", tag = "11")]
+    pub negative_count: Vec<f64>,
+    #[prost(message, repeated, tag = "12")]
+    pub positive_span: Vec<BucketSpan>,
+    #[prost(sint64, repeated, packed = "false", tag = "13")]
+    pub positive_delta: Vec<i64>,
+    #[prost(double, repeated, packed = "false", tag = "14")]
+    pub positive_count: Vec<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(message, repeated, tag = "1")]
+    pub label: Vec<LabelPair>,
+    #[prost(message, optional, tag = "2")]
+    pub gauge: Option<Gauge>,
+    #[prost(message, optional, tag = "3")]
+    pub counter: Option<Counter>,
+    #[prost(message, optional, tag = "4")]
+    pub summary: Option<Summary>,
+    #[prost(message, optional, tag = "5")]
+    pub untyped: Option<Untyped>,
+    #[prost(message, optional, tag = "7")]
+    pub histogram: Option<Histogram>,
+    #[prost(int64, optional, tag = "6")]
+    pub timestamp_ms: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MetricFamily {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub help: String,
+    #[prost(enumeration = "MetricType", tag = "3")]
+    pub r#type: i32,
+    #[prost(message, repeated, tag = "4")]
+    pub metric: Vec<Metric>,
+    #[prost(string, tag = "5")]
+    pub unit: String,
+}
+
+/// Decodes a protobuf exposition (a stream of length delimited MetricFamily messages) into families that
+/// can be merged into an Aggregator, adding the given extra labels to every metric. Each family is passed to `check`
+/// as soon as it's decoded, so that decoding can be stopped as soon as one is rejected
+pub fn decode_families<F>(mut data: &[u8], extra_labels: &HashMap<&str, &str>, mut check: F) -> Result<Vec<GravelMetricFamily>, AggregationError>
+where
+    F: FnMut(&GravelMetricFamily) -> Result<(), AggregationError>,
+{
+    let mut families = Vec::new();
+    while !data.is_empty() {
+        let family = MetricFamily::decode_length_delimited(&mut data).map_err(|e| AggregationError::Error(format!("Invalid protobuf exposition: {}", e)))?;
+        let family = decode_family(family, extra_labels)?;
+        check(&family)?;
+        families.push(family);
+    }
+
+    Ok(families)
+}
+
+/// Encodes the given families into a protobuf exposition
+pub fn encode_families<'a, T>(families: T) -> Vec<u8> where T: IntoIterator<Item = &'a GravelMetricFamily> {
+    let mut output = Vec::new();
+    for family in families {
+        encode_family(family).encode_length_delimited(&mut output).expect("a Vec always has enough capacity to encode into");
+    }
+
+    output
+}
+
+fn decode_family(family: MetricFamily, extra_labels: &HashMap<&str, &str>) -> Result<GravelMetricFamily, AggregationError> {
+    // Unlike the text format, nothing in protobuf stops names from containing anything, and they're written
+    // out as is when we're scraped, so a bad name could inject whole new series into the exposition
+    if !is_valid_metric_name(&family.name) {
+        return Err(AggregationError::Error(format!("Invalid metric name {:?}", family.name)));
+    }
+
+    if let Some(label) = family.metric.iter().flat_map(|metric| metric.label.iter()).find(|label| !is_valid_label_name(&label.name)) {
+        return Err(AggregationError::Error(format!("Invalid label name {:?} in {}", label.name, family.name)));
+    }
+
+    let family_type = match MetricType::from_i32(family.r#type) {
+        Some(MetricType::Counter) => PrometheusType::Counter,
+        Some(MetricType::Gauge) => PrometheusType::Gauge,
+        Some(MetricType::Summary) => PrometheusType::Summary,
+        Some(MetricType::Untyped) => PrometheusType::Unknown,
+        Some(MetricType::Histogram) => PrometheusType::Histogram,
+        Some(MetricType::GaugeHistogram) | None => return Err(AggregationError::Error(format!("Unsupported metric type {} for {}", family.r#type, family.name)))
+    };
+
+    // Every metric in a protobuf family carries its own labels, so the family's label names are the union of all of them
+    let mut label_names: Vec<String> = family.metric.iter()
+        .flat_map(|metric| metric.label.iter().map(|label| label.name.clone()))
+        .chain(extra_labels.keys().map(|&name| name.to_owned()))
+        .collect();
+    label_names.sort();
+    label_names.dedup();
+
+    let mut output = GravelMetricFamily::new(family.name.clone(), label_names.clone(), family_type, family.help, family.unit);
+    for metric in family.metric {
+        let label_values = label_names.iter().map(|name| {
+            match extra_labels.get(name.as_str()) {
+                Some(&value) => value.to_owned(),
+                None => metric.label.iter().find(|label| &label.name == name).map(|label| label.value.clone()).unwrap_or_default()
+            }
+        }).collect();
+
+        // Sample timestamps are kept in milliseconds, the same as the text format
+        let timestamp = metric.timestamp_ms.map(|millis| millis as f64);
+        let value = decode_value(&family.name, &output.family_type, metric)?;
+        output.add_sample(Sample::new(label_values, timestamp, value))?;
+    }
+
+    Ok(output)
+}
+
+fn decode_value(family_name: &str, family_type: &PrometheusType, metric: Metric) -> Result<GravelValue, AggregationError> {
+    let missing = || AggregationError::Error(format!("A metric in {} is missing its {} value", family_name, family_type));
+    let value = match family_type {
+        PrometheusType::Counter => {
+            let counter = metric.counter.ok_or_else(missing)?;
+            PrometheusValue::Counter(PrometheusCounterValue {
+                value: MetricNumber::Float(counter.value),
+                exemplar: counter.exemplar.map(decode_exemplar),
+            })
+        },
+        PrometheusType::Gauge => PrometheusValue::Gauge(MetricNumber::Float(metric.gauge.ok_or_else(missing)?.value)),
+        PrometheusType::Unknown => PrometheusValue::Unknown(MetricNumber::Float(metric.untyped.ok_or_else(missing)?.value)),
+        PrometheusType::Summary => {
+            let summary = metric.summary.ok_or_else(missing)?;
+            PrometheusValue::Summary(SummaryValue {
+                sum: Some(MetricNumber::Float(summary.sample_sum)),
+                count: Some(summary.sample_count),
+                created: None,
+                quantiles: summary.quantile.iter().map(|quantile| PrometheusQuantile { quantile: quantile.quantile, value: MetricNumber::Float(quantile.value) }).collect(),
+            })
+        },
+        PrometheusType::Histogram => {
+            let histogram = metric.histogram.ok_or_else(missing)?;
+            if is_native(&histogram) {
+                return Ok(GravelValue::NativeHistogram(decode_native_histogram(&histogram)?));
+            }
+
+            PrometheusValue::Histogram(decode_classic_histogram(histogram))
+        }
+    };
+
+    Ok(GravelValue::Prometheus(value))
+}
+
+fn decode_exemplar(exemplar: Exemplar) -> PrometheusExemplar {
+    let labels = exemplar.label.into_iter().map(|label| (label.name, label.value)).collect();
+    let timestamp = exemplar.timestamp.map(|timestamp| timestamp.seconds as f64 + timestamp.nanos as f64 / 1e9);
+    PrometheusExemplar::new(labels, exemplar.value, timestamp)
+}
+
+fn encode_exemplar(exemplar: &PrometheusExemplar) -> Exemplar {
+    let mut label: Vec<LabelPair> = exemplar.labels.iter().map(|(name, value)| LabelPair { name: name.clone(), value: value.clone() }).collect();
+    label.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Exemplar {
+        label,
+        value: exemplar.id,
+        timestamp: exemplar.timestamp.map(|timestamp| Timestamp { seconds: timestamp.trunc() as i64, nanos: (timestamp.fract() * 1e9) as i32 }),
+    }
+}
+
+/// Clients expose a histogram as native if it has any native buckets or zero bucket. An empty native histogram
+/// is marked with a single empty span, so that it can still be told apart from a classic one
+fn is_native(histogram: &Histogram) -> bool {
+    !histogram.positive_span.is_empty() || !histogram.negative_span.is_empty() || histogram.zero_threshold > 0. || histogram.zero_count > 0 || histogram.zero_count_float > 0.
+}
+
+fn decode_classic_histogram(histogram: Histogram) -> HistogramValue {
+    let count = if histogram.sample_count_float > 0. { histogram.sample_count_float as u64 } else { histogram.sample_count };
+    let mut buckets: Vec<HistogramBucket> = histogram.bucket.into_iter().map(|bucket| HistogramBucket {
+        count: if bucket.cumulative_count_float > 0. { MetricNumber::Float(bucket.cumulative_count_float) } else { MetricNumber::Int(bucket.cumulative_count as i64) },
+        upper_bound: bucket.upper_bound,
+        exemplar: bucket.exemplar.map(decode_exemplar),
+    }).collect();
+
+    // The +Inf bucket is implied by the sample count in the protobuf format
+    if buckets.last().is_none_or(|bucket| bucket.upper_bound != f64::INFINITY) {
+        buckets.push(HistogramBucket {
+            count: MetricNumber::Int(count as i64),
+            upper_bound: f64::INFINITY,
+            exemplar: None,
+        });
+    }
+
+    HistogramValue {
+        sum: Some(MetricNumber::Float(histogram.sample_sum)),
+        count: Some(count),
+        created: None,
+        buckets,
+    }
+}
+
+fn encode_classic_histogram(histogram: &HistogramValue) -> Histogram {
+    let inf_count = histogram.buckets.last().filter(|bucket| bucket.upper_bound == f64::INFINITY).map_or(0, |bucket| bucket.count.as_f64() as u64);
+    Histogram {
+        sample_count: histogram.count.unwrap_or(inf_count),
+        sample_sum: histogram.sum.map_or(0., |sum| sum.as_f64()),
+        bucket: histogram.buckets.iter().filter(|bucket| bucket.upper_bound != f64::INFINITY).map(|bucket| {
+            let (cumulative_count, cumulative_count_float) = match bucket.count {
+                MetricNumber::Int(count) => (count as u64, 0.),
+                MetricNumber::Float(count) => (0, count),
+            };
+
+            Bucket {
+                cumulative_count,
+                cumulative_count_float,
+                upper_bound: bucket.upper_bound,
+                exemplar: bucket.exemplar.as_ref().map(encode_exemplar),
+            }
+        }).collect(),
+        ..Default::default()
+    }
+}
+
+/// Expands a set of spans and their counts into a map of bucket index to count
+fn decode_buckets(spans: &[BucketSpan], deltas: &[i64], counts: &[f64], is_float: bool) -> Result<BTreeMap<i32, f64>, AggregationError> {
+    let overflow = || AggregationError::Error(String::from("Native histogram bucket indexes or counts overflowed"));
+
+    // The spans come from the client, so they're checked against the number of counts before they're expanded,
+    // otherwise a tiny push with a huge span could make us allocate billions of indexes
+    let num_counts = if is_float { counts.len() } else { deltas.len() };
+    let num_buckets = spans.iter().try_fold(0usize, |total, span| total.checked_add(span.length as usize)).ok_or_else(overflow)?;
+    if num_buckets != num_counts {
+        return Err(AggregationError::Error(format!("Native histogram spans describe {} buckets, but {} bucket counts were given", num_buckets, num_counts)));
+    }
+
+    let mut indexes = Vec::with_capacity(num_buckets);
+    let mut index: i32 = 0;
+    for span in spans {
+        index = index.checked_add(span.offset).ok_or_else(overflow)?;
+        for _ in 0..span.length {
+            indexes.push(index);
+            index = index.checked_add(1).ok_or_else(overflow)?;
+        }
+    }
+
+    let counts: Vec<f64> = if is_float {
+        counts.to_vec()
+    } else {
+        // Integer counts are delta encoded against the bucket before them
+        let mut count: i64 = 0;
+        deltas.iter().map(|&delta| {
+            count = count.checked_add(delta).ok_or_else(overflow)?;
+            Ok(count as f64)
+        }).collect::<Result<_, AggregationError>>()?
+    };
+
+    Ok(indexes.into_iter().zip(counts).collect())
+}
+
+/// Compresses a map of bucket index to count into a set of spans and the count of each bucket in them
+fn encode_buckets(buckets: &BTreeMap<i32, f64>) -> (Vec<BucketSpan>, Vec<f64>) {
+    let mut spans: Vec<BucketSpan> = Vec::new();
+    let mut last_index = None;
+    for &index in buckets.keys() {
+        match last_index {
+            Some(last) if index == last + 1 => spans.last_mut().expect("a span is pushed before the first index is recorded").length += 1,
+            Some(last) => spans.push(BucketSpan { offset: index - last - 1, length: 1 }),
+            None => spans.push(BucketSpan { offset: index, length: 1 }),
+        }
+
+        last_index = Some(index);
+    }
+
+    (spans, buckets.values().copied().collect())
+}
+
+fn delta_encode(counts: &[f64]) -> Vec<i64> {
+    let mut previous = 0;
+    counts.iter().map(|&count| {
+        let count = count as i64;
+        let delta = count - previous;
+        previous = count;
+        delta
+    }).collect()
+}
+
+fn decode_native_histogram(histogram: &Histogram) -> Result<NativeHistogram, AggregationError> {
+    // Float histograms put their counts in the _float and _count fields, and integer ones in the plain and _delta fields
+    let is_float = !histogram.positive_count.is_empty() || !histogram.negative_count.is_empty() || histogram.sample_count_float > 0. || histogram.zero_count_float > 0.;
+
+    Ok(NativeHistogram {
+        schema: histogram.schema,
+        zero_threshold: histogram.zero_threshold,
+        zero_count: if is_float { histogram.zero_count_float } else { histogram.zero_count as f64 },
+        count: if is_float { histogram.sample_count_float } else { histogram.sample_count as f64 },
+        sum: histogram.sample_sum,
+        positive_buckets: decode_buckets(&histogram.positive_span, &histogram.positive_delta, &histogram.positive_count, is_float)?,
+        negative_buckets: decode_buckets(&histogram.negative_span, &histogram.negative_delta, &histogram.negative_count, is_float)?,
+    })
+}
+
+fn encode_native_histogram(histogram: &NativeHistogram) -> Histogram {
+    let (positive_span, positive_counts) = encode_buckets(&histogram.positive_buckets);
+    let (negative_span, negative_counts) = encode_buckets(&histogram.negative_buckets);
+    let mut output = Histogram {
+        sample_sum: histogram.sum,
+        schema: histogram.schema,
+        zero_threshold: histogram.zero_threshold,
+        positive_span,
+        negative_span,
+        ..Default::default()
+    };
+
+    if histogram.has_integer_counts() {
+        output.sample_count = histogram.count as u64;
+        output.zero_count = histogram.zero_count as u64;
+        output.positive_delta = delta_encode(&positive_counts);
+        output.negative_delta = delta_encode(&negative_counts);
+    } else {
+        output.sample_count_float = histogram.count;
+        output.zero_count_float = histogram.zero_count;
+        output.positive_count = positive_counts;
+        output.negative_count = negative_counts;
+    }
+
+    if !is_native(&output) {
+        output.positive_span.push(BucketSpan { offset: 0, length: 0 });
+    }
+
+    output
+}
+
+/// Sets the value of a metric that only has a single number, using the field that matches the type of its family
+fn set_scalar(metric: &mut Metric, family_type: &PrometheusType, value: f64) {
+    match family_type {
+        PrometheusType::Counter => metric.counter = Some(Counter { value, exemplar: None }),
+        PrometheusType::Gauge => metric.gauge = Some(Gauge { value }),
+        _ => metric.untyped = Some(Untyped { value }),
+    }
+}
+
+fn encode_family(family: &GravelMetricFamily) -> MetricFamily {
+    let metric_type = match family.family_type {
+        PrometheusType::Counter => MetricType::Counter,
+        PrometheusType::Gauge => MetricType::Gauge,
+        PrometheusType::Summary => MetricType::Summary,
+        PrometheusType::Unknown => MetricType::Untyped,
+        PrometheusType::Histogram => MetricType::Histogram,
+    };
+
+    let mut metrics = Vec::new();
+    for sample in family.iter_samples() {
+        let labelset = match sample.get_labelset() {
+            Ok(labelset) => labelset,
+            Err(_) => continue
+        };
+
+        let mut metric = Metric {
+            // Prometheus treats empty labels as missing ones
+            label: labelset.iter().filter(|(_, value)| !value.is_empty()).map(|(name, value)| LabelPair { name: name.clone(), value: value.clone() }).collect(),
+            timestamp_ms: sample.timestamp.map(|timestamp| timestamp as i64),
+            ..Default::default()
+        };
+
+        match &sample.value {
+            GravelValue::Prometheus(PrometheusValue::Counter(counter)) => {
+                metric.counter = Some(Counter {
+                    value: counter.value.as_f64(),
+                    exemplar: counter.exemplar.as_ref().map(encode_exemplar),
+                });
+            },
+            GravelValue::Prometheus(PrometheusValue::Gauge(value)) | GravelValue::Prometheus(PrometheusValue::Unknown(value)) => set_scalar(&mut metric, &family.family_type, value.as_f64()),
+            GravelValue::Prometheus(PrometheusValue::Summary(summary)) => {
+                metric.summary = Some(Summary {
+                    sample_count: summary.count.unwrap_or(0),
+                    sample_sum: summary.sum.map_or(0., |sum| sum.as_f64()),
+                    quantile: summary.quantiles.iter().map(|quantile| Quantile { quantile: quantile.quantile, value: quantile.value.as_f64() }).collect(),
+                });
+            },
+            GravelValue::Prometheus(PrometheusValue::Histogram(histogram)) => metric.histogram = Some(encode_classic_histogram(histogram)),
+            GravelValue::Pebble(pebble) => set_scalar(&mut metric, &family.family_type, pebble.aggregate()),
+            GravelValue::HistogramPebble(pebble) => metric.histogram = Some(encode_classic_histogram(&pebble.aggregate())),
+            GravelValue::Ewma(ewma) => set_scalar(&mut metric, &family.family_type, ewma.aggregate()),
+            GravelValue::NativeHistogram(histogram) => metric.histogram = Some(encode_native_histogram(histogram)),
+            GravelValue::Cumulative(counter) => {
+                let counter = counter.to_counter();
+                metric.counter = Some(Counter {
+                    value: counter.value.as_f64(),
+                    exemplar: counter.exemplar.as_ref().map(encode_exemplar),
+                });
+            },
+        }
+
+        metrics.push(metric);
+    }
+
+    MetricFamily {
+        name: family.family_name.clone(),
+        help: family.help.clone(),
+        r#type: metric_type as i32,
+        metric: metrics,
+        unit: family.unit.clone(),
+    }
+}
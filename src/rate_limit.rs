@@ -0,0 +1,187 @@
+use std::{collections::{BTreeMap, HashMap}, str::FromStr, sync::Mutex, time::{Duration, Instant}};
+
+use anyhow::anyhow;
+use openmetrics_parser::{MetricNumber, PrometheusType};
+
+use crate::{pebble::parse_duration, self_metrics::SelfMetricFamily};
+
+/// The most clients that are tracked at once. Past this, the client that pushed least recently is forgotten
+/// about to make room for a new one, so that e.g. lots of source IPs can't grow the map forever
+pub(crate) const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// What pushes are grouped by for the purposes of rate limiting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitKey {
+    /// The identity that the push was authenticated as
+    Identity,
+    /// The job label of the push, from the push URL
+    Job,
+    /// The IP address that the push came from
+    Ip,
+}
+
+impl FromStr for RateLimitKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "identity" => Ok(RateLimitKey::Identity),
+            "job" => Ok(RateLimitKey::Job),
+            "ip" => Ok(RateLimitKey::Ip),
+            _ => Err(anyhow!("Invalid rate limit key: {}", s))
+        }
+    }
+}
+
+/// A number of pushes allowed per duration, e.g. `100/1m`. Clients can burst up to the full
+/// number of pushes at once, and then get them back evenly over the duration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub pushes: f64,
+    pub per: Duration,
+}
+
+impl RateLimit {
+    fn pushes_per_second(&self) -> f64 {
+        self.pushes / self.per.as_secs_f64()
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pushes, per) = s.split_once('/').ok_or_else(|| anyhow!("Invalid rate limit `{}`: expected <pushes>/<duration>, e.g. 100/1m", s))?;
+        let pushes = match pushes.parse::<f64>() {
+            Ok(pushes) if pushes >= 1. && pushes.is_finite() => pushes,
+            _ => return Err(anyhow!("Invalid rate limit `{}`: the number of pushes must be at least 1", s))
+        };
+
+        let per = parse_duration(per).map_err(|e| anyhow!("Invalid rate limit `{}`: {}", s, e.to_string()))?;
+        Ok(RateLimit { pushes, per })
+    }
+}
+
+/// A token bucket for a single client
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+    /// The number of pushes that have been rejected. This lives with the bucket so that it's forgotten about
+    /// along with it, rather than keeping a series for every client that's ever been limited
+    limited: u64,
+    /// When the client last pushed, as a position in Buckets::by_last_push
+    last_push: u64,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.pushes_per_second()).min(self.limit.pushes);
+        self.last_refill = now;
+    }
+}
+
+/// The token buckets of every tracked client, along with the order they last pushed in, so that the least recently
+/// used one can be found without looking through all of them
+#[derive(Debug, Default)]
+struct Buckets {
+    by_client: HashMap<String, TokenBucket>,
+    /// Clients by the position of their last push, oldest first
+    by_last_push: BTreeMap<u64, String>,
+    pushes: u64,
+}
+
+impl Buckets {
+    /// Returns the bucket of the given client, making room for it if it's new, and marking it as just pushed
+    fn get(&mut self, client: &str, limit: RateLimit, now: Instant) -> &mut TokenBucket {
+        if !self.by_client.contains_key(client) && self.by_client.len() >= MAX_TRACKED_CLIENTS {
+            if let Some((_, oldest)) = self.by_last_push.pop_first() {
+                self.by_client.remove(&oldest);
+            }
+        }
+
+        self.pushes += 1;
+        let bucket = self.by_client.entry(client.to_owned()).or_insert(TokenBucket { limit, tokens: limit.pushes, last_refill: now, limited: 0, last_push: 0 });
+        self.by_last_push.remove(&bucket.last_push);
+        self.by_last_push.insert(self.pushes, client.to_owned());
+        bucket.last_push = self.pushes;
+        bucket
+    }
+}
+
+/// Limits how often each client can push, with a token bucket per client
+#[derive(Debug)]
+pub struct RateLimiter {
+    key: RateLimitKey,
+    /// The limit for clients that don't have one of their own
+    default_limit: Option<RateLimit>,
+    /// Limits for specific identities, which override the default
+    identity_limits: HashMap<String, RateLimit>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(key: RateLimitKey, default_limit: Option<RateLimit>, identity_limits: HashMap<String, RateLimit>) -> RateLimiter {
+        RateLimiter {
+            key,
+            default_limit,
+            identity_limits,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Takes a push from the given client, returning how long they should wait before trying again if
+    /// they've used up their limit
+    pub fn check(&self, identity: &str, job: &str, ip: &str) -> Result<(), Duration> {
+        self.check_at(identity, job, ip, Instant::now())
+    }
+
+    /// Like check, but as if it were the given time
+    pub(crate) fn check_at(&self, identity: &str, job: &str, ip: &str, now: Instant) -> Result<(), Duration> {
+        let limit = match self.identity_limits.get(identity).or(self.default_limit.as_ref()) {
+            Some(limit) => limit,
+            None => return Ok(())
+        };
+
+        let client = match self.key {
+            RateLimitKey::Identity => identity,
+            RateLimitKey::Job => job,
+            RateLimitKey::Ip => ip,
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get(client, *limit, now);
+        bucket.refill(now);
+        // The identity limits can differ from the default, so a client's limit can change if e.g. it's keyed by IP
+        bucket.limit = *limit;
+        bucket.tokens = bucket.tokens.min(limit.pushes);
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            return Ok(());
+        }
+
+        bucket.limited += 1;
+        Err(Duration::from_secs_f64((1. - bucket.tokens) / limit.pushes_per_second()))
+    }
+
+    /// Renders the number of rate limited pushes in the Prometheus text exposition format
+    pub fn self_metrics_string(&self) -> String {
+        let mut limited = SelfMetricFamily::new("gravel_rate_limited_pushes_total", "The number of pushes that have been rejected for going over their rate limit", PrometheusType::Counter, &["client"]);
+        let buckets = self.buckets.lock().unwrap();
+        let mut clients: Vec<_> = buckets.by_client.iter().filter(|(_, bucket)| bucket.limited > 0).collect();
+        clients.sort_by_key(|(client, _)| *client);
+        for (client, bucket) in clients {
+            limited.add_sample(&[client], MetricNumber::Int(bucket.limited as i64));
+        }
+
+        limited.to_string()
+    }
+
+    /// The number of clients whose buckets are being tracked
+    #[cfg(test)]
+    pub(crate) fn tracked_clients(&self) -> usize {
+        self.buckets.lock().unwrap().by_client.len()
+    }
+}
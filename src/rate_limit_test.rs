@@ -0,0 +1,83 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use crate::rate_limit::*;
+
+#[test]
+fn test_rate_limit_parsing() {
+    assert_eq!("100/1m".parse::<RateLimit>().unwrap(), RateLimit { pushes: 100., per: Duration::from_secs(60) });
+    assert!("100".parse::<RateLimit>().is_err(), "rate limits without a duration should be rejected");
+    assert!("0/1m".parse::<RateLimit>().is_err(), "rate limits that don't allow any pushes should be rejected");
+    assert!("100/1".parse::<RateLimit>().is_err(), "durations without a unit should be rejected");
+}
+
+#[test]
+fn test_token_bucket() {
+    let limit = RateLimit { pushes: 2., per: Duration::from_secs(10) };
+    let limiter = RateLimiter::new(RateLimitKey::Ip, Some(limit), HashMap::new());
+    let start = Instant::now();
+
+    // Clients can burst up to the whole limit, and then have to wait for a push to refill
+    assert!(limiter.check_at("", "", "10.0.0.1", start).is_ok());
+    assert!(limiter.check_at("", "", "10.0.0.1", start).is_ok());
+    assert_eq!(limiter.check_at("", "", "10.0.0.1", start), Err(Duration::from_secs(5)));
+    assert!(limiter.check_at("", "", "10.0.0.1", start + Duration::from_secs(5)).is_ok());
+
+    // Other clients have their own buckets
+    assert!(limiter.check_at("", "", "10.0.0.2", start).is_ok());
+
+    let self_metrics = limiter.self_metrics_string();
+    assert!(self_metrics.contains("gravel_rate_limited_pushes_total{client=\"10.0.0.1\"} 1\n"), "missing rate limited count in {}", self_metrics);
+}
+
+#[test]
+fn test_identity_rate_limits() {
+    let mut identity_limits = HashMap::new();
+    identity_limits.insert("batch".to_owned(), RateLimit { pushes: 1., per: Duration::from_secs(60) });
+    let limiter = RateLimiter::new(RateLimitKey::Identity, None, identity_limits);
+    let start = Instant::now();
+
+    // Only identities with a limit are limited, when there's no default
+    assert!(limiter.check_at("batch", "", "", start).is_ok());
+    assert!(limiter.check_at("batch", "", "", start).is_err());
+    for _ in 0..10 {
+        assert!(limiter.check_at("api", "", "", start).is_ok());
+    }
+}
+
+#[test]
+fn test_forgotten_clients() {
+    let limit = RateLimit { pushes: 1., per: Duration::from_secs(10) };
+    let limiter = RateLimiter::new(RateLimitKey::Ip, Some(limit), HashMap::new());
+    let start = Instant::now();
+    assert!(limiter.check_at("", "", "10.0.0.1", start).is_ok());
+    assert!(limiter.check_at("", "", "10.0.0.1", start).is_err());
+
+    // Once the map is full, the client that pushed least recently (and its rejected pushes) is forgotten about to
+    // make room for new ones, so that lots of clients can't grow the self metrics forever
+    for i in 0..MAX_TRACKED_CLIENTS {
+        assert!(limiter.check_at("", "", &format!("10.1.{}.{}", i / 256, i % 256), start).is_ok());
+    }
+
+    let self_metrics = limiter.self_metrics_string();
+    assert!(!self_metrics.contains("10.0.0.1"), "forgotten client still in {}", self_metrics);
+}
+
+#[test]
+fn test_tracked_clients_are_capped() {
+    // With a slow limit, no bucket refills while the flood is going on, but the map still can't grow past the cap
+    let limit = RateLimit { pushes: 1., per: Duration::from_secs(60 * 60) };
+    let limiter = RateLimiter::new(RateLimitKey::Ip, Some(limit), HashMap::new());
+    let start = Instant::now();
+    assert!(limiter.check_at("", "", "10.0.0.1", start).is_ok());
+    for i in 0..MAX_TRACKED_CLIENTS * 2 {
+        assert!(limiter.check_at("", "", &format!("10.1.{}.{}", i / 256, i % 256), start).is_ok());
+
+        // A client that keeps pushing is never the least recently used one, so it stays limited
+        if i % 1000 == 0 {
+            assert!(limiter.check_at("", "", "10.0.0.1", start).is_err());
+        }
+    }
+
+    assert_eq!(limiter.tracked_clients(), MAX_TRACKED_CLIENTS);
+    assert!(limiter.self_metrics_string().contains("10.0.0.1"));
+}
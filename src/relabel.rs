@@ -0,0 +1,251 @@
+use std::{collections::{BTreeMap, HashMap}, fs, path::Path};
+
+use anyhow::anyhow;
+use openmetrics_parser::Sample;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::aggregator::{AggregationError, CLEARMODE_LABEL_NAME, GravelMetricFamily, GravelValue, is_valid_label_name, is_valid_metric_name};
+
+/// The pseudo label that holds the name of the family a series is in, like in Prometheus
+const NAME_LABEL: &str = "__name__";
+
+/// What a relabel config does to the series that it's applied to. These are the same as in Prometheus
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelabelAction {
+    /// Sets the target label to the replacement, if the regex matches the source labels
+    Replace,
+    /// Drops series where the regex doesn't match the source labels
+    Keep,
+    /// Drops series where the regex matches the source labels
+    Drop,
+    /// Removes every label whose name matches the regex
+    LabelDrop,
+    /// Removes every label whose name doesn't match the regex
+    LabelKeep,
+    /// Copies every label whose name matches the regex to a label named by the replacement
+    LabelMap,
+    /// Sets the target label to a hash of the source labels, modulo the modulus
+    HashMod,
+}
+
+/// A relabel config, as it appears in the relabel config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRelabelConfig {
+    #[serde(default)]
+    source_labels: Vec<String>,
+    separator: Option<String>,
+    target_label: Option<String>,
+    regex: Option<String>,
+    modulus: Option<u64>,
+    replacement: Option<String>,
+    action: Option<RelabelAction>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RelabelConfigFile {
+    #[serde(default)]
+    relabel_configs: Vec<RawRelabelConfig>,
+}
+
+/// A single relabeling step, which is applied to the labels of every pushed series
+#[derive(Debug, Clone)]
+pub struct RelabelConfig {
+    source_labels: Vec<String>,
+    separator: String,
+    target_label: String,
+    /// The (fully anchored, like in Prometheus) regex
+    regex: Regex,
+    modulus: u64,
+    replacement: String,
+    action: RelabelAction,
+}
+
+impl RelabelConfig {
+    fn from_raw(raw: RawRelabelConfig) -> Result<RelabelConfig, anyhow::Error> {
+        let action = raw.action.unwrap_or(RelabelAction::Replace);
+        let regex = raw.regex.unwrap_or_else(|| String::from("(.*)"));
+        let config = RelabelConfig {
+            source_labels: raw.source_labels,
+            separator: raw.separator.unwrap_or_else(|| String::from(";")),
+            target_label: raw.target_label.unwrap_or_default(),
+            regex: Regex::new(&format!("^(?:{})$", regex)).map_err(|e| anyhow!("Invalid regex `{}`: {}", regex, e))?,
+            modulus: raw.modulus.unwrap_or(0),
+            replacement: raw.replacement.unwrap_or_else(|| String::from("$1")),
+            action,
+        };
+
+        match action {
+            RelabelAction::Replace | RelabelAction::HashMod if config.target_label.is_empty() => Err(anyhow!("{:?} relabel configs need a target_label", action)),
+            RelabelAction::HashMod if config.modulus == 0 => Err(anyhow!("HashMod relabel configs need a non zero modulus")),
+            RelabelAction::Keep | RelabelAction::Drop | RelabelAction::HashMod if config.source_labels.is_empty() => Err(anyhow!("{:?} relabel configs need source_labels", action)),
+            _ => Ok(config),
+        }
+    }
+
+    /// Applies this config to the given labels, returning false if the series should be dropped
+    fn apply(&self, labels: &mut BTreeMap<String, String>) -> bool {
+        let value = self.source_labels.iter().map(|name| labels.get(name).map(|value| value.as_str()).unwrap_or("")).collect::<Vec<&str>>().join(&self.separator);
+        match self.action {
+            RelabelAction::Keep => return self.regex.is_match(&value),
+            RelabelAction::Drop => return !self.regex.is_match(&value),
+            RelabelAction::Replace => {
+                if let Some(captures) = self.regex.captures(&value) {
+                    let mut target = String::new();
+                    captures.expand(&self.target_label, &mut target);
+                    let mut replacement = String::new();
+                    captures.expand(&self.replacement, &mut replacement);
+                    if !is_valid_label_name(&target) {
+                        return true;
+                    }
+
+                    // Like Prometheus, replacing a label with nothing removes it
+                    if replacement.is_empty() {
+                        labels.remove(&target);
+                    } else {
+                        labels.insert(target, replacement);
+                    }
+                }
+            },
+            RelabelAction::HashMod => {
+                // The same hash as Prometheus uses, so that the same series get sharded the same way
+                let hash = md5::compute(value.as_bytes());
+                let mut sum = [0; 8];
+                sum.copy_from_slice(&hash[8..]);
+                labels.insert(self.target_label.clone(), (u64::from_be_bytes(sum) % self.modulus).to_string());
+            },
+            RelabelAction::LabelDrop => labels.retain(|name, _| !self.regex.is_match(name)),
+            RelabelAction::LabelKeep => labels.retain(|name, _| self.regex.is_match(name)),
+            RelabelAction::LabelMap => {
+                let mapped: Vec<(String, String)> = labels.iter()
+                    .filter(|(name, _)| self.regex.is_match(name))
+                    .map(|(name, value)| (self.regex.replace(name, self.replacement.as_str()).into_owned(), value.clone()))
+                    .collect();
+                labels.extend(mapped);
+            },
+        }
+
+        true
+    }
+}
+
+/// Loads relabel configs from a YAML file, which has a `relabel_configs` list in the same format as Prometheus'
+pub fn load_relabel_configs<P: AsRef<Path>>(path: P) -> Result<Vec<RelabelConfig>, anyhow::Error> {
+    parse_relabel_configs(&fs::read_to_string(path)?)
+}
+
+pub fn parse_relabel_configs(s: &str) -> Result<Vec<RelabelConfig>, anyhow::Error> {
+    let file: RelabelConfigFile = serde_yaml::from_str(s)?;
+    file.relabel_configs.into_iter().enumerate()
+        .map(|(i, raw)| RelabelConfig::from_raw(raw).map_err(|e| anyhow!("Invalid relabel config {}: {}", i + 1, e)))
+        .collect()
+}
+
+/// Runs the given labels through every config in turn, returning None if the series is dropped
+pub fn relabel(mut labels: BTreeMap<String, String>, configs: &[RelabelConfig]) -> Option<BTreeMap<String, String>> {
+    for config in configs {
+        if !config.apply(&mut labels) {
+            return None;
+        }
+    }
+
+    Some(labels)
+}
+
+/// A family being rebuilt from relabeled series
+struct RelabeledFamily {
+    template: GravelMetricFamily,
+    samples: Vec<(BTreeMap<String, String>, Sample<GravelValue>)>,
+}
+
+/// Relabels every series in the given families. The family name is available to the configs as `__name__`,
+/// and changing it moves a series into the family with the new name. The clearmode label is left alone, and
+/// any other labels starting with `__` are removed once relabeling is done. Relabeling can build names out of
+/// label values, which could be anything, so the push is rejected if it ends up with an invalid name in it
+pub(crate) fn relabel_families(families: Vec<(String, GravelMetricFamily)>, configs: &[RelabelConfig]) -> Result<Vec<(String, GravelMetricFamily)>, AggregationError> {
+    if configs.is_empty() {
+        return Ok(families);
+    }
+
+    let mut relabeled: Vec<(String, RelabeledFamily)> = Vec::new();
+    let mut indexes: HashMap<String, usize> = HashMap::new();
+    for (name, family) in families {
+        let template = GravelMetricFamily::new(name.clone(), Vec::new(), family.family_type.clone(), family.help.clone(), family.unit.clone());
+        let is_empty = family.iter_samples().next().is_none();
+        let mut series = Vec::new();
+        for sample in family.into_iter_samples() {
+            let labels = sample.get_labelset()?.iter().map(|(name, value)| (name.clone(), value.clone())).collect();
+            series.push((labels, Some(sample)));
+        }
+
+        // Families that are just TYPE and HELP lines can still be dropped or renamed
+        if is_empty {
+            series.push((BTreeMap::new(), None));
+        }
+
+        for (mut labels, sample) in series {
+            let clear_mode = labels.remove(CLEARMODE_LABEL_NAME);
+            labels.insert(NAME_LABEL.to_owned(), name.clone());
+            let mut labels = match relabel(labels, configs) {
+                Some(labels) => labels,
+                None => continue,
+            };
+
+            let new_name = match labels.remove(NAME_LABEL) {
+                Some(new_name) if !new_name.is_empty() => new_name,
+                _ => continue,
+            };
+
+            if !is_valid_metric_name(&new_name) {
+                return Err(AggregationError::Error(format!("Relabeling gave {} an invalid metric name {:?}", name, new_name)));
+            }
+
+            // Empty labels are the same as missing ones
+            labels.retain(|name, value| !name.starts_with("__") && !value.is_empty());
+            if let Some(label_name) = labels.keys().find(|label_name| !is_valid_label_name(label_name)) {
+                return Err(AggregationError::Error(format!("Relabeling gave {} an invalid label name {:?}", new_name, label_name)));
+            }
+            if let Some(clear_mode) = clear_mode {
+                labels.insert(CLEARMODE_LABEL_NAME.to_owned(), clear_mode);
+            }
+
+            let index = match indexes.get(&new_name) {
+                Some(&index) => index,
+                None => {
+                    let template = GravelMetricFamily::new(new_name.clone(), Vec::new(), template.family_type.clone(), template.help.clone(), template.unit.clone());
+                    indexes.insert(new_name.clone(), relabeled.len());
+                    relabeled.push((new_name.clone(), RelabeledFamily { template, samples: Vec::new() }));
+                    relabeled.len() - 1
+                }
+            };
+
+            let family = &mut relabeled[index].1;
+            if family.template.family_type != template.family_type {
+                return Err(AggregationError::Error(format!("relabeling moved a {:?} into {}, which is a {:?}", template.family_type, new_name, family.template.family_type)));
+            }
+
+            if let Some(sample) = sample {
+                family.samples.push((labels, sample));
+            }
+        }
+    }
+
+    relabeled.into_iter().map(|(name, family)| {
+        let mut label_names: Vec<String> = family.samples.iter().flat_map(|(labels, _)| labels.keys().cloned()).collect();
+        label_names.sort();
+        label_names.dedup();
+
+        let template = family.template;
+        let mut new_family = GravelMetricFamily::new(name.clone(), label_names.clone(), template.family_type, template.help, template.unit);
+        for (labels, sample) in family.samples {
+            let label_values = label_names.iter().map(|label_name| labels.get(label_name).cloned().unwrap_or_default()).collect();
+            new_family.add_sample(Sample::new(label_values, sample.timestamp, sample.value))
+                .map_err(|_| AggregationError::Error(format!("relabeling gave two series in {} the same labels", name)))?;
+        }
+
+        Ok((name, new_family))
+    }).collect()
+}
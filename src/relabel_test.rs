@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{aggregator::{Aggregator, AggregatorConfig}, relabel::*};
+
+/// Relabels the given labels with the given YAML relabel configs
+fn relabel_with(configs: &str, labels: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+    let configs = parse_relabel_configs(configs).unwrap();
+    let labels: BTreeMap<String, String> = labels.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+    relabel(labels, &configs).map(|labels| labels.into_iter().collect())
+}
+
+fn labels(labels: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+    Some(labels.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect())
+}
+
+#[test]
+fn test_replace() {
+    let configs = "relabel_configs:
+- source_labels: [fn]
+  target_label: function
+- action: labeldrop
+  regex: fn
+";
+    assert_eq!(relabel_with(configs, &[("fn", "handler"), ("job", "api")]), labels(&[("function", "handler"), ("job", "api")]));
+
+    // Capture groups can be used in the replacement, and the source labels are joined by the separator
+    let configs = "relabel_configs:
+- source_labels: [a, b]
+  separator: /
+  regex: (.*)/v(.*)
+  target_label: version
+  replacement: ${1}_$2
+";
+    assert_eq!(relabel_with(configs, &[("a", "api"), ("b", "v2")]), labels(&[("a", "api"), ("b", "v2"), ("version", "api_2")]));
+    // The regex has to match the whole value
+    assert_eq!(relabel_with(configs, &[("a", "api"), ("b", "2")]), labels(&[("a", "api"), ("b", "2")]));
+
+    // Replacing a label with nothing removes it
+    let configs = "relabel_configs:
+- source_labels: [missing]
+  target_label: job
+";
+    assert_eq!(relabel_with(configs, &[("job", "api")]), labels(&[]));
+}
+
+#[test]
+fn test_keep_and_drop() {
+    let configs = "relabel_configs:
+- source_labels: [env]
+  regex: prod|staging
+  action: keep
+- source_labels: [__name__]
+  regex: debug_.*
+  action: drop
+";
+    assert_eq!(relabel_with(configs, &[("__name__", "requests"), ("env", "prod")]), labels(&[("__name__", "requests"), ("env", "prod")]));
+    assert_eq!(relabel_with(configs, &[("__name__", "requests"), ("env", "dev")]), None);
+    assert_eq!(relabel_with(configs, &[("__name__", "debug_requests"), ("env", "prod")]), None);
+}
+
+#[test]
+fn test_label_actions() {
+    let configs = "relabel_configs:
+- action: labelmap
+  regex: team_(.+)
+- action: labelkeep
+  regex: owner|on_call
+";
+    assert_eq!(relabel_with(configs, &[("team_owner", "a"), ("team_on_call", "b"), ("pod", "c")]), labels(&[("on_call", "b"), ("owner", "a")]));
+}
+
+#[test]
+fn test_hashmod() {
+    // The same value as Prometheus gives
+    let configs = "relabel_configs:
+- source_labels: [c]
+  modulus: 1000
+  target_label: shard
+  action: hashmod
+";
+    assert_eq!(relabel_with(configs, &[("c", "baz")]), labels(&[("c", "baz"), ("shard", "976")]));
+}
+
+#[test]
+fn test_invalid_configs() {
+    assert!(parse_relabel_configs("relabel_configs:\n- action: replace\n").is_err(), "replace needs a target label");
+    assert!(parse_relabel_configs("relabel_configs:\n- action: hashmod\n  source_labels: [a]\n  target_label: b\n").is_err(), "hashmod needs a modulus");
+    assert!(parse_relabel_configs("relabel_configs:\n- action: keep\n").is_err(), "keep needs source labels");
+    assert!(parse_relabel_configs("relabel_configs:\n- action: labeldrop\n  regex: (\n").is_err(), "invalid regexes should be rejected");
+    assert!(parse_relabel_configs("relabel_configs:\n- action: explode\n").is_err(), "unknown actions should be rejected");
+    assert!(parse_relabel_configs("relabel_configs:\n- action: labeldrop\n  regexp: a\n").is_err(), "unknown fields should be rejected");
+}
+
+#[tokio::test]
+async fn test_relabel_pushes() {
+    let config = AggregatorConfig { relabel_configs: parse_relabel_configs("relabel_configs:
+- source_labels: [fn]
+  target_label: function
+- action: labeldrop
+  regex: fn|request_id
+- source_labels: [__name__]
+  regex: debug_.*
+  action: drop
+- source_labels: [__name__]
+  regex: legacy_(.*)
+  target_label: __name__
+").unwrap(), ..Default::default() };
+
+    let mut agg = Aggregator::new_with_config(config);
+    agg.parse_and_merge("# TYPE legacy_invocations_total counter
+legacy_invocations_total{fn=\"a\",request_id=\"1\"} 1
+# TYPE invocations_total counter
+invocations_total{fn=\"b\",request_id=\"2\"} 2
+# TYPE debug_requests_total counter
+debug_requests_total 1
+", &HashMap::new()).await.unwrap();
+
+    // The clearmode label isn't touched by relabeling, and series that end up the same get merged
+    agg.parse_and_merge("# TYPE invocations_total counter
+invocations_total{fn=\"a\",request_id=\"3\",clearmode=\"replace\"} 5
+invocations_total{fn=\"b\",request_id=\"4\",clearmode=\"aggregate\"} 3
+", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_string().await, "# TYPE invocations_total counter
+invocations_total{function=\"a\"} 5
+invocations_total{function=\"b\"} 5
+");
+}
+
+#[tokio::test]
+async fn test_relabeling_to_invalid_names() {
+    // Names built out of label values could be anything, so they're checked once relabeling is done
+    let configs = ["relabel_configs:
+- source_labels: [fn]
+  target_label: __name__
+", "relabel_configs:
+- action: labelmap
+  regex: team_(.+)
+  replacement: team $1
+"];
+
+    for configs in configs.iter() {
+        let mut agg = Aggregator::new_with_config(AggregatorConfig { relabel_configs: parse_relabel_configs(configs).unwrap(), ..Default::default() });
+        let result = agg.parse_and_merge("invocations_total{fn=\"a\\\"} 1\\ninjected_total 1\\n\",team_owner=\"b\"} 1\n", &HashMap::new()).await;
+        assert!(result.unwrap_err().to_string().starts_with("Relabeling gave"), "{} should give an invalid name", configs);
+        assert_eq!(agg.to_string().await, "");
+    }
+}
@@ -1,10 +1,11 @@
-use std::{collections::HashMap, sync::Arc, convert::Infallible};
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
 
+use futures::{Stream, StreamExt};
 use reqwest::StatusCode;
 use urlencoding::decode;
-use warp::{Filter, http::HeaderValue, hyper::{HeaderMap, body::Bytes}, path::Tail, reject::Reject};
+use warp::{Buf, Filter, Reply, http::HeaderValue, hyper::{HeaderMap, body::Bytes}, path::Tail, reject::Reject};
 
-use crate::{aggregator::{AggregationError, Aggregator}, auth::Authenticator};
+use crate::{acl::{AclViolation, PushAcl}, aggregator::{AggregationError, Aggregator, AggregatorConfig, ExpositionFormat, ParsedPush, ScrapeQuery}, auth::Authenticator, compression::{DecompressionError, accepts_gzip, decompress}, rate_limit::RateLimiter, selector::{LabelMatcher, MatchOp, Selector}, protobuf::{PROTOBUF_CONTENT_TYPE, PROTOBUF_MEDIA_TYPE}, tenants::{Tenancy, TenantSource, is_valid_tenant_id}};
 
 #[cfg(feature="clustering")]
 use crate::clustering::ClusterConfig;
@@ -13,20 +14,47 @@ use crate::clustering::ClusterConfig;
 enum GravelError {
     Error(String),
     AuthError,
+    /// The request body was bigger than the given number of bytes
+    PayloadTooLarge(u64),
+    /// The request body was compressed with an encoding we don't support
+    UnsupportedEncoding(String),
+    /// The client has pushed too often, and can try again after the given time
+    RateLimited(Duration),
+    /// The push contained things that the client isn't allowed to push
+    Forbidden(AclViolation),
     AggregationError(AggregationError)
 }
 
 impl Reject for GravelError {}
 
+/// Who a push came from
+struct PushClient {
+    /// The identity that the push was authenticated as
+    identity: String,
+    /// The IP address that the push was sent from
+    ip: String,
+    /// The tenant that the push is for, or None for the default one
+    tenant: Option<String>,
+}
+
 pub struct RoutesConfig {
     pub authenticator: Box<dyn Authenticator + Send + Sync>,
+    /// The largest request body, in bytes, that will be accepted on a push
+    pub max_body_size: u64,
+    /// The largest that a push body can be once it's been decompressed, in bytes
+    pub max_decompressed_size: u64,
+    pub rate_limiter: Option<RateLimiter>,
+    /// Which families and grouping keys each identity is allowed to push
+    pub push_acl: Option<PushAcl>,
+    /// The tenants that pushes can be split between, if tenancy is enabled
+    pub tenancy: Option<Tenancy>,
     #[cfg(feature="clustering")]
     pub cluster_conf: Option<ClusterConfig>
 }
 
-async fn auth(config: Arc<RoutesConfig>, header: String) -> Result<(), warp::Rejection> {
-    if let Ok(true) = config.authenticator.authenticate(&header) {
-        return Ok(());
+async fn auth(config: Arc<RoutesConfig>, header: String) -> Result<String, warp::Rejection> {
+    if let Ok(Some(identity)) = config.authenticator.authenticate(&header) {
+        return Ok(identity);
     }
 
     return Err(warp::reject::custom(GravelError::AuthError));
@@ -40,37 +68,207 @@ pub fn get_routes(aggregator: Aggregator, config: RoutesConfig) -> impl Filter<E
     let config = Arc::new(config);
     let auth_config = Arc::clone(&config);
 
-    let auth = warp::header::<String>("authorization").or(default_auth).unify().and_then(move |header| auth(auth_config.clone(), header)).untuple_one();
+    let auth = warp::header::<String>("authorization").or(default_auth).unify().and_then(move |header| auth(auth_config.clone(), header));
 
-    let push_metrics_path = warp::path("metrics")
-        .and(warp::post().or(warp::put()))
+    // Anything can be prefixed with /tenants/<id> to pick a tenant, if tenancy is enabled
+    let tenant_prefix = warp::path("tenants").and(warp::path::param::<String>()).map(Some)
+        .or(warp::any().map(|| None))
+        .unify();
+
+    let push_metrics_path = tenant_prefix
+        .and(warp::path("metrics"))
+        .and(warp::post().or(warp::put()).unify())
         .and(auth)
-        .and(warp::filters::body::bytes())
+        .and(warp::addr::remote())
+        .and(warp::header::headers_cloned())
+        .and(with_config(Arc::clone(&config)))
+        .and_then(push_client)
         .and(warp::path::tail())
+        .and(with_config(Arc::clone(&config)))
+        .and_then(rate_limit_push)
+        .untuple_one()
+        .and(decompressed_body(config.max_body_size, config.max_decompressed_size))
+        .and(warp::header::optional::<String>("content-type"))
         .and(with_aggregator(aggregator.clone()))
         .and(with_config(Arc::clone(&config)))
         .and_then(ingest_metrics);
 
-    let mut get_metrics_headers = HeaderMap::new();
-    get_metrics_headers.insert("Content-Type", HeaderValue::from_static("text/plain; version=0.0.4"));
-
-    let get_metrics_path = warp::path!("metrics")
+    let get_metrics_path = tenant_prefix
+        .and(warp::path!("metrics"))
         .and(warp::get())
+        .and(warp::header::headers_cloned())
         .and(with_aggregator(aggregator.clone()))
-        .and_then(get_metrics)
-        .with(warp::reply::with::headers(get_metrics_headers));
+        .and(with_config(Arc::clone(&config)))
+        .and_then(scrape_aggregator)
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and_then(get_metrics);
 
-    return push_metrics_path.or(get_metrics_path).recover(handle_rejection);
+    let mut self_metrics_headers = HeaderMap::new();
+    self_metrics_headers.insert("Content-Type", HeaderValue::from_static("text/plain; version=0.0.4"));
+
+    let self_metrics_path = warp::path!("self-metrics")
+        .and(warp::get())
+        .and(with_aggregator(aggregator))
+        .and(with_config(Arc::clone(&config)))
+        .and_then(get_self_metrics)
+        .with(warp::reply::with::headers(self_metrics_headers));
+
+    return push_metrics_path.or(get_metrics_path).or(self_metrics_path).recover(handle_rejection);
 }
 
 async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, std::convert::Infallible> {
     let gravel_error: Option<&GravelError> = err.find();
-    match gravel_error {
-        Some(GravelError::AuthError) => Ok(warp::reply::with_status(String::from("FORBIDDEN"), StatusCode::FORBIDDEN)),
-        Some(GravelError::AggregationError(err)) => Ok(warp::reply::with_status(err.to_string(), StatusCode::BAD_REQUEST)),
-        Some(GravelError::Error(err)) => Ok(warp::reply::with_status(err.clone(), StatusCode::BAD_REQUEST)),
-        None => Ok(warp::reply::with_status(String::new(), StatusCode::NOT_FOUND)),
+    let reply = match gravel_error {
+        Some(GravelError::AuthError) => warp::reply::with_status(String::from("FORBIDDEN"), StatusCode::FORBIDDEN),
+        Some(GravelError::PayloadTooLarge(limit)) => warp::reply::with_status(format!("Request body is larger than the limit of {} bytes", limit), StatusCode::PAYLOAD_TOO_LARGE),
+        Some(GravelError::UnsupportedEncoding(encoding)) => warp::reply::with_status(format!("Unsupported Content-Encoding: {}", encoding), StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        Some(GravelError::RateLimited(retry_after)) => {
+            // Retry-After is in whole seconds, so round up to make sure that retrying then will succeed
+            let retry_after = retry_after.as_secs() + if retry_after.subsec_nanos() > 0 { 1 } else { 0 };
+            let reply = warp::reply::with_status(String::from("Too many pushes, slow down"), StatusCode::TOO_MANY_REQUESTS);
+            return Ok(warp::reply::with_header(reply, "Retry-After", retry_after.to_string()).into_response());
+        },
+        Some(GravelError::Forbidden(violation)) => warp::reply::with_status(violation.to_string(), StatusCode::FORBIDDEN),
+        Some(GravelError::AggregationError(err @ AggregationError::LimitExceeded(_))) => warp::reply::with_status(err.to_string(), StatusCode::UNPROCESSABLE_ENTITY),
+        Some(GravelError::AggregationError(err)) => warp::reply::with_status(err.to_string(), StatusCode::BAD_REQUEST),
+        Some(GravelError::Error(err)) => warp::reply::with_status(err.clone(), StatusCode::BAD_REQUEST),
+        None => warp::reply::with_status(String::new(), StatusCode::NOT_FOUND),
+    };
+
+    Ok(reply.into_response())
+}
+
+/// Checks that a tenant ID from a request is valid
+fn valid_tenant(tenant: String) -> Result<String, warp::Rejection> {
+    if !is_valid_tenant_id(&tenant) {
+        return Err(warp::reject::custom(GravelError::Error(format!("Invalid tenant: {}", tenant))));
+    }
+
+    Ok(tenant)
+}
+
+/// Reads the given header as a tenant ID, if it's set
+fn header_tenant(headers: &HeaderMap, name: &str) -> Result<Option<String>, warp::Rejection> {
+    match headers.get(name).map(|value| value.to_str()) {
+        Some(Ok(tenant)) => valid_tenant(tenant.to_owned()).map(Some),
+        Some(Err(_)) => Err(warp::reject::custom(GravelError::Error(format!("Invalid {} header", name)))),
+        None => Ok(None),
+    }
+}
+
+/// Works out who a push is from, and which tenant it's for. Pushes without a tenant go to the default one
+async fn push_client(path_tenant: Option<String>, identity: String, remote: Option<SocketAddr>, headers: HeaderMap, conf: Arc<RoutesConfig>) -> Result<PushClient, warp::Rejection> {
+    let tenant = match (conf.tenancy.as_ref().map(|tenancy| &tenancy.source), path_tenant) {
+        (Some(TenantSource::Path), tenant) => tenant.map(valid_tenant).transpose()?,
+        // Otherwise, pushes could pick someone else's tenant with the path
+        (_, Some(_)) => return Err(warp::reject::not_found()),
+        (Some(TenantSource::Header(name)), None) => header_tenant(&headers, name)?,
+        (Some(TenantSource::Identity), None) if identity.is_empty() => None,
+        (Some(TenantSource::Identity), None) => Some(valid_tenant(identity.clone())?),
+        (None, None) => None,
+    };
+
+    // Like scrapes, only clients authenticated as a tenant can push to it (which is always true when tenants come from the identity)
+    if let (Some(tenancy), Some(tenant)) = (conf.tenancy.as_ref(), tenant.as_ref()) {
+        if !tenancy.public_pushes && identity != *tenant {
+            return Err(warp::reject::custom(GravelError::AuthError));
+        }
+    }
+
+    Ok(PushClient { identity, ip: remote.map(|addr| addr.ip().to_string()).unwrap_or_default(), tenant })
+}
+
+/// Picks the aggregator that a scrape is for. Tenants are scraped with the /tenants/<id> prefix, or with the tenant
+/// header if that's where tenants come from, and only by clients that authenticate as that tenant (unless scrapes are public)
+async fn scrape_aggregator(path_tenant: Option<String>, headers: HeaderMap, agg: Aggregator, conf: Arc<RoutesConfig>) -> Result<Aggregator, warp::Rejection> {
+    let tenancy = match conf.tenancy.as_ref() {
+        Some(tenancy) => tenancy,
+        None if path_tenant.is_some() => return Err(warp::reject::not_found()),
+        None => return Ok(agg),
+    };
+
+    let tenant = match (&tenancy.source, path_tenant) {
+        (_, Some(tenant)) => Some(valid_tenant(tenant)?),
+        (TenantSource::Header(name), None) => header_tenant(&headers, name)?,
+        (_, None) => None,
+    };
+
+    let tenant = match tenant {
+        Some(tenant) => tenant,
+        None => return Ok(agg),
+    };
+
+    if !tenancy.public_scrapes {
+        let authorization = headers.get("authorization").and_then(|value| value.to_str().ok()).unwrap_or("");
+        match conf.authenticator.authenticate(authorization) {
+            Ok(Some(identity)) if identity == tenant => {},
+            _ => return Err(warp::reject::custom(GravelError::AuthError)),
+        }
     }
+
+    // Tenants that haven't pushed anything yet don't have any metrics
+    Ok(tenancy.get(&tenant).unwrap_or_else(|| Aggregator::new_with_config(AggregatorConfig::default())))
+}
+
+/// Buffers the request body, rejecting it as soon as it's bigger than the given number of bytes. Unlike warp's
+/// content_length_limit, this also works for chunked bodies that don't say how big they are up front
+fn body_with_limit(limit: u64) -> impl Filter<Extract = (Bytes,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::body::stream())
+        .and_then(move |content_length, body| read_body(content_length, body, limit))
+}
+
+async fn read_body<S, B>(content_length: Option<u64>, body: S, limit: u64) -> Result<Bytes, warp::Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    if content_length.is_some_and(|length| length > limit) {
+        return Err(warp::reject::custom(GravelError::PayloadTooLarge(limit)));
+    }
+
+    futures::pin_mut!(body);
+    let mut data = Vec::with_capacity(content_length.unwrap_or(0) as usize);
+    while let Some(chunk) = body.next().await {
+        let mut chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => return Err(warp::reject::custom(GravelError::Error(e.to_string())))
+        };
+
+        if (data.len() + chunk.remaining()) as u64 > limit {
+            return Err(warp::reject::custom(GravelError::PayloadTooLarge(limit)));
+        }
+
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            let len = bytes.len();
+            data.extend_from_slice(bytes);
+            chunk.advance(len);
+        }
+    }
+
+    Ok(Bytes::from(data))
+}
+
+/// Buffers the request body like body_with_limit, and then decodes it according to its Content-Encoding
+fn decompressed_body(max_body_size: u64, max_decompressed_size: u64) -> impl Filter<Extract = (Bytes,), Error = warp::Rejection> + Clone {
+    body_with_limit(max_body_size)
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and_then(move |data: Bytes, content_encoding: Option<String>| async move {
+            let content_encoding = match content_encoding {
+                Some(content_encoding) => content_encoding,
+                None => return Ok(data),
+            };
+
+            match decompress(data.to_vec(), &content_encoding, max_decompressed_size) {
+                Ok(data) => Ok(Bytes::from(data)),
+                Err(DecompressionError::UnsupportedEncoding(encoding)) => Err(warp::reject::custom(GravelError::UnsupportedEncoding(encoding))),
+                Err(DecompressionError::TooLarge(limit)) => Err(warp::reject::custom(GravelError::PayloadTooLarge(limit))),
+                Err(e) => Err(warp::reject::custom(GravelError::Error(e.to_string()))),
+            }
+        })
 }
 
 fn with_aggregator(
@@ -86,9 +284,14 @@ fn with_config(
 }
 
 #[cfg(feature="clustering")]
-async fn forward_to_peer(peer: &str, data: Bytes, url_tail: Tail) -> Result<(), GravelError> {
+async fn forward_to_peer(peer: &str, data: Bytes, content_type: Option<String>, url_tail: Tail) -> Result<(), GravelError> {
     let client = reqwest::Client::new();
-    return match client.post(peer.to_owned() + "/" + url_tail.as_str()).body(data).send().await {
+    let mut request = client.post(peer.to_owned() + "/" + url_tail.as_str()).body(data);
+    if let Some(content_type) = content_type {
+        request = request.header("Content-Type", content_type);
+    }
+
+    return match request.send().await {
         Ok(o) => {
             if o.status().is_success() {
                 return Ok(());
@@ -100,47 +303,85 @@ async fn forward_to_peer(peer: &str, data: Bytes, url_tail: Tail) -> Result<(),
     }
 }
 
-/// The routes for POST /metrics requests - takes a Prometheus exposition format
-/// and merges it into the existing metrics. Also supports push gateway syntax - /metrics/job/foo
+/// The routes for POST /metrics requests - takes a Prometheus exposition format (text, or protobuf if the
+/// Content-Type says so) and merges it into the existing metrics. Also supports push gateway syntax - /metrics/job/foo
 /// adds a job="foo" label to all the metrics
-async fn ingest_metrics<T>(
-    _method: T,
-    data: Bytes,
+/// Parses the grouping labels of a push from the rest of its URL, e.g. /job/foo/instance/bar
+fn parse_grouping_labels(url_tail: &str) -> Result<HashMap<String, String>, GravelError> {
+    let mut labelset = HashMap::new();
+    let mut labels = url_tail.split("/").map(|s| decode(s)).peekable();
+    while labels.peek().is_some() {
+        let label_name = labels.next().unwrap();
+        let name = match label_name {
+            Ok(s) => s.into_owned(),
+            Err(_) => return Err(GravelError::Error("Invalid label name".into()))
+        };
+
+        if name.is_empty() {
+            break;
+        }
+
+        let value = match labels.next() {
+            Some(Ok(s)) => s.into_owned(),
+            Some(Err(_)) => return Err(GravelError::Error("Invalid label value".into())),
+            None => return Err(GravelError::Error("Label value missing".into()))
+        };
+
+        labelset.insert(name, value);
+    }
+
+    Ok(labelset)
+}
+
+/// Reads the grouping labels of a push, and checks it against the rate limit. This happens before the body is
+/// read, so that clients that are over their limit don't cost us a download and a decompression
+async fn rate_limit_push(client: PushClient, url_tail: Tail, conf: Arc<RoutesConfig>) -> Result<(PushClient, Tail, HashMap<String, String>), warp::Rejection> {
+    let labels = parse_grouping_labels(url_tail.as_str()).map_err(warp::reject::custom)?;
+    if let Some(rate_limiter) = conf.rate_limiter.as_ref() {
+        let job = labels.get("job").map(|s| s.as_str()).unwrap_or("");
+        if let Err(retry_after) = rate_limiter.check(&client.identity, job, &client.ip) {
+            return Err(warp::reject::custom(GravelError::RateLimited(retry_after)));
+        }
+    }
+
+    Ok((client, url_tail, labels))
+}
+
+async fn ingest_metrics(
+    client: PushClient,
     url_tail: Tail,
+    labels: HashMap<String, String>,
+    data: Bytes,
+    content_type: Option<String>,
     mut agg: Aggregator,
     conf: Arc<RoutesConfig>
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let labels = {
-        let mut labelset = HashMap::new();
-        let mut labels = url_tail.as_str().split("/").map(|s| decode(s)).peekable();
-        while labels.peek().is_some() {
-            let label_name = labels.next().unwrap();
-            let name = match label_name {
-                Ok(s) => s.into_owned(),
-                Err(_) => return Err(warp::reject::custom(GravelError::Error("Invalid label name".into())))
-            };
-
-            if name.is_empty() {
-                break;
-            }
-
-            let value = match labels.next() {
-                Some(Ok(s)) => s.into_owned(),
-                Some(Err(_)) => return Err(warp::reject::custom(GravelError::Error("Invalid label value".into()))),
-                None => return Err(warp::reject::custom(GravelError::Error("Label value missing".into())))
-            };
+    let mut str_labels = HashMap::new();
+    for (k, v) in labels.iter() {
+        str_labels.insert(k.as_str(), v.as_str());
+    }
 
-            labelset.insert(name, value);
-        }
-        labelset
+    // The per push limits are checked as the push is parsed, so it needs the config of the aggregator it's going to
+    let config = match (conf.tenancy.as_ref(), client.tenant.as_ref()) {
+        (Some(tenancy), Some(tenant)) => tenancy.config_for(tenant),
+        _ => agg.config(),
     };
 
+    // Pushes are checked before they're forwarded, as the peer doesn't know who the client authenticated as
+    let mut push = None;
+    if let Some(acl) = conf.push_acl.as_ref() {
+        acl.check_labels(&client.identity, &labels).map_err(|violation| warp::reject::custom(GravelError::Forbidden(violation)))?;
+        let parsed = parse_push(&data, content_type.as_deref(), &str_labels, config).map_err(warp::reject::custom)?;
+        acl.check_families(&client.identity, parsed.family_names()).map_err(|violation| warp::reject::custom(GravelError::Forbidden(violation)))?;
+        push = Some(parsed);
+    }
+
     // We're clustering, so might need to forward the metrics
     if let Some(cluster_conf) = conf.cluster_conf.as_ref() {
-        let job = labels.get("job").map(|s| s.to_owned()).unwrap_or(String::new());
+        let job = labels.get("job").cloned().unwrap_or_default();
         if let Some(peer) = cluster_conf.get_peer_for_key(&job) {
             if !cluster_conf.is_self(peer) {
-                match forward_to_peer(peer, data, url_tail).await {
+                match forward_to_peer(peer, data, content_type, url_tail).await {
                     Ok(_) => return Ok(""),
                     Err(e) => return Err(warp::reject::custom(e))
                 }
@@ -148,24 +389,83 @@ async fn ingest_metrics<T>(
         }
     }
 
-    let body = match String::from_utf8(data.to_vec()) {
-        Ok(s) => s,
-        Err(_) => {
-            return Err(warp::reject::custom(GravelError::Error("Invalid UTF-8 in body".into())));
-        }
+    let push = match push {
+        Some(push) => push,
+        None => parse_push(&data, content_type.as_deref(), &str_labels, config).map_err(warp::reject::custom)?,
     };
 
-    let mut str_labels = HashMap::new();
-    for (k, v) in labels.iter() {
-        str_labels.insert(k.as_str(), v.as_str());
+    if let (Some(tenancy), Some(tenant)) = (conf.tenancy.as_ref(), client.tenant.as_ref()) {
+        agg = tenancy.get_or_create(tenant).map_err(|e| warp::reject::custom(GravelError::AggregationError(e)))?;
     }
 
-    match agg.parse_and_merge(&body, &str_labels).await {
+    match agg.merge(push).await {
         Ok(_) => Ok(""),
         Err(e) => Err(warp::reject::custom(GravelError::AggregationError(e))),
     }
 }
 
-async fn get_metrics(agg: Aggregator) -> Result<impl warp::Reply, warp::Rejection> {
-    Ok(agg.to_string().await)
-}
\ No newline at end of file
+/// Parses a push body, as protobuf if the Content-Type says it is, or the text format otherwise
+fn parse_push(data: &[u8], content_type: Option<&str>, labels: &HashMap<&str, &str>, config: &AggregatorConfig) -> Result<ParsedPush, GravelError> {
+    if content_type.is_some_and(|content_type| content_type.starts_with(PROTOBUF_MEDIA_TYPE)) {
+        return ParsedPush::parse_protobuf(data, labels, config).map_err(GravelError::AggregationError);
+    }
+
+    let body = std::str::from_utf8(data).map_err(|_| GravelError::Error("Invalid UTF-8 in body".into()))?;
+    ParsedPush::parse(body, labels, config).map_err(GravelError::AggregationError)
+}
+
+/// Parses the selectors out of a scrape's query string - every `match[]` parameter is a series selector, and
+/// `job=foo` is shorthand for `match[]={job="foo"}`
+fn parse_scrape_query(query: &str) -> Result<ScrapeQuery, GravelError> {
+    let mut scrape_query = ScrapeQuery::default();
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        // Query strings encode spaces as +, which urlencoding doesn't know about
+        let decode_param = |s: &str| decode(&s.replace('+', " ")).map(|s| s.into_owned()).map_err(|_| GravelError::Error(format!("Invalid query parameter: {}", param)));
+        match decode_param(name)?.as_str() {
+            "match[]" => scrape_query.selectors.push(decode_param(value)?.parse().map_err(|e: anyhow::Error| GravelError::Error(e.to_string()))?),
+            "job" => scrape_query.selectors.push(Selector { matchers: vec![LabelMatcher::new("job", MatchOp::Equal, &decode_param(value)?).map_err(|e| GravelError::Error(e.to_string()))?] }),
+            "without" => scrape_query.without.extend(decode_param(value)?.split(',').map(|label| label.trim().to_owned()).filter(|label| !label.is_empty())),
+            _ => {}
+        }
+    }
+
+    Ok(scrape_query)
+}
+
+/// The route for GET /metrics requests - renders the text format, unless the scraper says that it accepts
+/// protobuf, which is needed to scrape native histograms. Responses are gzipped if the scraper accepts that,
+/// can be filtered down to the series matching `match[]` selectors, like Prometheus' /federate, and can have
+/// labels aggregated away with `without`
+async fn get_metrics(agg: Aggregator, query: String, accept: Option<String>, accept_encoding: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let scrape_query = parse_scrape_query(&query).map_err(warp::reject::custom)?;
+    let (format, content_type) = match accept {
+        Some(accept) if accept.contains(PROTOBUF_MEDIA_TYPE) => (ExpositionFormat::Protobuf, PROTOBUF_CONTENT_TYPE),
+        _ => (ExpositionFormat::Text, "text/plain; version=0.0.4"),
+    };
+
+    let gzip = accept_encoding.is_some_and(|accept_encoding| accepts_gzip(&accept_encoding));
+    let mut response = warp::reply::Response::new(agg.render_query(format, gzip, &scrape_query).await.into());
+    let headers = response.headers_mut();
+    headers.insert("Content-Type", HeaderValue::from_static(content_type));
+    headers.insert("Vary", HeaderValue::from_static("Accept, Accept-Encoding"));
+    if gzip {
+        headers.insert("Content-Encoding", HeaderValue::from_static("gzip"));
+    }
+
+    Ok(response)
+}
+
+async fn get_self_metrics(agg: Aggregator, conf: Arc<RoutesConfig>) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut self_metrics = agg.self_metrics_string().await;
+    if let Some(tenancy) = conf.tenancy.as_ref() {
+        // The default tenant's metrics are the ones above, as it can be scraped by anyone anyway
+        self_metrics.push_str(&tenancy.self_metrics_string().await);
+    }
+
+    if let Some(rate_limiter) = conf.rate_limiter.as_ref() {
+        self_metrics.push_str(&rate_limiter.self_metrics_string());
+    }
+
+    Ok(self_metrics)
+}
@@ -1,18 +1,28 @@
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
 
-use crate::{routes::{self, RoutesConfig}, aggregator::Aggregator, auth::pass_through_auth};
+use crate::{routes::{self, RoutesConfig}, acl::parse_push_acl, aggregator::{Aggregator, AggregatorConfig}, auth::{Authenticator, pass_through_auth}, tenants::{Tenancy, TenantSource}, rate_limit::{RateLimit, RateLimitKey, RateLimiter}};
 use tokio::time::sleep;
 
+/// A config without auth, limits, or tenants, for tests to override the parts that they care about
+fn test_config() -> RoutesConfig {
+    RoutesConfig {
+        authenticator: Box::new(pass_through_auth()),
+        max_body_size: 1024 * 1024,
+        max_decompressed_size: 1024 * 1024,
+        rate_limiter: None,
+        push_acl: None,
+        tenancy: None,
+        #[cfg(feature="clustering")]
+        cluster_conf: None
+    }
+}
+
 #[tokio::test]
 async fn test_27() {
     // https://github.com/sinkingpoint/prometheus-gravel-gateway/issues/27
 
     let agg = Aggregator::new();
-    let config = RoutesConfig{
-        authenticator: Box::new(pass_through_auth()),
-        #[cfg(feature="clustering")]
-        cluster_conf: None
-    };
+    let config = test_config();
 
     let routes = routes::get_routes(agg, config);
     let server = warp::serve(routes);
@@ -39,4 +49,290 @@ async fn test_27() {
     assert_eq!(res.text().await.unwrap(), "test_metric{job=\"localhost:80\"} 3\n");
 
     server.abort();
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_invalid_clearmode_response() {
+    let agg = Aggregator::new();
+    let config = test_config();
+
+    let routes = routes::get_routes(agg, config);
+    let server = warp::serve(routes);
+    let server = tokio::spawn(server.run(SocketAddr::V4("127.0.0.1:4279".parse().unwrap())));
+
+    // wait a bit for the server to come up.
+    sleep(tokio::time::Duration::from_millis(500)).await;
+
+    let client = reqwest::Client::new();
+    let res = client.post("http://127.0.0.1:4279/metrics").body("test_metric{clearmode=\"mean1h30\"} 1
+").send().await.unwrap();
+    assert_eq!(res.status(), 400);
+    assert_eq!(res.text().await.unwrap(), "Invalid duration `1h30`: missing unit after 30");
+
+    // A granularity that would allocate more buckets than the limit for every series is rejected the same way
+    let res = client.post("http://127.0.0.1:4279/metrics").body("test_metric{clearmode=\"mean1h@1000000000\"} 1
+").send().await.unwrap();
+    assert_eq!(res.status(), 400);
+    assert_eq!(res.text().await.unwrap(), "Invalid pebble granularity: 1000000000 is more than the limit of 1000");
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_body_size_limit() {
+    let agg = Aggregator::new();
+    let config = RoutesConfig {
+        max_body_size: 32,
+        ..test_config()
+    };
+
+    let routes = routes::get_routes(agg, config);
+    let server = warp::serve(routes);
+    let server = tokio::spawn(server.run(SocketAddr::V4("127.0.0.1:4280".parse().unwrap())));
+
+    // wait a bit for the server to come up.
+    sleep(tokio::time::Duration::from_millis(500)).await;
+
+    let client = reqwest::Client::new();
+    let res = client.post("http://127.0.0.1:4280/metrics").body("test_metric 1\n").send().await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let res = client.post("http://127.0.0.1:4280/metrics").body("test_metric{path=\"/a/very/long/path\"} 1\n").send().await.unwrap();
+    assert_eq!(res.status(), 413);
+
+    server.abort();
+
+    // Without a Content-Length (e.g. a chunked body) the limit has to be enforced as the body is read
+    let routes = routes::get_routes(Aggregator::new(), RoutesConfig {
+        max_body_size: 32,
+        ..test_config()
+    });
+    let res = warp::test::request().method("POST").path("/metrics").body("test_metric{path=\"/a/very/long/path\"} 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 413);
+}
+
+#[tokio::test]
+async fn test_rate_limit_response() {
+    let limit = RateLimit { pushes: 1., per: Duration::from_secs(60) };
+    let routes = routes::get_routes(Aggregator::new(), RoutesConfig {
+        rate_limiter: Some(RateLimiter::new(RateLimitKey::Job, Some(limit), HashMap::new())),
+        ..test_config()
+    });
+
+    let res = warp::test::request().method("POST").path("/metrics/job/a").body("test_metric 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 200);
+
+    let res = warp::test::request().method("POST").path("/metrics/job/a").body("test_metric 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 429);
+    assert_eq!(res.headers()["Retry-After"], "60");
+
+    // Clients over their limit are turned away before their body is read, so it doesn't matter that this isn't gzipped
+    let res = warp::test::request().method("POST").path("/metrics/job/a").header("content-encoding", "gzip").body("not gzip").reply(&routes).await;
+    assert_eq!(res.status(), 429);
+
+    // Limits are per job, so other jobs can still push
+    let res = warp::test::request().method("POST").path("/metrics/job/b").body("test_metric 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 200);
+
+    let res = warp::test::request().method("GET").path("/self-metrics").reply(&routes).await;
+    assert!(String::from_utf8_lossy(res.body()).contains("gravel_rate_limited_pushes_total{client=\"a\"} 2\n"));
+}
+
+#[tokio::test]
+async fn test_compressed_push() {
+    use std::io::Write;
+    use flate2::{Compression, write::GzEncoder};
+
+    let routes = routes::get_routes(Aggregator::new(), test_config());
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(b"test_metric 1\n").unwrap();
+    let res = warp::test::request().method("POST").path("/metrics").header("Content-Encoding", "gzip").body(encoder.finish().unwrap()).reply(&routes).await;
+    assert_eq!(res.status(), 200);
+
+    let res = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert_eq!(res.body(), "test_metric 1\n");
+
+    let res = warp::test::request().method("POST").path("/metrics").header("Content-Encoding", "br").body("test_metric 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 415);
+}
+
+#[tokio::test]
+async fn test_gzipped_scrape() {
+    let routes = routes::get_routes(Aggregator::new(), test_config());
+
+    let res = warp::test::request().method("POST").path("/metrics").body("test_metric 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 200);
+
+    let res = warp::test::request().method("GET").path("/metrics").header("Accept-Encoding", "gzip").reply(&routes).await;
+    assert_eq!(res.headers()["Content-Encoding"], "gzip");
+    assert_eq!(crate::compression::decompress(res.body().to_vec(), "gzip", 1024).unwrap(), b"test_metric 1\n");
+
+    let res = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert!(res.headers().get("Content-Encoding").is_none());
+    assert_eq!(res.body(), "test_metric 1\n");
+}
+
+#[tokio::test]
+async fn test_scrape_selectors() {
+    let routes = routes::get_routes(Aggregator::new(), test_config());
+
+    for (job, body) in [("api", "# TYPE requests_total counter\nrequests_total 1\n# TYPE errors_total counter\nerrors_total 2\n"), ("web", "# TYPE requests_total counter\nrequests_total 3\n")] {
+        let res = warp::test::request().method("POST").path(&format!("/metrics/job/{}", job)).body(body).reply(&routes).await;
+        assert_eq!(res.status(), 200);
+    }
+
+    let res = warp::test::request().method("GET").path("/metrics?job=web").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE requests_total counter\nrequests_total{job=\"web\"} 3\n");
+
+    // Multiple selectors select the union of what they match
+    let res = warp::test::request().method("GET").path("/metrics?match[]=errors_total&match%5B%5D=%7Bjob%3D%22web%22%7D").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE errors_total counter\nerrors_total{job=\"api\"} 2\n# TYPE requests_total counter\nrequests_total{job=\"web\"} 3\n");
+
+    let res = warp::test::request().method("GET").path("/metrics?match[]=%7Bjob%3D~%22api%22%7D").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE errors_total counter\nerrors_total{job=\"api\"} 2\n# TYPE requests_total counter\nrequests_total{job=\"api\"} 1\n");
+
+    let res = warp::test::request().method("GET").path("/metrics?match[]=%7B").reply(&routes).await;
+    assert_eq!(res.status(), 400);
+}
+
+#[tokio::test]
+async fn test_scrape_without() {
+    let routes = routes::get_routes(Aggregator::new(), test_config());
+
+    for instance in ["a", "b"] {
+        let res = warp::test::request().method("POST").path(&format!("/metrics/job/api/instance/{}", instance)).body("# TYPE requests_total counter\nrequests_total 2\n").reply(&routes).await;
+        assert_eq!(res.status(), 200);
+    }
+
+    let res = warp::test::request().method("GET").path("/metrics?without=instance").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE requests_total counter\nrequests_total{job=\"api\"} 4\n");
+
+    // Labels can be given as a comma separated list, and combined with selectors
+    let res = warp::test::request().method("GET").path("/metrics?job=api&without=instance,job").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE requests_total counter\nrequests_total 4\n");
+
+    let res = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE requests_total counter\nrequests_total{instance=\"a\",job=\"api\"} 2\nrequests_total{instance=\"b\",job=\"api\"} 2\n");
+}
+
+#[tokio::test]
+async fn test_push_acl() {
+    let routes = routes::get_routes(Aggregator::new(), RoutesConfig {
+        push_acl: Some(parse_push_acl("global:\n  allow_families: [team_a_.*]\n  labels:\n    job: team-a-.*\n").unwrap()),
+        ..test_config()
+    });
+
+    let res = warp::test::request().method("POST").path("/metrics/job/team-a-api").body("# TYPE team_a_requests_total counter\nteam_a_requests_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 200);
+
+    let res = warp::test::request().method("POST").path("/metrics/job/team-a-api").body("# TYPE team_b_requests_total counter\nteam_b_requests_total 1\n# TYPE team_b_errors_total counter\nteam_b_errors_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 403);
+    assert_eq!(res.body(), "Push not allowed - disallowed families: team_b_errors_total, team_b_requests_total");
+
+    let res = warp::test::request().method("POST").path("/metrics/job/team-b-api").body("# TYPE team_a_requests_total counter\nteam_a_requests_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 403);
+    assert_eq!(res.body(), "Push not allowed - disallowed grouping labels: job=team-b-api");
+
+    // Nothing from the rejected pushes was merged
+    let res = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE team_a_requests_total counter\nteam_a_requests_total{job=\"team-a-api\"} 1\n");
+}
+
+/// Authenticates everyone, as whatever is in their Authorization header
+struct HeaderIdentityAuthenticator {}
+
+impl Authenticator for HeaderIdentityAuthenticator {
+    fn authenticate(&self, header: &str) -> Result<Option<String>, anyhow::Error> {
+        Ok(Some(header.to_owned()))
+    }
+}
+
+fn tenanted_routes(source: TenantSource, public: bool) -> impl warp::Filter<Extract = impl warp::Reply, Error = std::convert::Infallible> + Clone {
+    routes::get_routes(Aggregator::new(), RoutesConfig {
+        authenticator: Box::new(HeaderIdentityAuthenticator {}),
+        tenancy: Some(Tenancy::new(source, AggregatorConfig::default(), 10).with_public_scrapes(public).with_public_pushes(public)),
+        ..test_config()
+    })
+}
+
+#[tokio::test]
+async fn test_path_tenants() {
+    let routes = tenanted_routes(TenantSource::Path, true);
+    for (path, value) in [("/tenants/a/metrics/job/api", 1), ("/tenants/b/metrics/job/api", 2), ("/metrics/job/api", 3)] {
+        let res = warp::test::request().method("POST").path(path).body(format!("# TYPE requests_total counter\nrequests_total {}\n", value)).reply(&routes).await;
+        assert_eq!(res.status(), 200);
+    }
+
+    let res = warp::test::request().method("GET").path("/tenants/a/metrics").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE requests_total counter\nrequests_total{job=\"api\"} 1\n");
+    let res = warp::test::request().method("GET").path("/tenants/b/metrics?without=job").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE requests_total counter\nrequests_total 2\n");
+    // Pushes without a tenant go to the default one
+    let res = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE requests_total counter\nrequests_total{job=\"api\"} 3\n");
+    // Tenants that haven't pushed yet are empty
+    let res = warp::test::request().method("GET").path("/tenants/c/metrics").reply(&routes).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.body(), "");
+
+    let res = warp::test::request().method("POST").path("/tenants/a%2Fb/metrics").body("# TYPE requests_total counter\nrequests_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 400);
+
+    // Self metrics cover the default tenant in full, and only the totals of every other tenant
+    let res = warp::test::request().method("POST").path("/tenants/a/metrics/job/secret-job").body("# TYPE secret_total counter\nsecret_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 200);
+    let res = warp::test::request().method("GET").path("/self-metrics").reply(&routes).await;
+    let self_metrics = String::from_utf8_lossy(res.body());
+    assert!(self_metrics.contains("gravel_tenants 2\n"));
+    assert!(self_metrics.contains("gravel_series{family=\"requests_total\"} 1\n"), "missing default tenant series count in {}", self_metrics);
+    assert!(self_metrics.contains("gravel_tenant_series{tenant=\"a\"} 2\n"), "missing series count for tenant a in {}", self_metrics);
+    assert!(self_metrics.contains("gravel_tenant_series{tenant=\"b\"} 1\n"), "missing series count for tenant b in {}", self_metrics);
+    assert!(!self_metrics.contains("secret"), "tenant family names or label values in {}", self_metrics);
+}
+
+#[tokio::test]
+async fn test_header_and_identity_tenants() {
+    let routes = tenanted_routes(TenantSource::Header("X-Tenant".to_owned()), false);
+    let res = warp::test::request().method("POST").path("/metrics").header("X-Tenant", "a").header("Authorization", "a").body("# TYPE requests_total counter\nrequests_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 200);
+    let res = warp::test::request().method("GET").path("/metrics").header("X-Tenant", "a").header("Authorization", "a").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE requests_total counter\nrequests_total 1\n");
+    let res = warp::test::request().method("GET").path("/tenants/a/metrics").header("Authorization", "a").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE requests_total counter\nrequests_total 1\n");
+
+    // Only clients authenticated as the tenant can scrape it
+    let res = warp::test::request().method("GET").path("/tenants/a/metrics").reply(&routes).await;
+    assert_eq!(res.status(), 403);
+    let res = warp::test::request().method("GET").path("/metrics").header("X-Tenant", "a").header("Authorization", "b").reply(&routes).await;
+    assert_eq!(res.status(), 403);
+
+    let res = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert_eq!(res.body(), "");
+
+    // Only clients authenticated as the tenant can push to it either
+    let res = warp::test::request().method("POST").path("/metrics").header("X-Tenant", "a").header("Authorization", "b").body("# TYPE requests_total counter\nrequests_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 403);
+    let res = warp::test::request().method("POST").path("/metrics").header("X-Tenant", "a").body("# TYPE requests_total counter\nrequests_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 403);
+
+    // Pushes can only pick their tenant with the path when that's where tenants come from
+    let res = warp::test::request().method("POST").path("/tenants/b/metrics").body("# TYPE requests_total counter\nrequests_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 404);
+
+    let routes = tenanted_routes(TenantSource::Path, false);
+    let res = warp::test::request().method("POST").path("/tenants/b/metrics").header("Authorization", "a").body("# TYPE requests_total counter\nrequests_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 403);
+    let res = warp::test::request().method("GET").path("/tenants/b/metrics").header("Authorization", "b").reply(&routes).await;
+    assert_eq!(res.body(), "", "a rejected push shouldn't have created the tenant");
+    let res = warp::test::request().method("POST").path("/tenants/b/metrics").header("Authorization", "b").body("# TYPE requests_total counter\nrequests_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 200);
+
+    let routes = tenanted_routes(TenantSource::Identity, false);
+    let res = warp::test::request().method("POST").path("/metrics").header("Authorization", "team-a").body("# TYPE requests_total counter\nrequests_total 1\n").reply(&routes).await;
+    assert_eq!(res.status(), 200);
+    let res = warp::test::request().method("GET").path("/tenants/team-a/metrics").header("Authorization", "team-a").reply(&routes).await;
+    assert_eq!(res.body(), "# TYPE requests_total counter\nrequests_total 1\n");
+    let res = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert_eq!(res.body(), "");
+}
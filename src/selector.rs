@@ -0,0 +1,200 @@
+use std::{iter::Peekable, str::{Chars, FromStr}};
+
+use anyhow::anyhow;
+use openmetrics_parser::LabelSet;
+use regex::Regex;
+
+/// The label that a selector's metric name is matched against, like in PromQL
+const NAME_LABEL: &str = "__name__";
+
+/// How a label matcher compares the value of its label
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchOp {
+    /// `=`
+    Equal,
+    /// `!=`
+    NotEqual,
+    /// `=~`
+    Regex,
+    /// `!~`
+    NotRegex,
+}
+
+/// A single condition on a label, e.g. `job=~"api|web"`
+#[derive(Debug, Clone)]
+pub struct LabelMatcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+    /// The compiled (and fully anchored, like PromQL) regex, for the regex ops
+    regex: Option<Regex>,
+}
+
+impl LabelMatcher {
+    pub fn new(name: &str, op: MatchOp, value: &str) -> Result<LabelMatcher, anyhow::Error> {
+        let regex = match op {
+            MatchOp::Regex | MatchOp::NotRegex => Some(Regex::new(&format!("^(?:{})$", value)).map_err(|e| anyhow!("Invalid regex `{}`: {}", value, e))?),
+            MatchOp::Equal | MatchOp::NotEqual => None,
+        };
+
+        Ok(LabelMatcher { name: name.to_owned(), op, value: value.to_owned(), regex })
+    }
+
+    /// Whether the given label value matches. Labels that a series doesn't have count as empty
+    pub fn matches(&self, value: &str) -> bool {
+        match (&self.op, &self.regex) {
+            (MatchOp::Equal, _) => value == self.value,
+            (MatchOp::NotEqual, _) => value != self.value,
+            (MatchOp::Regex, Some(regex)) => regex.is_match(value),
+            (MatchOp::NotRegex, Some(regex)) => !regex.is_match(value),
+            (_, None) => false,
+        }
+    }
+}
+
+/// A PromQL series selector, e.g. `http_requests_total{job="api",status!~"5.."}`, which selects the series
+/// that match all of its label matchers
+#[derive(Debug, Clone)]
+pub struct Selector {
+    pub matchers: Vec<LabelMatcher>,
+}
+
+impl Selector {
+    /// Whether a series, in the family with the given name and with the given labels, is selected
+    pub fn matches(&self, family_name: &str, labels: &LabelSet) -> bool {
+        self.matchers.iter().all(|matcher| {
+            if matcher.name == NAME_LABEL {
+                return matcher.matches(family_name);
+            }
+
+            matcher.matches(labels.get_label_value(&matcher.name).unwrap_or(""))
+        })
+    }
+}
+
+/// A cursor over a selector string being parsed
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    /// Takes the next character if it's the given one
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&c) {
+            self.chars.next();
+            return true;
+        }
+
+        false
+    }
+
+    /// Parses a label or metric name. Metric names can also contain colons
+    fn name(&mut self, allow_colons: bool) -> Result<String, anyhow::Error> {
+        self.skip_whitespace();
+        let mut name = String::new();
+        while let Some(&c) = self.chars.peek() {
+            let valid = c.is_ascii_alphabetic() || c == '_' || (allow_colons && c == ':') || (!name.is_empty() && c.is_ascii_digit());
+            if !valid {
+                break;
+            }
+
+            name.push(c);
+            self.chars.next();
+        }
+
+        if name.is_empty() {
+            return Err(anyhow!("expected a name"));
+        }
+
+        Ok(name)
+    }
+
+    fn op(&mut self) -> Result<MatchOp, anyhow::Error> {
+        self.skip_whitespace();
+        let op = match (self.chars.next(), self.chars.peek()) {
+            (Some('='), Some('~')) => MatchOp::Regex,
+            (Some('='), _) => return Ok(MatchOp::Equal),
+            (Some('!'), Some('=')) => MatchOp::NotEqual,
+            (Some('!'), Some('~')) => MatchOp::NotRegex,
+            _ => return Err(anyhow!("expected one of =, !=, =~, or !~")),
+        };
+
+        self.chars.next();
+        Ok(op)
+    }
+
+    /// Parses a quoted string, in double quotes, single quotes, or backticks (which don't have escapes)
+    fn string(&mut self) -> Result<String, anyhow::Error> {
+        self.skip_whitespace();
+        let quote = match self.chars.next() {
+            Some(quote @ ('"' | '\'' | '`')) => quote,
+            _ => return Err(anyhow!("expected a quoted string")),
+        };
+
+        let mut value = String::new();
+        loop {
+            match self.chars.next() {
+                Some(c) if c == quote => return Ok(value),
+                Some('\\') if quote != '`' => match self.chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some(c) => value.push(c),
+                    None => return Err(anyhow!("unterminated string")),
+                },
+                Some(c) => value.push(c),
+                None => return Err(anyhow!("unterminated string")),
+            }
+        }
+    }
+
+    fn selector(&mut self) -> Result<Selector, anyhow::Error> {
+        let mut matchers = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() != Some(&'{') {
+            let name = self.name(true)?;
+            matchers.push(LabelMatcher::new(NAME_LABEL, MatchOp::Equal, &name)?);
+        }
+
+        if self.eat('{') {
+            while !self.eat('}') {
+                let name = self.name(false)?;
+                let op = self.op()?;
+                let value = self.string()?;
+                matchers.push(LabelMatcher::new(&name, op, &value)?);
+
+                if !self.eat(',') {
+                    if !self.eat('}') {
+                        return Err(anyhow!("expected , or }}"));
+                    }
+                    break;
+                }
+            }
+        }
+
+        self.skip_whitespace();
+        if self.chars.peek().is_some() {
+            return Err(anyhow!("unexpected characters after the selector"));
+        }
+
+        if matchers.is_empty() {
+            return Err(anyhow!("selectors need at least one matcher"));
+        }
+
+        Ok(Selector { matchers })
+    }
+}
+
+impl FromStr for Selector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Parser { chars: s.chars().peekable() }.selector().map_err(|e| anyhow!("Invalid selector `{}`: {}", s, e))
+    }
+}
@@ -0,0 +1,56 @@
+use openmetrics_parser::{MetricFamily, MetricNumber, PrometheusType, PrometheusValue, Sample};
+
+use crate::selector::*;
+
+/// Checks whether the selector matches a series in the given family, with the given labels
+fn matches(selector: &str, family_name: &str, labels: &[(&str, &str)]) -> bool {
+    let selector: Selector = selector.parse().unwrap();
+    let mut family: MetricFamily<PrometheusType, PrometheusValue> = MetricFamily::new(family_name.to_owned(), labels.iter().map(|(name, _)| name.to_string()).collect(), PrometheusType::Gauge, String::new(), String::new());
+    family.add_sample(Sample::new(labels.iter().map(|(_, value)| value.to_string()).collect(), None, PrometheusValue::Gauge(MetricNumber::Int(1)))).unwrap();
+    let sample = family.iter_samples().next().unwrap();
+    selector.matches(family_name, &sample.get_labelset().unwrap())
+}
+
+#[test]
+fn test_selector_parsing() {
+    let selector: Selector = "http_requests_total{job=\"api\", status!~\"5..\",path=~'/a.*',env!=`prod`,}".parse().unwrap();
+    let matchers: Vec<(&str, MatchOp, &str)> = selector.matchers.iter().map(|m| (m.name.as_str(), m.op, m.value.as_str())).collect();
+    assert_eq!(matchers, vec![
+        ("__name__", MatchOp::Equal, "http_requests_total"),
+        ("job", MatchOp::Equal, "api"),
+        ("status", MatchOp::NotRegex, "5.."),
+        ("path", MatchOp::Regex, "/a.*"),
+        ("env", MatchOp::NotEqual, "prod"),
+    ]);
+
+    let selector: Selector = "{job=\"a \\\"quoted\\\" job\"}".parse().unwrap();
+    assert_eq!(selector.matchers[0].value, "a \"quoted\" job");
+
+    assert!("".parse::<Selector>().is_err(), "empty selectors should be rejected");
+    assert!("{}".parse::<Selector>().is_err(), "selectors without matchers should be rejected");
+    assert!("foo{job=\"api\"".parse::<Selector>().is_err(), "unclosed selectors should be rejected");
+    assert!("foo{job==\"api\"}".parse::<Selector>().is_err(), "invalid operators should be rejected");
+    assert!("foo{job=api}".parse::<Selector>().is_err(), "unquoted values should be rejected");
+    assert!("foo{job=~\"(\"}".parse::<Selector>().is_err(), "invalid regexes should be rejected");
+    assert!("foo bar".parse::<Selector>().is_err(), "trailing garbage should be rejected");
+}
+
+#[test]
+fn test_selector_matching() {
+    assert!(matches("requests_total", "requests_total", &[("job", "api")]));
+    assert!(!matches("requests_total", "errors_total", &[("job", "api")]));
+    assert!(matches("{job=\"api\"}", "requests_total", &[("job", "api")]));
+    assert!(!matches("{job!=\"api\"}", "requests_total", &[("job", "api")]));
+
+    // Regexes have to match the whole value
+    assert!(matches("{job=~\"a.*\"}", "requests_total", &[("job", "api")]));
+    assert!(!matches("{job=~\"p\"}", "requests_total", &[("job", "api")]));
+    assert!(matches("{job!~\"web|batch\"}", "requests_total", &[("job", "api")]));
+
+    // Missing labels are treated as empty
+    assert!(matches("{env=\"\"}", "requests_total", &[("job", "api")]));
+    assert!(!matches("{env=\"prod\"}", "requests_total", &[("job", "api")]));
+
+    // Every matcher has to match
+    assert!(!matches("requests_total{job=\"web\"}", "requests_total", &[("job", "api")]));
+}
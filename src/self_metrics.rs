@@ -0,0 +1,33 @@
+use std::fmt;
+
+use openmetrics_parser::{MetricNumber, PrometheusCounterValue, PrometheusMetricFamily, PrometheusType, PrometheusValue, Sample};
+
+/// A single metric family describing the state of the gateway itself (as opposed to the metrics
+/// that have been pushed to it), which can be rendered out in the Prometheus text format
+pub struct SelfMetricFamily {
+    family: PrometheusMetricFamily,
+}
+
+impl SelfMetricFamily {
+    pub fn new(name: &str, help: &str, family_type: PrometheusType, label_names: &[&str]) -> SelfMetricFamily {
+        SelfMetricFamily {
+            family: PrometheusMetricFamily::new(name.to_owned(), label_names.iter().map(|&s| s.to_owned()).collect(), family_type, help.to_owned(), String::new()),
+        }
+    }
+
+    pub fn add_sample(&mut self, label_values: &[&str], value: MetricNumber) {
+        let value = match self.family.family_type {
+            PrometheusType::Counter => PrometheusValue::Counter(PrometheusCounterValue { value, exemplar: None }),
+            _ => PrometheusValue::Gauge(value),
+        };
+
+        // We control all the label values here, so the only way for this to fail is a programming error
+        self.family.add_sample(Sample::new(label_values.iter().map(|&s| s.to_owned()).collect(), None, value)).expect("invalid self metric sample");
+    }
+}
+
+impl fmt::Display for SelfMetricFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.family.fmt(f)
+    }
+}
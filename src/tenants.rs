@@ -0,0 +1,215 @@
+use std::{collections::HashMap, fs, path::Path, str::FromStr, sync::RwLock};
+
+use anyhow::anyhow;
+use openmetrics_parser::{MetricNumber, PrometheusType};
+use serde::Deserialize;
+
+use crate::{aggregator::{AggregationError, Aggregator, AggregatorConfig}, self_metrics::SelfMetricFamily};
+
+/// The header that tenants are read from by default, which is the same one that Cortex and Mimir use
+pub const DEFAULT_TENANT_HEADER: &str = "X-Scope-OrgID";
+
+/// The longest tenant ID that we'll accept
+const MAX_TENANT_ID_LENGTH: usize = 128;
+
+/// Where the tenant that a push belongs to comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum TenantSource {
+    /// A `/tenants/<id>` prefix on the push URL
+    Path,
+    /// The value of the given header
+    Header(String),
+    /// The identity that the push was authenticated as
+    Identity,
+}
+
+impl FromStr for TenantSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(TenantSource::Path),
+            "header" => Ok(TenantSource::Header(DEFAULT_TENANT_HEADER.to_owned())),
+            "identity" => Ok(TenantSource::Identity),
+            _ => Err(anyhow!("Invalid tenant source: {}", s))
+        }
+    }
+}
+
+/// Tenant IDs end up in URLs, so they're limited to characters that don't need escaping
+pub fn is_valid_tenant_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_TENANT_ID_LENGTH && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// A set of tenants, each of which has its own aggregator so that their metrics (and limits) are kept apart.
+/// Tenants are created the first time that they push
+pub struct Tenancy {
+    pub source: TenantSource,
+    /// The config that every tenant's aggregator is created with, unless it has overrides
+    config: AggregatorConfig,
+    /// The configs of tenants with their own limits
+    overrides: HashMap<String, AggregatorConfig>,
+    /// The most tenants that can be created. As each tenant has its own series limits, this is what bounds the total
+    /// number of series across all of them
+    max_tenants: usize,
+    /// Whether anyone can scrape any tenant, rather than only clients authenticated as that tenant
+    pub public_scrapes: bool,
+    /// Whether anyone can push to any tenant, rather than only clients authenticated as that tenant
+    pub public_pushes: bool,
+    tenants: RwLock<HashMap<String, Aggregator>>,
+}
+
+impl Tenancy {
+    pub fn new(source: TenantSource, config: AggregatorConfig, max_tenants: usize) -> Tenancy {
+        Tenancy {
+            source,
+            config,
+            overrides: HashMap::new(),
+            max_tenants,
+            public_scrapes: false,
+            public_pushes: false,
+            tenants: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Gives the given tenants their own configs, instead of the one that every other tenant gets
+    pub fn with_overrides(mut self, overrides: HashMap<String, AggregatorConfig>) -> Tenancy {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Lets anyone scrape any tenant's metrics, without authenticating as that tenant
+    pub fn with_public_scrapes(mut self, public_scrapes: bool) -> Tenancy {
+        self.public_scrapes = public_scrapes;
+        self
+    }
+
+    /// Lets anyone push to any tenant, without authenticating as that tenant
+    pub fn with_public_pushes(mut self, public_pushes: bool) -> Tenancy {
+        self.public_pushes = public_pushes;
+        self
+    }
+
+    /// The config of the given tenant's aggregator
+    pub fn config_for(&self, tenant: &str) -> &AggregatorConfig {
+        self.overrides.get(tenant).unwrap_or(&self.config)
+    }
+
+    /// The aggregator of the given tenant, if it's pushed anything
+    pub fn get(&self, tenant: &str) -> Option<Aggregator> {
+        self.tenants.read().unwrap().get(tenant).cloned()
+    }
+
+    /// The aggregator of the given tenant, creating it if this is the tenant's first push
+    pub fn get_or_create(&self, tenant: &str) -> Result<Aggregator, AggregationError> {
+        if let Some(agg) = self.get(tenant) {
+            return Ok(agg);
+        }
+
+        let mut tenants = self.tenants.write().unwrap();
+        // Someone else could have created it while we didn't have the lock
+        if let Some(agg) = tenants.get(tenant) {
+            return Ok(agg.clone());
+        }
+
+        if tenants.len() >= self.max_tenants {
+            return Err(AggregationError::LimitExceeded(format!("Can't create tenant {}, as there are already {} tenants, which is the limit", tenant, self.max_tenants)));
+        }
+
+        let agg = Aggregator::new_with_config(self.config_for(tenant).clone());
+        tenants.insert(tenant.to_owned(), agg.clone());
+        Ok(agg)
+    }
+
+    /// Every tenant's aggregator, sorted by tenant
+    pub fn aggregators(&self) -> Vec<(String, Aggregator)> {
+        let mut aggregators: Vec<_> = self.tenants.read().unwrap().iter().map(|(tenant, agg)| (tenant.clone(), agg.clone())).collect();
+        aggregators.sort_by(|(a, _), (b, _)| a.cmp(b));
+        aggregators
+    }
+
+    /// Renders the number of tenants, and the totals of each tenant's self metrics, in the Prometheus text exposition format.
+    /// Only the totals are shown, as anyone can see the self metrics, and the family names and label values that a tenant
+    /// has pushed would give away what they're running
+    pub async fn self_metrics_string(&self) -> String {
+        let mut tenants = SelfMetricFamily::new("gravel_tenants", "The number of tenants that have pushed to the gateway", PrometheusType::Gauge, &[]);
+        let mut pebbles = SelfMetricFamily::new("gravel_tenant_pebbles", "The number of pebbles in each tenant", PrometheusType::Gauge, &["tenant"]);
+        let mut pebble_memory = SelfMetricFamily::new("gravel_tenant_pebble_memory_bytes", "The approximate memory used by the pebbles in each tenant", PrometheusType::Gauge, &["tenant"]);
+        let mut dropped_samples = SelfMetricFamily::new("gravel_tenant_dropped_samples_total", "The number of samples that each tenant has had dropped, either for being invalid in lenient mode or too late", PrometheusType::Counter, &["tenant", "reason"]);
+        let mut series = SelfMetricFamily::new("gravel_tenant_series", "The number of series in each tenant", PrometheusType::Gauge, &["tenant"]);
+        let mut series_limits = SelfMetricFamily::new("gravel_tenant_series_limit", "The configured limits on the number of series in each tenant", PrometheusType::Gauge, &["tenant", "scope"]);
+
+        let aggregators = self.aggregators();
+        tenants.add_sample(&[], MetricNumber::Int(aggregators.len() as i64));
+        for (tenant, agg) in aggregators {
+            let totals = agg.self_metric_totals().await;
+            if totals.pebbles > 0 {
+                pebbles.add_sample(&[&tenant], MetricNumber::Int(totals.pebbles as i64));
+                pebble_memory.add_sample(&[&tenant], MetricNumber::Int(totals.pebble_memory_bytes as i64));
+            }
+
+            for (reason, count) in totals.dropped_samples {
+                dropped_samples.add_sample(&[&tenant, reason], MetricNumber::Int(count as i64));
+            }
+
+            series.add_sample(&[&tenant], MetricNumber::Int(totals.series as i64));
+            for (scope, limit) in totals.series_limits {
+                series_limits.add_sample(&[&tenant, scope], MetricNumber::Int(limit as i64));
+            }
+        }
+
+        [tenants, pebbles, pebble_memory, dropped_samples, series, series_limits].iter().map(|family| family.to_string()).collect()
+    }
+}
+
+/// Limits for a single tenant, as they appear in the tenant limits file. Limits that aren't set are the same as
+/// every other tenant's
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTenantLimits {
+    max_series_per_family: Option<usize>,
+    max_series_per_job_label: Option<usize>,
+    max_series: Option<usize>,
+    max_families_per_push: Option<usize>,
+    max_samples_per_push: Option<usize>,
+    max_label_name_length: Option<usize>,
+    max_label_value_length: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTenantLimitsFile {
+    #[serde(default)]
+    tenants: HashMap<String, RawTenantLimits>,
+}
+
+/// Loads per tenant limits from a YAML file, returning the config of each tenant in it, which is the given one
+/// with the tenant's limits on top
+pub fn load_tenant_limits<P: AsRef<Path>>(path: P, config: &AggregatorConfig) -> Result<HashMap<String, AggregatorConfig>, anyhow::Error> {
+    parse_tenant_limits(&fs::read_to_string(path)?, config)
+}
+
+pub fn parse_tenant_limits(s: &str, config: &AggregatorConfig) -> Result<HashMap<String, AggregatorConfig>, anyhow::Error> {
+    let raw: RawTenantLimitsFile = serde_yaml::from_str(s)?;
+    let mut overrides = HashMap::new();
+    for (tenant, limits) in raw.tenants {
+        if !is_valid_tenant_id(&tenant) {
+            return Err(anyhow!("Invalid tenant: {}", tenant));
+        }
+
+        let tenant_config = AggregatorConfig {
+            max_series_per_family: limits.max_series_per_family.or(config.max_series_per_family),
+            max_series_per_job_label: limits.max_series_per_job_label.or(config.max_series_per_job_label),
+            max_series: limits.max_series.or(config.max_series),
+            max_families_per_push: limits.max_families_per_push.or(config.max_families_per_push),
+            max_samples_per_push: limits.max_samples_per_push.or(config.max_samples_per_push),
+            max_label_name_length: limits.max_label_name_length.or(config.max_label_name_length),
+            max_label_value_length: limits.max_label_value_length.or(config.max_label_value_length),
+            ..config.clone()
+        };
+
+        overrides.insert(tenant, tenant_config);
+    }
+
+    Ok(overrides)
+}
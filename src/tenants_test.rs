@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use crate::{aggregator::{AggregationError, AggregatorConfig}, tenants::*};
+
+#[test]
+fn test_tenant_ids() {
+    assert!(is_valid_tenant_id("team-a"));
+    assert!(is_valid_tenant_id("Team_A.prod"));
+    assert!(!is_valid_tenant_id(""));
+    assert!(!is_valid_tenant_id("team/a"));
+    assert!(!is_valid_tenant_id("team a"));
+    assert!(!is_valid_tenant_id(&"a".repeat(129)));
+}
+
+#[test]
+fn test_tenant_sources() {
+    assert_eq!("path".parse::<TenantSource>().unwrap(), TenantSource::Path);
+    assert_eq!("header".parse::<TenantSource>().unwrap(), TenantSource::Header(DEFAULT_TENANT_HEADER.to_owned()));
+    assert_eq!("identity".parse::<TenantSource>().unwrap(), TenantSource::Identity);
+    assert!("query".parse::<TenantSource>().is_err());
+}
+
+#[tokio::test]
+async fn test_isolated_tenants() {
+    let tenancy = Tenancy::new(TenantSource::Path, AggregatorConfig::default(), 2);
+    assert!(tenancy.get("a").is_none());
+
+    let mut a = tenancy.get_or_create("a").unwrap();
+    a.parse_and_merge("# TYPE requests_total counter\nrequests_total 1\n", &HashMap::new()).await.unwrap();
+    let mut b = tenancy.get_or_create("b").unwrap();
+    b.parse_and_merge("# TYPE requests_total counter\nrequests_total 5\n", &HashMap::new()).await.unwrap();
+
+    // Getting a tenant again gives the same aggregator
+    tenancy.get_or_create("a").unwrap().parse_and_merge("# TYPE requests_total counter\nrequests_total 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(tenancy.get("a").unwrap().to_string().await, "# TYPE requests_total counter\nrequests_total 2\n");
+    assert_eq!(tenancy.get("b").unwrap().to_string().await, "# TYPE requests_total counter\nrequests_total 5\n");
+
+    assert!(matches!(tenancy.get_or_create("c"), Err(AggregationError::LimitExceeded(_))));
+    let self_metrics = tenancy.self_metrics_string().await;
+    assert!(self_metrics.starts_with("# HELP gravel_tenants The number of tenants that have pushed to the gateway\n# TYPE gravel_tenants gauge\ngravel_tenants 2\n"));
+    assert!(self_metrics.contains("gravel_tenant_series{tenant=\"a\"} 1\n"));
+    assert!(self_metrics.contains("gravel_tenant_series{tenant=\"b\"} 1\n"));
+    // Only the totals are shown, not what the tenants have pushed
+    assert!(!self_metrics.contains("requests_total"), "family names in {}", self_metrics);
+}
+
+#[tokio::test]
+async fn test_tenant_limits() {
+    let config = AggregatorConfig {
+        max_series: Some(1),
+        max_samples_per_push: Some(10),
+        ..Default::default()
+    };
+
+    let overrides = parse_tenant_limits("tenants:
+  big:
+    max_series: 2
+", &config).unwrap();
+    assert_eq!(overrides["big"].max_series, Some(2));
+    // Limits that aren't overridden are the same as everyone else's
+    assert_eq!(overrides["big"].max_samples_per_push, Some(10));
+
+    let tenancy = Tenancy::new(TenantSource::Path, config, 10).with_overrides(overrides);
+    let push = "# TYPE requests_total counter\nrequests_total{path=\"/a\"} 1\nrequests_total{path=\"/b\"} 1\n";
+    assert!(tenancy.get_or_create("big").unwrap().parse_and_merge(push, &HashMap::new()).await.is_ok());
+    assert!(matches!(tenancy.get_or_create("small").unwrap().parse_and_merge(push, &HashMap::new()).await, Err(AggregationError::LimitExceeded(_))));
+
+    assert!(parse_tenant_limits("tenants:\n  a/b:\n    max_series: 1\n", &AggregatorConfig::default()).is_err(), "invalid tenant IDs should be rejected");
+    assert!(parse_tenant_limits("tenants:\n  a:\n    max_serie: 1\n", &AggregatorConfig::default()).is_err(), "unknown limits should be rejected");
+}